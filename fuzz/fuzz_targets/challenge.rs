@@ -0,0 +1,14 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+#![no_main]
+//! The challenge the server sends when we connect.
+use libfuzzer_sys::fuzz_target;
+use mapi::auth::Challenge;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Challenge::parse(data);
+});
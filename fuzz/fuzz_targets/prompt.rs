@@ -0,0 +1,23 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+#![no_main]
+//! Prompts, i.e. the first bytes of every message the server sends.
+use libfuzzer_sys::fuzz_target;
+use mapi::framing;
+use mapi::mapi::{Endianness, MapiConnection};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = MapiConnection::parse_prompt(data);
+
+    // The same bytes as a stream of blocks
+    let mut message = vec![];
+    let _ = framing::read_message(&mut &data[..], &mut message, Endianness::Little);
+
+    // Or as the challenge that starts a login
+    message.clear();
+    let _ = framing::read_challenge(&mut &data[..], &mut message);
+});
@@ -0,0 +1,19 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+#![no_main]
+//! Responses to queries: result headers, column headers, rows and errors.
+use libfuzzer_sys::fuzz_target;
+use monetdb::response::{block_start, parse_block, parse_response, split_rows};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(response) = std::str::from_utf8(data) {
+        let _ = parse_response(response);
+        let _ = split_rows(response);
+        let _ = block_start(response);
+        let _ = parse_block(response, 3);
+    }
+});
@@ -0,0 +1,26 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+#![no_main]
+//! A single row of a result set.
+use libfuzzer_sys::fuzz_target;
+use monetdb::monetizer::SqlType;
+use monetdb::response::{parse_tuple, RawRow};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(line) = std::str::from_utf8(data) {
+        let _ = parse_tuple(line, 3);
+        if let Ok(row) = RawRow::parse(line) {
+            for field in row.fields() {
+                let _ = field;
+            }
+            for i in 0..4 {
+                let _ = row.get_str(i);
+                let _ = row.get::<i64>(i, &SqlType::new("bigint"));
+            }
+        }
+    }
+});
@@ -0,0 +1,59 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! The block framing and prompt parsing, on in-memory streams.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mapi::framing::{read_message, write_message};
+use mapi::mapi::{Endianness, MapiConnection};
+
+const SIZES: [usize; 3] = [100, 64 * 1024, 1024 * 1024];
+
+fn framing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("framing");
+    for size in SIZES {
+        let message = vec![b'x'; size];
+        let mut wire = vec![];
+        write_message(&mut wire, &message, Endianness::Little).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let mut output = Vec::with_capacity(wire.len());
+        group.bench_with_input(BenchmarkId::new("write", size), &message, |b, m| {
+            b.iter(|| {
+                output.clear();
+                write_message(&mut output, m, Endianness::Little).unwrap();
+            })
+        });
+        let mut buffer = Vec::with_capacity(size);
+        group.bench_with_input(BenchmarkId::new("read", size), &wire, |b, w| {
+            b.iter(|| {
+                buffer.clear();
+                read_message(&mut w.as_slice(), &mut buffer, Endianness::Little).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn prompts(c: &mut Criterion) {
+    let responses: [(&str, &[u8]); 5] = [
+        ("table", b"&1 0 100 3 100\n% sys.t # table_name\n"),
+        ("update", b"&2 1 -1\n"),
+        ("error", b"!42000!syntax error\n"),
+        ("more", b"\x01\x02\n"),
+        ("ok", b"=OK\n"),
+    ];
+    let mut group = c.benchmark_group("parse_prompt");
+    for (name, response) in responses {
+        let response = response.to_vec();
+        group.bench_with_input(name, &response, |b, r| {
+            b.iter(|| MapiConnection::parse_prompt(black_box(r)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, framing, prompts);
+criterion_main!(benches);
@@ -0,0 +1,133 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Round trips of messages of several sizes against a local mock server,
+//! comparing `MapiConnection` with unbuffered framing that writes header and
+//! payload of every block separately, as the connection used to do.
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mapi::framing::BLOCK_SIZE;
+use mapi::mapi::{MapiConnection, MapiConnectionParams};
+use mapi::session::SessionOptions;
+
+const SIZES: [usize; 3] = [16, 64 * 1024, 1024 * 1024];
+
+fn read_message<R: Read>(stream: &mut R) -> Option<Vec<u8>> {
+    let mut message = vec![];
+    loop {
+        let mut header = [0u8; 2];
+        stream.read_exact(&mut header).ok()?;
+        let header = u16::from_le_bytes(header);
+        let start = message.len();
+        message.resize(start + (header >> 1) as usize, 0);
+        stream.read_exact(&mut message[start..]).ok()?;
+        if header & 1 == 1 {
+            return Some(message);
+        }
+    }
+}
+
+fn write_message<W: Write>(stream: &mut W, message: &[u8]) {
+    let mut blocks = message.chunks(BLOCK_SIZE).peekable();
+    if blocks.peek().is_none() {
+        stream.write_all(&1u16.to_le_bytes()).unwrap();
+    }
+    while let Some(block) = blocks.next() {
+        let header = (block.len() << 1) as u16 | blocks.peek().is_none() as u16;
+        stream.write_all(&header.to_le_bytes()).unwrap();
+        stream.write_all(block).unwrap();
+    }
+    stream.flush().unwrap();
+}
+
+/// A server that logs in every client and echoes every message it receives.
+/// The server side is buffered, so that the client is what is measured.
+fn echo_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            stream.set_nodelay(true).unwrap();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = BufWriter::new(stream);
+                let challenge = b"somesalt:mserver:9:SHA512:LIT:SHA512:";
+                write_message(&mut writer, challenge);
+                read_message(&mut reader).unwrap();
+                write_message(&mut writer, b"");
+                while let Some(message) = read_message(&mut reader) {
+                    write_message(&mut writer, &message);
+                }
+            });
+        }
+    });
+    port
+}
+
+fn buffered_client(port: u16) -> MapiConnection {
+    let mut params = MapiConnectionParams::new(
+        "demo",
+        "monetdb",
+        Some("monetdb"),
+        None,
+        Some("127.0.0.1"),
+        Some(port),
+    );
+    params.session = SessionOptions {
+        timezone: None,
+        schema: None,
+        role: None,
+        client_info: None,
+        size_header: false,
+    };
+    MapiConnection::connect(params).unwrap()
+}
+
+/// The framing as it was: no buffering, no TCP_NODELAY, and a separate
+/// write for the header and the payload of every block.
+struct Unbuffered(TcpStream);
+
+impl Unbuffered {
+    fn connect(port: u16) -> Unbuffered {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        read_message(&mut stream).unwrap();
+        write_message(&mut stream, b"LIT:monetdb:{PLAIN}monetdb:sql:demo:");
+        read_message(&mut stream).unwrap();
+        Unbuffered(stream)
+    }
+
+    fn round_trip(&mut self, message: &[u8]) -> Vec<u8> {
+        write_message(&mut self.0, message);
+        read_message(&mut self.0).unwrap()
+    }
+}
+
+fn round_trips(c: &mut Criterion) {
+    let port = echo_server();
+    let mut buffered = buffered_client(port);
+    let mut unbuffered = Unbuffered::connect(port);
+    let mut response = vec![];
+
+    let mut group = c.benchmark_group("round_trip");
+    for size in SIZES {
+        let message = "x".repeat(size);
+        group.throughput(Throughput::Bytes(2 * size as u64));
+        group.bench_with_input(BenchmarkId::new("buffered", size), &message, |b, m| {
+            b.iter(|| buffered.query_into(m, &mut response).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("unbuffered", size), &message, |b, m| {
+            b.iter(|| unbuffered.round_trip(m.as_bytes()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, round_trips);
+criterion_main!(benches);
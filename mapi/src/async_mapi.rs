@@ -0,0 +1,407 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! An asynchronous connection to MonetDB, enabled with the `tokio` feature.
+//!
+//! `AsyncMapiConnection` logs in and sends commands like `MapiConnection`,
+//! but waits for the server without blocking the thread. Wire dumps,
+//! transcripts, replays, timeouts, observers and the control language are
+//! only supported by `MapiConnection`, and connecting with any of them set
+//! is an error. For timeouts, wrap the calls in `tokio::time::timeout`; a
+//! command that timed out leaves the connection in an unknown state.
+use std::result;
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+#[cfg(target_family = "unix")]
+use tokio::net::UnixStream;
+use tracing::field::Empty;
+use tracing::{debug, debug_span, info, info_span, Instrument, Span};
+use zeroize::Zeroize;
+
+use crate::errors::MapiError;
+use crate::framing;
+use crate::handshake::{self, Handshake};
+use crate::mapi::{socket_address, Endianness, MapiConnectionParams, MapiLanguage, SocketAddress};
+use crate::trace;
+
+type Result<T> = result::Result<T, MapiError>;
+
+/// A stream an `AsyncMapiConnection` can talk to the server over.
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncTransport for T {}
+
+/// Asynchronous low level connection to MonetDB, for SQL and MAL.
+pub struct AsyncMapiConnection {
+    socket: BufStream<Box<dyn AsyncTransport>>,
+    hostname: String,
+    endianness: Endianness,
+    handshake: Handshake,
+}
+
+impl AsyncMapiConnection {
+    /// Establish a connection given a set of connection params.
+    pub async fn connect(params: MapiConnectionParams) -> Result<AsyncMapiConnection> {
+        AsyncMapiConnection::establish(params, None).await
+    }
+
+    /// Like `connect`, but talk to the server over `transport`. Over a Unix
+    /// domain socket, send the byte `0` before handing over the transport.
+    pub async fn connect_with_transport<T: AsyncTransport + 'static>(
+        params: MapiConnectionParams,
+        transport: T,
+    ) -> Result<AsyncMapiConnection> {
+        AsyncMapiConnection::establish(params, Some(Box::new(transport))).await
+    }
+
+    async fn establish(
+        mut params: MapiConnectionParams,
+        transport: Option<Box<dyn AsyncTransport>>,
+    ) -> Result<AsyncMapiConnection> {
+        let port = params.port.unwrap_or(50000);
+        let language = params.language.take().unwrap_or(MapiLanguage::Sql);
+        if language == MapiLanguage::Control {
+            return Err(MapiError::ConnectionError(String::from(
+                "the control language needs a MapiConnection",
+            )));
+        }
+        if let Some(name) = unsupported(&params).first() {
+            return Err(MapiError::ConnectionError(format!(
+                "{} needs a MapiConnection",
+                name
+            )));
+        }
+        let span = info_span!(
+            "connect",
+            database = %params.database,
+            host = Empty,
+            port,
+            language = %language
+        );
+
+        async move {
+            let address = socket_address(params.hostname.as_deref(), port, &language)?;
+            let hostname = match address {
+                SocketAddress::Tcp(ref h) => h.clone(),
+                #[cfg(target_family = "unix")]
+                SocketAddress::Unix(_) => String::from("localhost"),
+            };
+            let transport = match (transport, address) {
+                (Some(transport), _) => transport,
+                (None, SocketAddress::Tcp(h)) => {
+                    Span::current().record("host", h.as_str());
+                    let stream = TcpStream::connect(&h).await?;
+                    // We flush complete messages ourselves, waiting for
+                    // more data only delays them.
+                    stream.set_nodelay(true)?;
+                    Box::new(stream)
+                }
+                #[cfg(target_family = "unix")]
+                (None, SocketAddress::Unix(path)) => {
+                    Span::current().record("host", path.as_str());
+                    let mut stream = UnixStream::connect(&path).await?;
+                    // We need to send b'0' to initialize the connection
+                    stream.write_all(b"0").await?;
+                    Box::new(stream)
+                }
+            };
+
+            let mut connection = AsyncMapiConnection {
+                socket: BufStream::new(transport),
+                hostname,
+                // Until the server tells us otherwise assume the usual
+                // little endian headers.
+                endianness: Endianness::Little,
+                handshake: Handshake::new(&mut params, language),
+            };
+            connection.start().await?;
+            info!("connected");
+
+            Ok(connection)
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        let started = Instant::now();
+        self.handshake.begin()?;
+        let logged_in = self.login().await;
+        self.handshake.end();
+        logged_in?;
+        debug!(
+            duration_us = started.elapsed().as_micros() as u64,
+            "logged in"
+        );
+
+        for command in self.handshake.session_commands() {
+            debug!("Session: {}", command);
+            handshake::session_error(&self.query(&command).await?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Answer challenges until the server lets us in.
+    async fn login(&mut self) -> Result<()> {
+        loop {
+            let span = debug_span!(
+                "login",
+                user = %self.handshake.username,
+                iteration = self.handshake.iteration()
+            );
+            if self.answer_challenge().instrument(span).await? {
+                return Ok(());
+            }
+            debug!("Restarting authentication");
+        }
+    }
+
+    /// Answer the challenge of the server. Returns false if we have to log
+    /// in again because monetdbd now proxies the connection.
+    async fn answer_challenge(&mut self) -> Result<bool> {
+        let mut challenge = vec![];
+        self.endianness = framing::read_challenge_async(&mut self.socket, &mut challenge).await?;
+        debug!(challenge = %String::from_utf8_lossy(&challenge), "challenge received");
+        let (mut response, _) = self.handshake.respond(&challenge)?;
+        let sent = self.send(&response).await;
+        response.zeroize();
+        sent?;
+
+        let mut response = vec![];
+        self.read_message(&mut response).await?;
+        self.handshake.accepted(&response)
+    }
+
+    /// Send a command and return the response, or
+    /// `MapiError::OperationError` with the response if it has an error.
+    pub async fn cmd(&mut self, operation: &str) -> Result<String> {
+        let response = self.query(operation).await?;
+        if response.lines().any(|l| l.starts_with('!')) {
+            return Err(MapiError::OperationError(response));
+        }
+        Ok(response)
+    }
+
+    /// Send a command and return the complete response without looking at
+    /// it, like `MapiConnection::query`.
+    pub async fn query(&mut self, operation: &str) -> Result<String> {
+        let mut response = vec![];
+        self.query_into(operation, &mut response).await?;
+        Ok(String::from_utf8(response)?)
+    }
+
+    /// Like `query`, but the response replaces the contents of `buffer`.
+    pub async fn query_into(&mut self, operation: &str, buffer: &mut Vec<u8>) -> Result<()> {
+        self.exchange(operation, buffer).await?;
+        // Tell the server it's not getting anything more from us
+        while buffer == b"\x01\x02\n" {
+            self.exchange("", buffer).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a command and read the response into `buffer`, as one span.
+    async fn exchange(&mut self, operation: &str, buffer: &mut Vec<u8>) -> Result<()> {
+        let span = debug_span!(
+            "command",
+            database = %self.handshake.database,
+            host = %self.hostname,
+            command = %trace::summary(operation),
+            bytes = operation.len(),
+            response_bytes = Empty,
+            duration_us = Empty
+        );
+        async move {
+            let started = Instant::now();
+            self.send(operation.as_bytes()).await?;
+            buffer.clear();
+            self.read_message(buffer).await?;
+
+            let span = Span::current();
+            span.record("response_bytes", buffer.len());
+            span.record("duration_us", started.elapsed().as_micros() as u64);
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn send(&mut self, message: &[u8]) -> Result<()> {
+        framing::write_message_async(&mut self.socket, message, Endianness::native()).await?;
+        // Only now does the message go out, in as few packets as possible
+        self.socket.flush().await?;
+        Ok(())
+    }
+
+    /// Read a complete message, appending it to `buff`.
+    async fn read_message(&mut self, buff: &mut Vec<u8>) -> Result<()> {
+        framing::read_message_async(&mut self.socket, buff, self.endianness).await?;
+        Ok(())
+    }
+
+    /// Shut down the connection.
+    pub async fn close(&mut self) -> Result<()> {
+        self.socket.get_mut().shutdown().await?;
+        Ok(())
+    }
+}
+
+/// The parameters in `params` that only `MapiConnection` supports.
+fn unsupported(params: &MapiConnectionParams) -> Vec<&'static str> {
+    let mut names = vec![];
+    if params.wire_dump.is_some() {
+        names.push("wire_dump");
+    }
+    if params.record.is_some() {
+        names.push("record");
+    }
+    if params.replay.is_some() {
+        names.push("replay");
+    }
+    if params.timeout.is_some() {
+        names.push("timeout");
+    }
+    #[cfg(feature = "metrics")]
+    if params.observer.is_some() {
+        names.push("observer");
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+    use crate::session::SessionOptions;
+    use std::thread;
+    use std::time::Duration;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+    }
+
+    /// Log in a client, first redirecting it to log in again if `proxied`,
+    /// and answer its commands with `responses`. Returns what it sent.
+    fn mock_server(
+        endianness: Endianness,
+        proxied: bool,
+        responses: Vec<&'static str>,
+    ) -> (u16, thread::JoinHandle<Vec<Vec<u8>>>) {
+        MockServer {
+            proxied,
+            responses: responses.into_iter().map(String::from).collect(),
+            ..MockServer::new(endianness)
+        }
+        .start()
+    }
+
+    fn mock_params(port: u16) -> MapiConnectionParams {
+        let mut params = MapiConnectionParams::new(
+            "demo",
+            "monetdb",
+            Some("monetdb"),
+            None,
+            Some("127.0.0.1"),
+            Some(port),
+        );
+        params.session.timezone = Some(60);
+        params
+    }
+
+    #[test]
+    fn session() {
+        for endianness in [Endianness::Little, Endianness::Big] {
+            let (port, server) = mock_server(endianness, false, vec!["", "", "", "&2 1 -1\n"]);
+            runtime().block_on(async {
+                let mut connection = AsyncMapiConnection::connect(mock_params(port))
+                    .await
+                    .unwrap();
+                assert_eq!(connection.endianness, endianness);
+                let response = connection.cmd("sINSERT INTO foo VALUES (1);").await;
+                assert_eq!(response.unwrap(), "&2 1 -1\n");
+                connection.close().await.unwrap();
+            });
+
+            let received = server.join().unwrap();
+            let login = String::from_utf8(received[0].clone()).unwrap();
+            assert!(login.starts_with(&format!("{}:monetdb:{{SHA512}}", Endianness::native())));
+            assert!(login.ends_with(":sql:demo:"));
+            assert_eq!(received[1], b"Xsizeheader 1");
+            assert_eq!(
+                received[2],
+                b"sSET TIME ZONE INTERVAL '+01:00' HOUR TO MINUTE\n;"
+            );
+            assert!(received[3].starts_with(b"Xclientinfo "));
+            assert_eq!(received[4], b"sINSERT INTO foo VALUES (1);");
+        }
+    }
+
+    #[test]
+    fn errors_and_redirects() {
+        let (port, server) = mock_server(
+            Endianness::Little,
+            true,
+            vec!["!42000!syntax error\n", "&1 0 0 0 0\n"],
+        );
+        let mut params = mock_params(port);
+        params.session = SessionOptions {
+            timezone: None,
+            schema: None,
+            role: None,
+            client_info: None,
+            size_header: false,
+        };
+        runtime().block_on(async {
+            let mut connection = AsyncMapiConnection::connect(params).await.unwrap();
+            let error = connection.cmd("sSELEC 1;").await.unwrap_err();
+            assert!(matches!(error, MapiError::OperationError(ref e) if e.contains("42000")));
+            let response = connection.query("sSELECT 1;").await.unwrap();
+            assert_eq!(response, "&1 0 0 0 0\n");
+        });
+
+        let received = server.join().unwrap();
+        // Logged in twice, then the commands
+        assert_eq!(received.len(), 4);
+        assert_eq!(received[0], received[1]);
+    }
+
+    #[test]
+    fn credential_provider_behind_proxy() {
+        let (port, server) = mock_server(Endianness::Little, true, vec![]);
+        let mut params = mock_params(port);
+        params.password = None;
+        params.credential_provider = Some(Box::new(|| Ok(String::from("monetdb"))));
+        runtime().block_on(async {
+            let connection = AsyncMapiConnection::connect(params).await.unwrap();
+            assert!(connection.handshake.password.is_empty());
+        });
+
+        // The second challenge is answered with the same password
+        let received = server.join().unwrap();
+        let login = String::from_utf8(received[0].clone()).unwrap();
+        assert!(login.contains(":{SHA512}f15f4633c278512d1d0a144f72e87f6b8542ba15dca446d395b262650db0c2d8d29682bcb5e7b9c082581540f1a4968398f6f21296942cfc6966da2a6426a8c7:"));
+        assert_eq!(received[0], received[1]);
+    }
+
+    #[test]
+    fn unsupported_params() {
+        let mut params = mock_params(0);
+        params.timeout = Some(Duration::from_secs(1));
+        runtime().block_on(async {
+            let error = AsyncMapiConnection::connect(params).await.err().unwrap();
+            assert!(
+                matches!(error, MapiError::ConnectionError(ref e) if e == "timeout needs a MapiConnection")
+            );
+        });
+    }
+}
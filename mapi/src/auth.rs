@@ -0,0 +1,403 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Authentication against a MonetDB server.
+//!
+//! During login the server sends a challenge that contains a salt and the
+//! hash algorithms it supports. The client answers with the password, hashed
+//! with the algorithm the server uses to store passwords, and then hashed
+//! again together with the salt. An [`Authenticator`] computes that answer.
+use std::fmt::Write as fmtWrite;
+use std::path::{Path, PathBuf};
+use std::result;
+
+use crate::errors::MapiError;
+use crate::mapi::Endianness;
+use digest::DynDigest;
+use md5::Md5;
+use ripemd::Ripemd160;
+use sha1::Sha1;
+use sha2::{Sha224, Sha256, Sha384, Sha512};
+use sha3::{Sha3_224, Sha3_256, Sha3_384, Sha3_512};
+use zeroize::Zeroizing;
+
+type Result<T> = result::Result<T, MapiError>;
+
+/// The hash algorithms we know about, from the strongest to the weakest. This
+/// is also the order of preference when the server offers more than one.
+pub const SUPPORTED_ALGORITHMS: [&str; 11] = [
+    "SHA3-512",
+    "SHA512",
+    "SHA3-384",
+    "SHA384",
+    "SHA3-256",
+    "SHA256",
+    "SHA3-224",
+    "SHA224",
+    "RIPEMD160",
+    "SHA1",
+    "MD5",
+];
+
+/// Get a hasher for one of the `SUPPORTED_ALGORITHMS`.
+pub fn get_digest(algorithm: &str) -> Option<Box<dyn DynDigest>> {
+    match algorithm {
+        "SHA3-512" => Some(Box::new(Sha3_512::default())),
+        "SHA512" => Some(Box::new(Sha512::default())),
+        "SHA3-384" => Some(Box::new(Sha3_384::default())),
+        "SHA384" => Some(Box::new(Sha384::default())),
+        "SHA3-256" => Some(Box::new(Sha3_256::default())),
+        "SHA256" => Some(Box::new(Sha256::default())),
+        "SHA3-224" => Some(Box::new(Sha3_224::default())),
+        "SHA224" => Some(Box::new(Sha224::default())),
+        "RIPEMD160" => Some(Box::new(Ripemd160::default())),
+        "SHA1" => Some(Box::new(Sha1::default())),
+        "MD5" => Some(Box::new(Md5::default())),
+        _ => None,
+    }
+}
+
+/// The login challenge sent by the server.
+///
+/// It has the form `salt:identity:protocol:hashes:endianness:algorithm:`,
+/// possibly followed by options such as `sql=6` or `CLIENTINFO`.
+#[derive(Debug, PartialEq)]
+pub struct Challenge {
+    pub salt: String,
+    pub identity: String,
+    pub protocol: String,
+    /// The algorithms the server accepts for the salted hash.
+    pub hashes: Vec<String>,
+    pub endianness: Endianness,
+    /// The algorithm the server uses to store passwords.
+    pub algorithm: String,
+    /// Any further fields, e.g. `CLIENTINFO` if the server accepts
+    /// `Xclientinfo`.
+    pub options: Vec<String>,
+}
+
+impl Challenge {
+    /// Parse a challenge, returning `MapiError::UnknownServerResponse` if it
+    /// is malformed.
+    pub fn parse(challenge: &[u8]) -> Result<Challenge> {
+        let challenge = std::str::from_utf8(challenge)
+            .map_err(|_| MapiError::UnknownServerResponse("challenge: invalid UTF8".to_string()))?;
+        let mut iter = challenge.trim_end().split(':');
+        let mut next = |field: &str| match iter.next() {
+            Some(value) if !value.is_empty() => Ok(value.to_string()),
+            _ => Err(MapiError::UnknownServerResponse(format!(
+                "challenge: missing {} in {:?}",
+                field, challenge
+            ))),
+        };
+
+        let salt = next("salt")?;
+        let identity = next("server type")?;
+        let protocol = next("protocol version")?;
+        let hashes = next("hash list")?
+            .split_terminator(',')
+            .map(String::from)
+            .collect();
+        let endianness = match next("endianness")?.as_ref() {
+            "BIG" => Endianness::Big,
+            "LIT" => Endianness::Little,
+            e => {
+                return Err(MapiError::UnknownServerResponse(format!(
+                    "challenge: unknown endianness {}",
+                    e
+                )))
+            }
+        };
+        let algorithm = next("password hash algorithm")?;
+        let options = iter.filter(|o| !o.is_empty()).map(String::from).collect();
+
+        Ok(Challenge {
+            salt,
+            identity,
+            protocol,
+            hashes,
+            endianness,
+            algorithm,
+            options,
+        })
+    }
+}
+
+/// Computes the password part of the login response.
+///
+/// Implement this to use an authentication scheme other than the default
+/// salted password hash.
+pub trait Authenticator: Send {
+    /// Answer `challenge` for `password`. The result is sent verbatim as the
+    /// password field of the response, e.g. `{SHA512}<hex digest>`.
+    fn respond(&self, challenge: &Challenge, password: &str) -> Result<String>;
+}
+
+/// The standard MonetDB authentication: the password is hashed with the
+/// algorithm the server stores passwords with, and the hex digest together
+/// with the salt is hashed again with the strongest algorithm both sides
+/// support.
+pub struct HashAuthenticator {
+    preferences: Vec<String>,
+}
+
+impl HashAuthenticator {
+    /// Restrict the algorithms used for the salted hash to `preferences`, in
+    /// order of preference.
+    pub fn with_preferences(preferences: &[&str]) -> HashAuthenticator {
+        HashAuthenticator {
+            preferences: preferences.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+}
+
+impl Default for HashAuthenticator {
+    fn default() -> Self {
+        HashAuthenticator::with_preferences(&SUPPORTED_ALGORITHMS)
+    }
+}
+
+impl Authenticator for HashAuthenticator {
+    fn respond(&self, challenge: &Challenge, password: &str) -> Result<String> {
+        let hashed_passwd = hash_password(&challenge.algorithm, password)?;
+        let hashed_passwd = &hashed_passwd[challenge.algorithm.len() + 2..];
+
+        let (name, mut hasher) = self
+            .preferences
+            .iter()
+            .filter(|p| challenge.hashes.contains(p))
+            .find_map(|p| get_digest(p).map(|d| (p, d)))
+            .ok_or_else(|| {
+                MapiError::ConnectionError("No supported hash algorithm found".to_string())
+            })?;
+        hasher.update(hashed_passwd.as_bytes());
+        hasher.update(challenge.salt.as_bytes());
+        let salted_passwd = bytes_to_hex(&hasher.finalize_reset())?;
+
+        Ok(format!("{{{}}}{}", name, salted_passwd))
+    }
+}
+
+/// Hash `password` with `algorithm`, the way the server stores it, giving
+/// `{ALGORITHM}<hex digest>`.
+///
+/// A password that is already pre-hashed in that form is returned as is,
+/// provided it uses the same algorithm.
+pub fn hash_password(algorithm: &str, password: &str) -> Result<String> {
+    if let Some(prehashed) = prehashed_algorithm(password) {
+        return if prehashed == algorithm {
+            Ok(password.to_string())
+        } else {
+            Err(MapiError::ConnectionError(format!(
+                "Password is pre-hashed with {} but the server requires {}",
+                prehashed, algorithm
+            )))
+        };
+    }
+
+    let mut hasher = get_digest(algorithm).ok_or_else(|| {
+        MapiError::ConnectionError(format!(
+            "Server requested unsupported cryptographic algorithm {}",
+            algorithm
+        ))
+    })?;
+    hasher.update(password.as_bytes());
+    let digest = Zeroizing::new(hasher.finalize_reset());
+
+    Ok(format!("{{{}}}{}", algorithm, bytes_to_hex(&digest)?))
+}
+
+/// If `password` has the form `{ALGORITHM}<hex digest>` for one of the
+/// `SUPPORTED_ALGORITHMS`, return the algorithm.
+fn prehashed_algorithm(password: &str) -> Option<&str> {
+    let (algorithm, digest) = password.strip_prefix('{')?.split_once('}')?;
+    let size = get_digest(algorithm)?.output_size();
+    if digest.len() == 2 * size && digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Some(algorithm)
+    } else {
+        None
+    }
+}
+
+/// Supplies the password every time a connection logs in.
+///
+/// Closures returning the password implement this trait, so a keyring or
+/// vault lookup can be plugged in directly.
+pub trait CredentialProvider: Send {
+    fn password(&mut self) -> Result<String>;
+}
+
+impl<F> CredentialProvider for F
+where
+    F: FnMut() -> Result<String> + Send,
+{
+    fn password(&mut self) -> Result<String> {
+        self()
+    }
+}
+
+/// Reads the password from an environment variable.
+pub struct EnvCredentials {
+    variable: String,
+}
+
+impl EnvCredentials {
+    pub fn new(variable: &str) -> EnvCredentials {
+        EnvCredentials {
+            variable: variable.to_string(),
+        }
+    }
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn password(&mut self) -> Result<String> {
+        std::env::var(&self.variable).map_err(|e| {
+            MapiError::ConnectionError(format!(
+                "Cannot read password from ${}: {}",
+                self.variable, e
+            ))
+        })
+    }
+}
+
+/// Reads the password from the first line of a file.
+pub struct FileCredentials {
+    path: PathBuf,
+}
+
+impl FileCredentials {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileCredentials {
+        FileCredentials {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl CredentialProvider for FileCredentials {
+    fn password(&mut self) -> Result<String> {
+        let contents = Zeroizing::new(std::fs::read_to_string(&self.path)?);
+        Ok(contents.lines().next().unwrap_or("").to_string())
+    }
+}
+
+fn bytes_to_hex(bts: &[u8]) -> Result<String> {
+    let mut hex_str = String::with_capacity(2 * bts.len());
+    for byte in bts.iter() {
+        write!(hex_str, "{:02x}", byte)?;
+    }
+
+    Ok(hex_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_challenge() {
+        let challenge =
+            Challenge::parse(b"somesalt:mserver:9:RIPEMD160,SHA512,SHA256:LIT:SHA512:sql=6:")
+                .unwrap();
+        assert_eq!(challenge.salt, "somesalt");
+        assert_eq!(challenge.identity, "mserver");
+        assert_eq!(challenge.protocol, "9");
+        assert_eq!(challenge.hashes, vec!["RIPEMD160", "SHA512", "SHA256"]);
+        assert_eq!(challenge.endianness, Endianness::Little);
+        assert_eq!(challenge.algorithm, "SHA512");
+    }
+
+    #[test]
+    fn malformed_challenges() {
+        for c in [
+            &b""[..],
+            b"salt",
+            b"salt:mserver:9",
+            b"salt:mserver:9:SHA512:MIDDLE:SHA512:",
+            b"salt:mserver:9:SHA512:BIG::",
+            b"salt:mserver:9:SHA512:BIG:\xff:",
+        ] {
+            match Challenge::parse(c) {
+                Err(MapiError::UnknownServerResponse(_)) => (),
+                r => panic!("unexpected result for {:?}: {:?}", c, r),
+            }
+        }
+    }
+
+    fn respond(challenge: &[u8], authenticator: &dyn Authenticator) -> Result<String> {
+        authenticator.respond(&Challenge::parse(challenge)?, "monetdb")
+    }
+
+    #[test]
+    fn hashed_responses() {
+        let default = HashAuthenticator::default();
+        assert_eq!(
+            respond(b"somesalt:mserver:9:SHA1,SHA512:BIG:SHA512:", &default).unwrap(),
+            "{SHA512}f15f4633c278512d1d0a144f72e87f6b8542ba15dca446d395b262650db0c2d8d29682bcb5e7b9c082581540f1a4968398f6f21296942cfc6966da2a6426a8c7"
+        );
+        assert_eq!(
+            respond(b"somesalt:mserver:9:SHA256,SHA3-256:BIG:SHA512:", &default).unwrap(),
+            "{SHA3-256}ab46d3784e3ef04eca8f3273e47da14e562ba88d6173ee0b9ecc46b61aaa0888"
+        );
+
+        let legacy = HashAuthenticator::with_preferences(&["MD5"]);
+        assert_eq!(
+            respond(b"xyz:mserver:9:SHA512,MD5:BIG:SHA256:", &legacy).unwrap(),
+            "{MD5}e463a242c94c3fb1f8d8ab72db7842c8"
+        );
+    }
+
+    #[test]
+    fn prehashed_passwords() {
+        let default = HashAuthenticator::default();
+        let challenge = Challenge::parse(b"somesalt:mserver:9:SHA1,SHA512:BIG:SHA512:").unwrap();
+        let prehashed = hash_password("SHA512", "monetdb").unwrap();
+        assert!(prehashed.starts_with("{SHA512}"));
+        assert_eq!(hash_password("SHA512", &prehashed).unwrap(), prehashed);
+        assert_eq!(
+            default.respond(&challenge, &prehashed).unwrap(),
+            default.respond(&challenge, "monetdb").unwrap()
+        );
+
+        // Wrong algorithm for this server
+        let prehashed = hash_password("SHA256", "monetdb").unwrap();
+        assert!(default.respond(&challenge, &prehashed).is_err());
+
+        // Not a valid digest, so this is just an odd plain text password
+        assert_eq!(prehashed_algorithm("{SHA512}abc"), None);
+        assert_eq!(prehashed_algorithm("{FOO}abc"), None);
+        assert_eq!(prehashed_algorithm("{SHA256"), None);
+    }
+
+    #[test]
+    fn credential_providers() {
+        let mut calls = 0;
+        let mut closure = move || {
+            calls += 1;
+            Ok(format!("secret{}", calls))
+        };
+        assert_eq!(closure.password().unwrap(), "secret1");
+        assert_eq!(closure.password().unwrap(), "secret2");
+
+        std::env::set_var("MAPI_AUTH_TEST_PASSWORD", "fromenv");
+        let mut env = EnvCredentials::new("MAPI_AUTH_TEST_PASSWORD");
+        assert_eq!(env.password().unwrap(), "fromenv");
+        assert!(EnvCredentials::new("MAPI_AUTH_TEST_UNSET")
+            .password()
+            .is_err());
+
+        let path = std::env::temp_dir().join(format!("mapi-auth-{}", std::process::id()));
+        std::fs::write(&path, "fromfile\n").unwrap();
+        assert_eq!(FileCredentials::new(&path).password().unwrap(), "fromfile");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unsupported_algorithms() {
+        let default = HashAuthenticator::default();
+        assert!(respond(b"salt:mserver:9:SHA512:BIG:CRC32:", &default).is_err());
+        assert!(respond(b"salt:mserver:9:CRC32:BIG:SHA512:", &default).is_err());
+    }
+}
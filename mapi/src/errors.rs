@@ -75,6 +75,10 @@ pub enum MonetDBError {
     InvalidUrl(url::ParseError),
     UnimplementedError(String),
     ConnectionError(MapiError),
+    InvalidParameters(String),
+    ConversionError(String),
+    /// Statement number `.0` (counting from 0) of a script failed.
+    StatementError(usize, String),
 }
 
 impl fmt::Display for MonetDBError {
@@ -86,6 +90,9 @@ impl fmt::Display for MonetDBError {
             UnimplementedError(ref s) => {
                 write!(f, "MonetDBError: Unimplemented SQL functionality: {}", s)
             }
+            InvalidParameters(ref s) => write!(f, "MonetDBError: Invalid parameters: {}", s),
+            ConversionError(ref s) => write!(f, "MonetDBError: Conversion error: {}", s),
+            StatementError(i, ref s) => write!(f, "MonetDBError: Statement {} failed: {}", i, s),
         }
     }
 }
@@ -0,0 +1,404 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! The block framing of the MAPI protocol.
+//!
+//! A message is sent as a sequence of blocks of at most `BLOCK_SIZE` bytes.
+//! Every block starts with a 2 byte header: the length of the block shifted
+//! left by 1, with the lowest bit set on the last block of the message.
+//! Both sides write headers in their own byte order, and announce it during
+//! the login: the server in its challenge, the client in its response.
+use std::io::{self, IoSlice, Read, Write};
+use std::result;
+
+use crate::errors::MapiError;
+use crate::mapi::Endianness;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::trace;
+
+type Result<T> = result::Result<T, MapiError>;
+
+// MAPI Protocol version 9: Server and client exchange information in blocks of
+// 8094 bytes.
+pub const BLOCK_SIZE: usize = 8 * 1024 - 2;
+
+/// Write `message` as a sequence of blocks, and return the number of blocks.
+/// The last block is always sent, even if it is empty: that is how we answer
+/// the server's request for more input.
+///
+/// Header and payload of a block are written together. Nothing is flushed,
+/// that is up to the caller once the message is complete.
+pub fn write_message<W: Write>(
+    writer: &mut W,
+    message: &[u8],
+    endianness: Endianness,
+) -> io::Result<usize> {
+    let mut blocks = message.chunks(BLOCK_SIZE).peekable();
+    if blocks.peek().is_none() {
+        trace!(length = 0, last = true, "block sent");
+        let header = encode_header(0, true, endianness);
+        write_all_vectored(writer, &mut [IoSlice::new(&header)])?;
+        return Ok(1);
+    }
+    let mut count = 0;
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        trace!(length = block.len(), last, "block sent");
+        let header = encode_header(block.len(), last, endianness);
+        write_all_vectored(writer, &mut [IoSlice::new(&header), IoSlice::new(block)])?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Read a complete message with headers in `endianness`, appending it to
+/// `buff`. Returns the number of blocks.
+pub fn read_message<R: Read>(
+    reader: &mut R,
+    buff: &mut Vec<u8>,
+    endianness: Endianness,
+) -> Result<usize> {
+    let mut last = false;
+    let mut count = 0;
+    while !last {
+        let mut header_bytes = [0u8; 2];
+        read_exactly(&mut *reader, &mut header_bytes)?;
+        let header = decode_header(&header_bytes, endianness);
+        last = header & 1 == 1;
+        block_received((header >> 1) as usize, last);
+
+        // Read straight into the buffer
+        let start = buff.len();
+        buff.resize(start + (header >> 1) as usize, 0);
+        read_exactly(&mut *reader, &mut buff[start..])?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Read the challenge the server starts a login with, appending it to
+/// `buff`, and return the byte order of its header.
+///
+/// The challenge is always a single block, so its header has the last bit
+/// set and a length of at most `BLOCK_SIZE`. When that holds in both byte
+/// orders, the shorter length is read first: either it is the whole
+/// challenge, which then names that byte order in its endianness field, or
+/// it is a prefix of the longer one.
+pub fn read_challenge<R: Read>(reader: &mut R, buff: &mut Vec<u8>) -> Result<Endianness> {
+    let mut header = [0u8; 2];
+    read_exactly(&mut *reader, &mut header)?;
+    let (short, long) = challenge_lengths(&header)?;
+    let start = buff.len();
+    buff.resize(start + short.1, 0);
+    read_exactly(&mut *reader, &mut buff[start..])?;
+    match long {
+        Some(long) if declared_endianness(&buff[start..]) != Some(short.0) => {
+            block_received(long.1, true);
+            let read = buff.len();
+            buff.resize(start + long.1, 0);
+            read_exactly(&mut *reader, &mut buff[read..])?;
+            Ok(long.0)
+        }
+        _ => {
+            block_received(short.1, true);
+            Ok(short.0)
+        }
+    }
+}
+
+/// `write_message` for an asynchronous writer.
+#[cfg(feature = "tokio")]
+pub async fn write_message_async<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &[u8],
+    endianness: Endianness,
+) -> io::Result<usize> {
+    let mut blocks = message.chunks(BLOCK_SIZE).peekable();
+    if blocks.peek().is_none() {
+        trace!(length = 0, last = true, "block sent");
+        writer
+            .write_all(&encode_header(0, true, endianness))
+            .await?;
+        return Ok(1);
+    }
+    let mut count = 0;
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        trace!(length = block.len(), last, "block sent");
+        writer
+            .write_all(&encode_header(block.len(), last, endianness))
+            .await?;
+        writer.write_all(block).await?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// `read_message` for an asynchronous reader.
+#[cfg(feature = "tokio")]
+pub async fn read_message_async<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buff: &mut Vec<u8>,
+    endianness: Endianness,
+) -> Result<usize> {
+    let mut last = false;
+    let mut count = 0;
+    while !last {
+        let mut header_bytes = [0u8; 2];
+        reader
+            .read_exact(&mut header_bytes)
+            .await
+            .map_err(read_error)?;
+        let header = decode_header(&header_bytes, endianness);
+        last = header & 1 == 1;
+        block_received((header >> 1) as usize, last);
+
+        let start = buff.len();
+        buff.resize(start + (header >> 1) as usize, 0);
+        reader
+            .read_exact(&mut buff[start..])
+            .await
+            .map_err(read_error)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// `read_challenge` for an asynchronous reader.
+#[cfg(feature = "tokio")]
+pub async fn read_challenge_async<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buff: &mut Vec<u8>,
+) -> Result<Endianness> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await.map_err(read_error)?;
+    let (short, long) = challenge_lengths(&header)?;
+    let start = buff.len();
+    buff.resize(start + short.1, 0);
+    reader
+        .read_exact(&mut buff[start..])
+        .await
+        .map_err(read_error)?;
+    match long {
+        Some(long) if declared_endianness(&buff[start..]) != Some(short.0) => {
+            block_received(long.1, true);
+            let read = buff.len();
+            buff.resize(start + long.1, 0);
+            reader
+                .read_exact(&mut buff[read..])
+                .await
+                .map_err(read_error)?;
+            Ok(long.0)
+        }
+        _ => {
+            block_received(short.1, true);
+            Ok(short.0)
+        }
+    }
+}
+
+/// Trace a block that was received, from one call site for both messages
+/// and challenges.
+fn block_received(length: usize, last: bool) {
+    trace!(length, last, "block received");
+}
+
+type Candidate = (Endianness, usize);
+
+/// The byte orders and lengths for which `header` can be the header of a
+/// challenge, the shortest first. The second one is only there if its
+/// length differs.
+fn challenge_lengths(header: &[u8; 2]) -> Result<(Candidate, Option<Candidate>)> {
+    let mut candidates: Vec<Candidate> = [Endianness::Little, Endianness::Big]
+        .into_iter()
+        .map(|e| (e, decode_header(header, e)))
+        .filter(|(_, h)| h & 1 == 1 && (h >> 1) as usize <= BLOCK_SIZE)
+        .map(|(e, h)| (e, (h >> 1) as usize))
+        .collect();
+    candidates.sort_by_key(|(_, length)| *length);
+    match candidates[..] {
+        [] => Err(MapiError::UnknownServerResponse(format!(
+            "challenge: invalid block header {:02x?}",
+            header
+        ))),
+        [only] => Ok((only, None)),
+        [short, long] => Ok((short, Some(long).filter(|l| l.1 != short.1))),
+        _ => unreachable!(),
+    }
+}
+
+/// The byte order in the endianness field of (the start of) a challenge,
+/// if it is complete.
+fn declared_endianness(challenge: &[u8]) -> Option<Endianness> {
+    let mut fields = challenge.split(|b| *b == b':');
+    let field = fields.nth(4)?;
+    // Followed by a separator, so we have all of it
+    fields.next()?;
+    match field {
+        b"BIG" => Some(Endianness::Big),
+        b"LIT" => Some(Endianness::Little),
+        _ => None,
+    }
+}
+
+pub(crate) fn decode_header(bytes: &[u8; 2], endianness: Endianness) -> u16 {
+    match endianness {
+        Endianness::Big => u16::from_be_bytes(*bytes),
+        Endianness::Little => u16::from_le_bytes(*bytes),
+    }
+}
+
+fn encode_header(length: usize, last: bool, endianness: Endianness) -> [u8; 2] {
+    let header = ((length << 1) | last as usize) as u16;
+    match endianness {
+        Endianness::Big => header.to_be_bytes(),
+        Endianness::Little => header.to_le_bytes(),
+    }
+}
+
+/// `Write::write_all` for vectored writes, which is not stable yet.
+fn write_all_vectored<W: Write>(writer: &mut W, mut bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+    while !bufs.is_empty() {
+        match writer.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn read_exactly<R: Read>(mut stream: R, buff: &mut [u8]) -> Result<()> {
+    stream.read_exact(buff).map_err(read_error)
+}
+
+fn read_error(e: io::Error) -> MapiError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => {
+            MapiError::ConnectionError("Server closed the connection".to_string())
+        }
+        _ => MapiError::IOError(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: &[u8], endianness: Endianness) -> Vec<u8> {
+        let mut wire = vec![];
+        let sent = write_message(&mut wire, message, endianness).unwrap();
+        let mut received = vec![];
+        let blocks = read_message(&mut wire.as_slice(), &mut received, endianness).unwrap();
+        assert_eq!(blocks, sent);
+        assert_eq!(blocks, message.len().div_ceil(BLOCK_SIZE).max(1));
+        received
+    }
+
+    #[test]
+    fn blocks() {
+        for size in [0, 10, BLOCK_SIZE, 2 * BLOCK_SIZE + 10] {
+            let message: Vec<u8> = (0..size).map(|i| b'a' + (i % 26) as u8).collect();
+            for endianness in [Endianness::Little, Endianness::Big] {
+                assert_eq!(round_trip(&message, endianness), message);
+            }
+        }
+        assert_eq!(
+            encode_header(BLOCK_SIZE, false, Endianness::Big),
+            [0x3f, 0xfc]
+        );
+        assert_eq!(encode_header(0, true, Endianness::Little), [1, 0]);
+    }
+
+    /// A challenge of exactly `length` bytes that declares `endianness`.
+    fn challenge(length: usize, endianness: Endianness) -> Vec<u8> {
+        let mut challenge = format!("salt:mserver:9:SHA512:{}:SHA512:sql=6:", endianness);
+        let padding = length - challenge.len() - 1;
+        challenge.push_str(&"x".repeat(padding));
+        challenge.push(':');
+        challenge.into_bytes()
+    }
+
+    #[test]
+    fn byte_order_is_detected() {
+        // 140 and 384 bytes have headers that are valid challenge headers
+        // in both byte orders, and 20 is shorter than the endianness field
+        for length in [20, 40, 128, 140, 384, 1000, BLOCK_SIZE] {
+            for endianness in [Endianness::Little, Endianness::Big] {
+                let message = if length < 40 {
+                    vec![b'x'; length]
+                } else {
+                    challenge(length, endianness)
+                };
+                let mut wire = vec![];
+                write_message(&mut wire, &message, endianness).unwrap();
+                // Something the server sends after the challenge
+                wire.extend_from_slice(&[0xff; 16]);
+                let mut received = vec![];
+                let detected = read_challenge(&mut wire.as_slice(), &mut received).unwrap();
+                assert_eq!(received, message, "{} {}", length, endianness);
+                if length != 128 {
+                    assert_eq!(detected, endianness, "{}", length);
+                }
+            }
+        }
+
+        let mut wire = vec![];
+        write_message(&mut wire, &vec![b'x'; BLOCK_SIZE + 1], Endianness::Big).unwrap();
+        let result = read_challenge(&mut wire.as_slice(), &mut vec![]);
+        assert!(matches!(result, Err(MapiError::UnknownServerResponse(_))));
+    }
+
+    #[test]
+    fn truncated_messages() {
+        let mut wire = vec![];
+        write_message(&mut wire, b"hello", Endianness::Little).unwrap();
+        wire.truncate(4);
+        let result = read_message(&mut wire.as_slice(), &mut vec![], Endianness::Little);
+        assert!(matches!(result, Err(MapiError::ConnectionError(_))));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_framing() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let message = vec![b'y'; 2 * BLOCK_SIZE + 3];
+            let mut wire = vec![];
+            let sent = write_message_async(&mut wire, &message, Endianness::Big)
+                .await
+                .unwrap();
+            let mut expected = vec![];
+            write_message(&mut expected, &message, Endianness::Big).unwrap();
+            assert_eq!(wire, expected);
+
+            let mut received = vec![];
+            let blocks = read_message_async(&mut wire.as_slice(), &mut received, Endianness::Big)
+                .await
+                .unwrap();
+            assert_eq!(blocks, sent);
+            assert_eq!(received, message);
+
+            let challenge = b"salt:mserver:9:SHA512:BIG:SHA512:sql=6:".repeat(4);
+            let mut wire = vec![];
+            write_message(&mut wire, &challenge, Endianness::Big).unwrap();
+            let mut received = vec![];
+            let detected = read_challenge_async(&mut wire.as_slice(), &mut received)
+                .await
+                .unwrap();
+            assert_eq!((detected, received), (Endianness::Big, challenge));
+
+            wire.truncate(4);
+            let result =
+                read_message_async(&mut wire.as_slice(), &mut vec![], Endianness::Big).await;
+            assert!(matches!(result, Err(MapiError::ConnectionError(_))));
+        });
+    }
+}
@@ -0,0 +1,235 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Logging in and setting up the session, without the I/O, so that
+//! `MapiConnection` and `AsyncMapiConnection` only differ in how they send
+//! and receive.
+//!
+//! A login is a challenge from the server, our response, and the verdict of
+//! the server: we are in, we are not, or we have to answer a new challenge
+//! because monetdbd now proxies the connection to the database.
+use std::result;
+
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::auth::{Authenticator, CredentialProvider, HashAuthenticator};
+use crate::errors::MapiError;
+use crate::mapi::{
+    login_response, parse_challenge, parse_redirect, MapiConnection, MapiConnectionParams,
+    MapiLanguage, Redirect, ServerResponsePrompt, MAX_REDIRECTS,
+};
+use crate::session::SessionOptions;
+
+type Result<T> = result::Result<T, MapiError>;
+
+/// Who we log in as, and the state of the login.
+pub(crate) struct Handshake {
+    pub username: String,
+    pub password: Zeroizing<String>,
+    pub database: String,
+    pub language: MapiLanguage,
+    authenticator: Box<dyn Authenticator>,
+    credential_provider: Option<Box<dyn CredentialProvider>>,
+    session: SessionOptions,
+    // The number of challenges answered in the current login
+    challenges: u8,
+    // Whether the server announced that it accepts Xclientinfo.
+    client_info_supported: bool,
+}
+
+impl Handshake {
+    /// Take the credentials and session options out of `params`.
+    pub fn new(params: &mut MapiConnectionParams, language: MapiLanguage) -> Handshake {
+        Handshake {
+            username: params
+                .username
+                .take()
+                .unwrap_or_else(|| String::from("monetdb")),
+            password: Zeroizing::new(
+                params
+                    .password
+                    .take()
+                    .unwrap_or_else(|| String::from("monetdb")),
+            ),
+            database: params.database.clone(),
+            language,
+            authenticator: params
+                .authenticator
+                .take()
+                .unwrap_or_else(|| Box::<HashAuthenticator>::default()),
+            credential_provider: params.credential_provider.take(),
+            session: params.session.clone(),
+            challenges: 0,
+            client_info_supported: false,
+        }
+    }
+
+    /// Start a login, with a fresh password if there is a credential
+    /// provider.
+    pub fn begin(&mut self) -> Result<()> {
+        self.challenges = 0;
+        if let Some(ref mut provider) = self.credential_provider {
+            self.password = Zeroizing::new(provider.password()?);
+        }
+        Ok(())
+    }
+
+    /// The number of challenges answered so far in this login.
+    pub fn iteration(&self) -> u8 {
+        self.challenges
+    }
+
+    /// The response to `challenge`, and the same with the password redacted.
+    pub fn respond(&mut self, challenge: &[u8]) -> Result<(Vec<u8>, String)> {
+        self.challenges += 1;
+        let challenge = parse_challenge(challenge)?;
+        self.client_info_supported = challenge.options.iter().any(|o| o == "CLIENTINFO");
+        login_response(
+            &challenge,
+            self.authenticator.as_ref(),
+            &self.username,
+            &mut self.password,
+            self.credential_provider.is_some(),
+            &self.language,
+            &self.database,
+        )
+    }
+
+    /// Whether the server let us in with its `response` to ours. `false`
+    /// means that it sends a new challenge to answer.
+    pub fn accepted(&self, response: &[u8]) -> Result<bool> {
+        use crate::mapi::ServerResponsePrompt::*;
+
+        let (prompt, prompt_length): (ServerResponsePrompt, usize) =
+            MapiConnection::parse_prompt(response)?;
+        match prompt {
+            MsgPrompt | MsgOk => Ok(true),
+            MsgError => Err(MapiError::ConnectionError(format!(
+                "login: Server error: {}",
+                String::from_utf8_lossy(response)
+            ))),
+            MsgRedirect => match parse_redirect(&response[prompt_length..])? {
+                Redirect::Proxy if self.challenges > MAX_REDIRECTS => {
+                    Err(MapiError::ConnectionError(format!(
+                        "login: too many redirects ({})",
+                        self.challenges
+                    )))
+                }
+                Redirect::Proxy => Ok(false),
+                Redirect::Server(_) => Err(MapiError::UnimplementedError(
+                    "E03 (unimplemented redirect)".to_string(),
+                )),
+            },
+            _ => Err(MapiError::UnknownServerResponse(format!(
+                "login: server responded with {:?} during login",
+                prompt
+            ))),
+        }
+    }
+
+    /// Done logging in, whether it worked or not. A redirect to a proxy
+    /// means answering another challenge, so only now are we done with a
+    /// password that we can ask the credential provider for again.
+    pub fn end(&mut self) {
+        if self.credential_provider.is_some() {
+            self.password.zeroize();
+        }
+    }
+
+    /// The commands that set up an SQL session, in order.
+    pub fn session_commands(&self) -> Vec<String> {
+        let mut commands = vec![];
+        if self.language != MapiLanguage::Sql {
+            return commands;
+        }
+        if self.session.size_header {
+            commands.push(String::from("Xsizeheader 1"));
+        }
+        commands.extend(self.session.command());
+        if self.client_info_supported {
+            commands.extend(self.session.client_info.as_ref().map(|i| i.command()));
+        }
+        commands
+    }
+}
+
+/// The error in the `response` to a session command, if any. With several
+/// statements in the command it need not be the first response.
+pub(crate) fn session_error(response: &str) -> Result<()> {
+    match response.lines().find(|l| l.starts_with('!')) {
+        Some(line) => Err(MapiError::OperationError(line.to_string())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::ClientInfo;
+
+    fn handshake(session: SessionOptions) -> Handshake {
+        let mut params =
+            MapiConnectionParams::new("demo", "monetdb", Some("secret"), None, None, None);
+        params.session = session;
+        Handshake::new(&mut params, MapiLanguage::Sql)
+    }
+
+    #[test]
+    fn redirects() {
+        let mut handshake = handshake(SessionOptions::default());
+        let challenge = b"salt:merovingian:9:SHA512:LIT:SHA512:";
+        for _ in 0..MAX_REDIRECTS {
+            handshake.respond(challenge).unwrap();
+            let redirect = b"^mapi:merovingian://proxy?database=demo\n";
+            assert!(!handshake.accepted(redirect).unwrap());
+        }
+        handshake.respond(challenge).unwrap();
+        let error = handshake.accepted(b"^mapi:merovingian://proxy\n");
+        assert!(error
+            .unwrap_err()
+            .to_string()
+            .contains("too many redirects"));
+
+        handshake.begin().unwrap();
+        handshake.respond(challenge).unwrap();
+        assert!(handshake.accepted(b"").unwrap());
+        let error = handshake.accepted(b"!InvalidCredentialsException\n");
+        assert!(matches!(error, Err(MapiError::ConnectionError(_))));
+    }
+
+    #[test]
+    fn session_commands() {
+        let session = SessionOptions {
+            timezone: Some(0),
+            schema: None,
+            role: None,
+            client_info: Some(ClientInfo::default()),
+            size_header: true,
+        };
+        let mut handshake = handshake(session);
+        handshake
+            .respond(b"salt:mserver:9:SHA512:LIT:SHA512:")
+            .unwrap();
+        let commands = handshake.session_commands();
+        assert_eq!(
+            commands,
+            [
+                "Xsizeheader 1",
+                "sSET TIME ZONE INTERVAL '+00:00' HOUR TO MINUTE\n;"
+            ]
+        );
+
+        // Only servers that ask for it get the client info
+        handshake
+            .respond(b"salt:mserver:9:SHA512:LIT:SHA512:sql=6:CLIENTINFO:")
+            .unwrap();
+        assert!(handshake.session_commands()[2].starts_with("Xclientinfo "));
+
+        handshake.language = MapiLanguage::Mapi;
+        assert!(handshake.session_commands().is_empty());
+        assert!(session_error("&3 1 2\n!42000!no such schema\n").is_err());
+    }
+}
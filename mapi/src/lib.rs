@@ -4,6 +4,23 @@
 //
 // Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
 //
+#![deny(unsafe_code)]
 
+#[cfg(feature = "tokio")]
+pub mod async_mapi;
+pub mod auth;
+pub mod config;
 pub mod errors;
+pub mod framing;
+mod handshake;
 pub mod mapi;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(any(test, feature = "mock"))]
+#[doc(hidden)]
+pub mod mock;
+pub mod replay;
+pub mod session;
+mod sys;
+pub mod trace;
+pub mod transport;
@@ -1,24 +1,30 @@
 //! The implementation of the low level connection to MonetDB.
 use std::clone::Clone;
 use std::fmt;
-use std::fmt::Write as fmtWrite;
 use std::io;
-use std::io::Read;
-use std::io::Write;
-use std::net::Shutdown;
+use std::io::{BufReader, BufWriter, IoSlice, Read, Write};
 use std::net::TcpStream;
 #[cfg(target_family = "unix")]
 use std::os::unix::net::UnixStream;
 #[cfg(target_family = "unix")]
 use std::path::Path;
-use std::rc::Rc;
+use std::path::PathBuf;
 use std::result;
+use std::time::{Duration, Instant};
 
+use crate::auth::{hash_password, Authenticator, Challenge, CredentialProvider};
 use crate::errors::MapiError;
-use digest::DynDigest;
-use log::debug;
-use ripemd::Ripemd160;
-use sha2::{Sha256, Sha512};
+use crate::framing::{self, read_exactly, BLOCK_SIZE};
+use crate::handshake::{self, Handshake};
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, Observer};
+use crate::replay::{RecordingSocket, ReplaySocket, Transcript};
+use crate::session::SessionOptions;
+use crate::trace::{self, Direction, WireDump, REDACTED};
+use crate::transport::Transport;
+use tracing::field::Empty;
+use tracing::{debug, debug_span, info, info_span};
+use zeroize::{Zeroize, Zeroizing};
 
 /// This enum specifies the different languages that the protocol can handle.
 #[derive(PartialEq)]
@@ -38,15 +44,53 @@ impl fmt::Display for MapiLanguage {
     }
 }
 
+impl std::str::FromStr for MapiLanguage {
+    type Err = MapiError;
+
+    fn from_str(s: &str) -> Result<MapiLanguage> {
+        match s {
+            "sql" => Ok(MapiLanguage::Sql),
+            "mal" | "mapi" => Ok(MapiLanguage::Mapi),
+            "control" => Ok(MapiLanguage::Control),
+            _ => Err(MapiError::ConnectionError(format!(
+                "Unknown language: {}",
+                s
+            ))),
+        }
+    }
+}
+
 /// The connection parameters for a mapi connection.
 pub struct MapiConnectionParams {
     pub database: String,
     pub username: Option<String>,
+    /// The password, either in plain text or pre-hashed in the form
+    /// `{SHA512}<hex digest>`. Ignored if a `credential_provider` is given.
     pub password: Option<String>,
     pub language: Option<MapiLanguage>,
     pub hostname: Option<String>,
     pub port: Option<u16>,
     pub unix_socket: Option<String>,
+    /// Asked for the password every time we (re)connect.
+    pub credential_provider: Option<Box<dyn CredentialProvider>>,
+    /// How to answer the login challenge. Defaults to `HashAuthenticator`.
+    pub authenticator: Option<Box<dyn Authenticator>>,
+    /// Applied to SQL sessions after every (re)connect.
+    pub session: SessionOptions,
+    /// Append every message exchanged with the server to this file, see
+    /// `trace` for the format.
+    pub wire_dump: Option<PathBuf>,
+    /// Record everything exchanged with the server to a transcript in this
+    /// file, see `replay`. Pin `session.timezone` to be able to replay it.
+    pub record: Option<PathBuf>,
+    /// Talk to this instead of the server.
+    pub replay: Option<ReplaySocket>,
+    /// Give up on reads and writes that take longer than this, also during
+    /// login. See `MapiConnection::set_timeout`.
+    pub timeout: Option<Duration>,
+    /// Told about the messages, queries and errors on the connection.
+    #[cfg(feature = "metrics")]
+    pub observer: Option<Box<dyn Observer>>,
 }
 
 impl MapiConnectionParams {
@@ -71,97 +115,219 @@ impl MapiConnectionParams {
             hostname: Some(hostname.unwrap_or("localhost").to_string()),
             port: Some(port.unwrap_or(50000)),
             unix_socket: None,
+            credential_provider: None,
+            authenticator: None,
+            session: SessionOptions::default(),
+            wire_dump: None,
+            record: None,
+            replay: None,
+            timeout: None,
+            #[cfg(feature = "metrics")]
+            observer: None,
+        }
+    }
+}
+
+// Tell the observer of `$connection` about an event, if there is one.
+macro_rules! observe {
+    ($connection:expr, $observer:ident => $event:expr) => {
+        #[cfg(feature = "metrics")]
+        {
+            if let Some($observer) = $connection.observer.as_mut() {
+                $event;
+            }
+        }
+    };
+}
+
+// Room for one full block and its header: smaller messages are collected in
+// the buffer, full blocks are written straight from the message.
+const BUFFER_SIZE: usize = BLOCK_SIZE + 2;
+
+// Maximum number of redirects we follow during login before giving up.
+pub(crate) const MAX_REDIRECTS: u8 = 10;
+
+/// The byte order used for the block headers of the protocol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endianness {
+    Big,
+    Little,
+}
+
+impl Endianness {
+    /// The byte order of the machine we are running on.
+    pub fn native() -> Endianness {
+        if cfg!(target_endian = "big") {
+            Endianness::Big
+        } else {
+            Endianness::Little
         }
     }
 }
 
-// MAPI Protocol version 9: Server and client exchange information in blocks of
-// 8094 bytes.
-const BLOCK_SIZE: usize = 8 * 1024 - 2;
+impl fmt::Display for Endianness {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Endianness::Big => write!(f, "BIG"),
+            Endianness::Little => write!(f, "LIT"),
+        }
+    }
+}
 
 /// Low level connection to MonetDB. This struct implements the mapi protocol version 9.
 #[allow(dead_code)]
 pub struct MapiConnection {
     hostname: String,
-    username: String,
-    password: String,
-    database: String,
     port: u16,
-    language: MapiLanguage,
-    socket: MapiSocket,
+    // None for a transport given to connect_with_transport
+    address: Option<SocketAddress>,
+    socket: BufferedSocket,
     state: MapiConnectionState,
+    endianness: Endianness,
+    handshake: Handshake,
+    wire_dump: Option<WireDump>,
+    transcript: Option<Transcript>,
+    replay: Option<ReplaySocket>,
+    timeout: Option<Duration>,
+    #[cfg(feature = "metrics")]
+    observer: Option<Box<dyn Observer>>,
 }
 
 type Result<T> = result::Result<T, MapiError>;
 impl MapiConnection {
     /// Establish a mapi connection given a set of connection params.
     pub fn connect(params: MapiConnectionParams) -> Result<MapiConnection> {
-        let port = params.port.unwrap_or(50000);
-
-        #[cfg(target_family = "unix")]
-        let mut socket_path = params
-            .unix_socket
-            .unwrap_or_else(|| format!("/tmp/.s.monetdb.{}", port));
+        MapiConnection::establish(params, None)
+    }
 
-        let hostname = match params.hostname {
-            #[cfg(target_family = "unix")]
-            Some(h) => {
-                if h.starts_with('/') {
-                    socket_path = format!("{}/.s.monetdb.{}", h, port);
-                    None
-                } else {
-                    Some(format!("{}:{}", h, port))
-                }
-            }
-            #[cfg(not(target_family = "unix"))]
-            Some(h) => {
-                if h.starts_with('/') {
-                    None
-                } else {
-                    Some(format!("{}:{}", h, port))
-                }
-            }
-            None => Some(format!("localhost:{}", port)),
-        };
+    /// Like `connect`, but talk to the server over `transport` instead of a
+    /// socket opened for the host, port or Unix socket in `params`. The
+    /// connection cannot `reconnect`, as it cannot open another transport.
+    ///
+    /// Over a Unix domain socket, the server expects the byte `0` before the
+    /// login; send it before handing over the transport.
+    pub fn connect_with_transport<T: Transport + 'static>(
+        params: MapiConnectionParams,
+        transport: T,
+    ) -> Result<MapiConnection> {
+        MapiConnection::establish(params, Some(Box::new(transport)))
+    }
 
-        let lang = params.language.unwrap_or(MapiLanguage::Sql);
+    fn establish(
+        mut params: MapiConnectionParams,
+        transport: Option<Box<dyn Transport>>,
+    ) -> Result<MapiConnection> {
+        let port = params.port.unwrap_or(50000);
+        let lang = params.language.take().unwrap_or(MapiLanguage::Sql);
+        let span = info_span!(
+            "connect",
+            database = %params.database,
+            host = Empty,
+            port,
+            language = %lang
+        );
+        let _entered = span.enter();
 
-        let socket = match hostname.clone() {
-            Some(h) => MapiSocket::Tcp(TcpStream::connect(h)?),
-            #[cfg(target_family = "unix")]
-            None => {
-                let sbuf = [b'0'; 1];
-                let mut c = UnixStream::connect(Path::new(&socket_path))?;
-                // We need to send b'0' to initialize the connection
-                if lang != MapiLanguage::Control {
-                    c.write_all(&sbuf).unwrap();
-                }
-                MapiSocket::Unix(c)
+        let address = socket_address(params.hostname.as_deref(), port, &lang)?;
+        let hostname = match address {
+            SocketAddress::Tcp(ref h) => {
+                span.record("host", h.as_str());
+                h.clone()
             }
-            #[cfg(not(target_family = "unix"))]
-            None => {
-                return Err(MapiError::ConnectionError(
-                    "Hostname must be specified".to_string(),
-                ));
+            #[cfg(target_family = "unix")]
+            SocketAddress::Unix(ref path) => {
+                span.record("host", path.as_str());
+                String::from("localhost")
             }
         };
+        let wire_dump = match params.wire_dump.take() {
+            Some(path) => Some(WireDump::create(path)?),
+            None => None,
+        };
+        let transcript = match params.record.take() {
+            Some(path) => Some(Transcript::create(path)?),
+            None => None,
+        };
+        let (transport, address) = match (transport, &params.replay) {
+            // The user's transport takes the place of the socket
+            (Some(transport), None) => (transport, None),
+            _ => (
+                open_transport(&address, &lang, &params.replay)?,
+                Some(address),
+            ),
+        };
+        let socket = buffered(transport, &transcript, params.timeout)?;
         let mut connection = MapiConnection {
             socket,
-            language: lang,
-            hostname: hostname.unwrap_or_else(|| String::from("localhost")),
-            username: params.username.unwrap_or_else(|| String::from("monetdb")),
-            password: params.password.unwrap_or_else(|| String::from("monetdb")),
-            database: params.database,
+            hostname,
+            handshake: Handshake::new(&mut params, lang),
             port,
+            address,
             state: MapiConnectionState::StateInit,
+            // Until the server tells us otherwise assume the usual little
+            // endian headers.
+            endianness: Endianness::Little,
+            wire_dump,
+            transcript,
+            replay: params.replay,
+            timeout: params.timeout,
+            #[cfg(feature = "metrics")]
+            observer: params.observer,
         };
 
-        connection.login(0)?;
-        connection.state = MapiConnectionState::StateReady;
+        connection.start()?;
+        info!("connected");
 
         Ok(connection)
     }
 
+    /// Close the current socket and connect and log in to the server again.
+    ///
+    /// If a `CredentialProvider` was given it is consulted again for the
+    /// password.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let span = info_span!(
+            "reconnect",
+            database = %self.handshake.database,
+            host = %self.hostname
+        );
+        let _entered = span.enter();
+        let address = self.address.as_ref().ok_or_else(|| {
+            MapiError::ConnectionError(String::from(
+                "cannot reconnect over a transport given to connect_with_transport",
+            ))
+        })?;
+        let transport = open_transport(address, &self.handshake.language, &self.replay)?;
+        let _ = self.transport().shutdown();
+        self.socket = buffered(transport, &self.transcript, self.timeout)?;
+        self.state = MapiConnectionState::StateInit;
+        self.endianness = Endianness::Little;
+
+        self.start()
+    }
+
+    fn start(&mut self) -> Result<()> {
+        // Control over the local socket does not require authentication.
+        if !self.is_local_control() {
+            let started = Instant::now();
+            self.handshake.begin()?;
+            let logged_in = self.login();
+            self.handshake.end();
+            logged_in?;
+            let duration = started.elapsed();
+            debug!(duration_us = duration.as_micros() as u64, "logged in");
+            observe!(self, observer => observer.logged_in(duration));
+        }
+        self.state = MapiConnectionState::StateReady;
+
+        for command in self.handshake.session_commands() {
+            debug!("Session: {}", command);
+            handshake::session_error(&self.query(&command)?)?;
+        }
+
+        Ok(())
+    }
+
     /// Send a command to the server
     pub fn cmd(&mut self, operation: &str) -> Result<String> {
         use self::ServerResponsePrompt::*;
@@ -170,8 +336,21 @@ impl MapiConnection {
                 Err(MapiError::ConnectionError("Not connected".to_string()))
             }
             MapiConnectionState::StateReady => {
-                self.put_block(operation.as_bytes().to_vec())?;
-                let response = self.get_block()?;
+                let mut response = vec![];
+                self.exchange(operation, &mut response)?;
+
+                // The local control channel speaks plain text without prompts.
+                if self.is_local_control() {
+                    let response = String::from_utf8(response)?;
+                    return if let Some(rest) = response.strip_prefix("OK") {
+                        Ok(rest.trim().to_string())
+                    } else if response.starts_with('!') {
+                        Err(MapiError::OperationError(response))
+                    } else {
+                        Ok(response)
+                    };
+                }
+
                 let (prompt, prompt_length) = MapiConnection::parse_prompt(&response)?;
 
                 match prompt {
@@ -182,388 +361,547 @@ impl MapiConnection {
                     }
                     // Tell the server it's not getting anything more from us
                     MsgMore => self.cmd(""),
-                    MsgQ(p) => {
-                        match p {
-                            QResponse::Update => {
-                                // TODO: find a way to remove this clone
-                                for line in String::from_utf8(response.clone())?.lines() {
-                                    if line.starts_with('!') {
-                                        return Err(MapiError::OperationError(line.to_string()));
-                                    }
-                                }
-                                Ok(String::from_utf8(response)?)
+                    MsgQ(p) => match p {
+                        QResponse::Update => {
+                            let response = String::from_utf8(response)?;
+                            if let Some(line) = response.lines().find(|l| l.starts_with('!')) {
+                                return Err(MapiError::OperationError(line.to_string()));
                             }
-                            _ => Ok(String::from_utf8(response)?),
+                            Ok(response)
                         }
-                    }
+                        _ => Ok(String::from_utf8(response)?),
+                    },
                     MsgHeader => Ok(String::from_utf8(response)?),
                     MsgTuple => Ok(String::from_utf8(response)?),
+                    // monetdbd answers control commands with '=' prefixed lines
+                    MsgTupleNoSclice if self.handshake.language == MapiLanguage::Control => {
+                        Ok(String::from_utf8(response)?)
+                    }
                     MsgError => {
                         let er = String::from_utf8(response)?;
                         Err(MapiError::OperationError(er))
                     }
 
-                    _ => {
-                        return Err(MapiError::ConnectionError(format!(
-                            "E05 (cmd unimplemented handling of: {:?})",
-                            prompt
-                        )))
-                    }
+                    _ => Err(MapiError::ConnectionError(format!(
+                        "E05 (cmd unimplemented handling of: {:?})",
+                        prompt
+                    ))),
                 }
             }
         }
     }
 
-    fn login(&mut self, iteration: u8) -> Result<()> {
-        debug!("Starting login dance");
-        use self::ServerResponsePrompt::*;
+    /// Send a command and return the complete response without looking at
+    /// it. A command with several SQL statements gets the responses to all
+    /// of them, including any errors, concatenated.
+    pub fn query(&mut self, operation: &str) -> Result<String> {
+        let mut response = vec![];
+        self.query_into(operation, &mut response)?;
+        Ok(String::from_utf8(response)?)
+    }
 
-        let challenge = self.get_block()?;
-        debug!("Server sent: {}", String::from_utf8(challenge.clone())?);
-        let response = self.challenge_response(&challenge)?;
-        self.put_block(response)?;
-
-        let mut response = self.get_block()?;
-        let (prompt, prompt_length) = MapiConnection::parse_prompt(&response)?;
-
-        match prompt {
-            MsgPrompt => Ok(()), // Server is happy
-            MsgOk => Ok(()),     // Server is happy
-            MsgError => {
-                return Err(MapiError::ConnectionError(format!(
-                    "login: Server error: {}",
-                    String::from_utf8(response)?
-                )))
-            }
-            MsgRedirect => {
-                let redirect = response.split_off(prompt_length);
-                let mut iter = redirect.split(|x| *x == b':');
-                let prot = String::from_utf8_lossy(iter.nth(1).unwrap());
-                debug!("prot = {}", prot);
-                if prot == "merovingian" {
-                    debug!("Restarting authentication");
-                    self.login(iteration + 1)
-                } else if prot == "monetdb" {
-                    Err(MapiError::UnimplementedError(
-                        "E03 (unimplemented redirect)".to_string(),
-                    ))
-                } else {
-                    return Err(MapiError::ConnectionError(format!(
-                        "Unknown redirect: {}",
-                        String::from_utf8_lossy(redirect.as_ref())
-                    )));
-                }
-            }
-            _ => {
-                return Err(MapiError::UnknownServerResponse(format!(
-                    "login: server responded with {:?} during login",
-                    prompt
-                )))
-            }
+    /// Like `query`, but the response replaces the contents of `buffer`.
+    /// Reusing a buffer saves allocating one for every response.
+    pub fn query_into(&mut self, operation: &str, buffer: &mut Vec<u8>) -> Result<()> {
+        if let MapiConnectionState::StateInit = self.state {
+            return Err(MapiError::ConnectionError("Not connected".to_string()));
+        }
+        self.exchange(operation, buffer)?;
+        // Tell the server it's not getting anything more from us
+        while buffer == b"\x01\x02\n" {
+            self.exchange("", buffer)?;
         }
-    }
 
-    fn parse_prompt(bytes: &Vec<u8>) -> Result<(ServerResponsePrompt, usize)> {
-        use self::QResponse::*;
-        use self::ServerResponsePrompt::*;
-        use bytes::{Buf, IntoBuf};
+        Ok(())
+    }
 
-        let mut buf = bytes.into_buf();
+    /// Send all `operations` before reading any response, so that together
+    /// they cost a single round trip, and return the responses in order.
+    /// Like `query`, this does not look at the responses.
+    pub fn pipeline<S: AsRef<str>>(&mut self, operations: &[S]) -> Result<Vec<String>> {
+        if let MapiConnectionState::StateInit = self.state {
+            return Err(MapiError::ConnectionError("Not connected".to_string()));
+        }
+        let span = debug_span!("pipeline", commands = operations.len());
+        let _entered = span.enter();
 
-        if buf.remaining() == 0 {
-            Ok((MsgPrompt, 0))
-        } else {
-            let initial_byte = buf.get_u8();
-            if initial_byte == b'#' {
-                Ok((MsgInfo, 1))
-            } else if initial_byte == b'!' {
-                Ok((MsgError, 1))
-            } else if initial_byte == b'%' {
-                Ok((MsgHeader, 1))
-            } else if initial_byte == b'[' {
-                Ok((MsgTuple, 1))
-            } else if initial_byte == b'^' {
-                Ok((MsgRedirect, 1))
-            } else if initial_byte == 1 {
-                let byte = buf.get_u8();
-                if byte == 2 {
-                    let byte = buf.get_u8();
-                    if byte == b'\n' {
-                        Ok((MsgMore, 3))
-                    } else {
-                        Err(MapiError::UnknownServerResponse(format!(
-                            "parse_prompt: Invalid More prompt: \\1\\2{}",
-                            byte
-                        )))
-                    }
-                } else {
-                    Err(MapiError::UnknownServerResponse(format!(
-                        "parse_prompt: Invalid More prompt: \\1{}",
-                        byte
-                    )))
-                }
-            } else if initial_byte == b'&' {
-                let byte = buf.get_u8();
-                if byte == b'1' {
-                    Ok((MsgQ(Table), 2))
-                } else if byte == b'2' {
-                    Ok((MsgQ(Update), 2))
-                } else if byte == b'3' {
-                    Ok((MsgQ(Schema), 2))
-                } else if byte == b'4' {
-                    Ok((MsgQ(Trans), 2))
-                } else if byte == b'5' {
-                    Ok((MsgQ(Prepare), 2))
-                } else if byte == b'6' {
-                    Ok((MsgQ(Block), 2))
-                } else {
-                    Err(MapiError::UnknownServerResponse(format!(
-                        "parse_prompt: Invalid Q: &{}",
-                        byte
-                    )))
-                }
-            } else if initial_byte == b'=' {
-                if buf.get_u8() == b'O' && buf.get_u8() == b'K' {
-                    Ok((MsgOk, 3))
-                } else {
-                    Ok((MsgTupleNoSclice, 1))
-                }
-            } else {
-                Err(MapiError::UnknownServerResponse(format!(
-                    "parse_prompt: Invalid prompt: Byte[0] = {}",
-                    initial_byte
-                )))
-            }
+        for operation in operations {
+            self.put_block(operation.as_ref().as_bytes())?;
         }
+        let mut responses = Vec::with_capacity(operations.len());
+        for _ in operations {
+            let mut buffer = vec![];
+            self.read_message(&mut buffer)?;
+            #[cfg(feature = "metrics")]
+            self.observe_errors(&buffer);
+            responses.push(String::from_utf8(buffer)?);
+        }
+        Ok(responses)
     }
 
-    fn challenge_response(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
-        let mut iter = challenge.split(|x| *x == b':');
+    /// Send a command and read the response into `buffer`, as one span.
+    fn exchange(&mut self, operation: &str, buffer: &mut Vec<u8>) -> Result<()> {
+        let span = debug_span!(
+            "command",
+            database = %self.handshake.database,
+            host = %self.hostname,
+            command = %trace::summary(operation),
+            bytes = operation.len(),
+            response_bytes = Empty,
+            duration_us = Empty
+        );
+        let _entered = span.enter();
+        let started = Instant::now();
 
-        let salt = String::from_utf8_lossy(iter.next().unwrap());
-        let identity = String::from_utf8_lossy(iter.next().unwrap());
-        let protocol = String::from_utf8_lossy(iter.next().unwrap());
-        let hashes = String::from_utf8_lossy(iter.next().unwrap());
-        let _endianess = String::from_utf8_lossy(iter.next().unwrap()); // Unused for now
-        let algo = String::from_utf8_lossy(iter.next().unwrap());
-        let password = self.password.clone();
+        self.put_block(operation.as_bytes())?;
+        buffer.clear();
+        self.read_message(buffer)?;
 
-        if protocol != "9" {
-            return Err(MapiError::ConnectionError(format!(
-                "Unsupported protocol version: {}",
-                protocol
-            )));
+        let duration = started.elapsed();
+        span.record("response_bytes", buffer.len());
+        span.record("duration_us", duration.as_micros() as u64);
+        #[cfg(feature = "metrics")]
+        if self.observer.is_some() {
+            if self.handshake.language == MapiLanguage::Sql && operation.starts_with('s') {
+                observe!(self, observer => observer.query(duration));
+            }
+            self.observe_errors(buffer);
         }
+        Ok(())
+    }
 
-        if identity != "mserver" && identity != "merovingian" {
-            return Err(MapiError::ConnectionError(format!(
-                "Unknown server type: {}",
-                identity
-            )));
+    /// Tell the observer about the errors in `response`. An error can span
+    /// several lines, only the first one has the SQLSTATE.
+    #[cfg(feature = "metrics")]
+    fn observe_errors(&mut self, response: &[u8]) {
+        let mut previous_error = false;
+        for line in response.split(|b| *b == b'\n') {
+            let error = line.first() == Some(&b'!');
+            if error && !previous_error {
+                let line = String::from_utf8_lossy(line);
+                observe!(self, observer => observer.server_error(metrics::sqlstate(&line)));
+            }
+            previous_error = error;
         }
+    }
 
-        let mut algorithm = self.get_encoding_algorithm(&algo)?;
-
-        let hash_list: Vec<&str> = hashes.split_terminator(',').collect();
-        let hash_algo = self.get_hash_algorithm(hash_list)?;
-
-        let hasher = Rc::<dyn DynDigest>::get_mut(&mut algorithm)
-            .ok_or_else(|| MapiError::ConnectionError("Unavailable hash algorithm".to_string()))?;
-        let algo_string = hash_algo.0;
-        hasher.update(password.as_bytes());
-        let pw = hasher.finalize_reset();
-        let hashed_passwd = bytes_to_hex(pw.as_ref())?;
-
-        let mut algorithm = self.get_encoding_algorithm(&algo[..])?;
-        let hasher = Rc::<dyn DynDigest>::get_mut(&mut algorithm)
-            .ok_or_else(|| MapiError::ConnectionError("Unavailable hash algorithm".to_string()))?;
-        hasher.update(format!("{}{}", hashed_passwd, salt).as_bytes());
-        let spw = hasher.finalize_reset();
-        let salted_passwd = bytes_to_hex(spw.as_ref())?;
+    /// Use `observer` from now on, instead of the one in the connection
+    /// parameters.
+    #[cfg(feature = "metrics")]
+    pub fn set_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observer = Some(observer);
+    }
 
-        let ret = format!(
-            "BIG:{}:{}{}:{}:{}:",
-            self.username, algo_string, salted_passwd, self.language, self.database
-        );
+    /// Answer challenges until the server lets us in.
+    fn login(&mut self) -> Result<()> {
+        loop {
+            let span = debug_span!(
+                "login",
+                user = %self.handshake.username,
+                iteration = self.handshake.iteration()
+            );
+            let _entered = span.enter();
 
-        debug!("Response: {}", ret);
+            let challenge = self.read_challenge()?;
+            debug!(challenge = %String::from_utf8_lossy(&challenge), "challenge received");
+            let (mut response, redacted) = self.handshake.respond(&challenge)?;
+            let sent = self.send_block(&response, redacted.as_bytes());
+            response.zeroize();
+            sent?;
 
-        Ok(ret.as_bytes().to_vec())
+            let response = self.get_block()?;
+            #[cfg(feature = "metrics")]
+            self.observe_errors(&response);
+            if self.handshake.accepted(&response)? {
+                return Ok(());
+            }
+            debug!("Restarting authentication");
+        }
     }
 
-    fn get_encoding_algorithm(&self, algo: &str) -> Result<Rc<dyn DynDigest>> {
-        if algo == "SHA256" {
-            Ok(Rc::new(Sha256::default()))
-        } else if algo == "SHA512" {
-            Ok(Rc::new(Sha512::default()))
-        } else {
-            Err(MapiError::ConnectionError(format!(
-                "Server requested unsupported cryptographic algorithm {}",
-                algo
+    /// The kind of response the server sent, and the length of the prompt
+    /// that says so.
+    pub fn parse_prompt(bytes: &[u8]) -> Result<(ServerResponsePrompt, usize)> {
+        use self::QResponse::*;
+        use self::ServerResponsePrompt::*;
+
+        let invalid = |what: &str| {
+            Err(MapiError::UnknownServerResponse(format!(
+                "parse_prompt: Invalid {}: {:?}",
+                what,
+                String::from_utf8_lossy(&bytes[..bytes.len().min(3)])
             )))
+        };
+        match bytes {
+            [] => Ok((MsgPrompt, 0)),
+            [b'#', ..] => Ok((MsgInfo, 1)),
+            [b'!', ..] => Ok((MsgError, 1)),
+            [b'%', ..] => Ok((MsgHeader, 1)),
+            [b'[', ..] => Ok((MsgTuple, 1)),
+            [b'^', ..] => Ok((MsgRedirect, 1)),
+            [1, 2, b'\n', ..] => Ok((MsgMore, 3)),
+            [1, ..] => invalid("More prompt"),
+            [b'&', b'1', ..] => Ok((MsgQ(Table), 2)),
+            [b'&', b'2', ..] => Ok((MsgQ(Update), 2)),
+            [b'&', b'3', ..] => Ok((MsgQ(Schema), 2)),
+            [b'&', b'4', ..] => Ok((MsgQ(Trans), 2)),
+            [b'&', b'5', ..] => Ok((MsgQ(Prepare), 2)),
+            [b'&', b'6', ..] => Ok((MsgQ(Block), 2)),
+            [b'&', ..] => invalid("Q"),
+            [b'=', b'O', b'K', ..] => Ok((MsgOk, 3)),
+            [b'=', ..] => Ok((MsgTupleNoSclice, 1)),
+            [initial_byte, ..] => Err(MapiError::UnknownServerResponse(format!(
+                "parse_prompt: Invalid prompt: Byte[0] = {}",
+                initial_byte
+            ))),
         }
     }
 
-    fn get_hash_algorithm(&self, algs: Vec<&str>) -> Result<(String, Rc<dyn DynDigest>)> {
-        if algs.contains(&"SHA512") {
-            Ok(("{SHA512}".to_string(), Rc::new(Sha512::default())))
-        } else if algs.contains(&"SHA256") {
-            Ok(("{SHA256}".to_string(), Rc::new(Sha256::default())))
-        } else if algs.contains(&"RIPEMD160") {
-            Ok(("{RIPEMD160}".to_string(), Rc::new(Ripemd160::default())))
-        } else {
-            Err(MapiError::ConnectionError(
-                "No supported hash algorithm found".to_string(),
-            ))
+    /// Read the challenge, which also tells the byte order of the server.
+    fn read_challenge(&mut self) -> Result<Vec<u8>> {
+        let mut buff = vec![];
+        self.endianness = framing::read_challenge(&mut self.socket, &mut buff)?;
+        observe!(self, observer => observer.message_received(buff.len() + 2, 1));
+        if let Some(dump) = self.wire_dump.as_mut() {
+            dump.record(Direction::Received, &buff, self.endianness);
         }
+        Ok(buff)
     }
 
     fn get_block(&mut self) -> Result<Vec<u8>> {
-        use bytes::{Buf, IntoBuf};
         let mut buff = vec![];
-        if self.language == MapiLanguage::Control
-        // && local
-        {
-            // TODO: implement local control
-            return Err(MapiError::UnimplementedError("E01".to_string()));
+        self.read_message(&mut buff)?;
+        Ok(buff)
+    }
+
+    /// Read a complete message, appending it to `buff`.
+    fn read_message(&mut self, buff: &mut Vec<u8>) -> Result<()> {
+        let start = buff.len();
+        if self.is_local_control() {
+            // Local control does not use blocks: monetdbd closes the socket
+            // after it has sent its reply.
+            self.socket.read_to_end(buff)?;
+            observe!(self, observer => observer.message_received(buff.len() - start, 0));
         } else {
-            let mut last = false;
-            while !last {
-                // Header is 2 bytes: The length of the block (maximum value is
-                // BLOCK_SIZE) left shifted by 1. If this is the last block of
-                // the message then the LSB of the header is set.
-
-                // TODO: Need to control the endianess based on what the server sent
-                let header = get_bytes(&mut self.socket, 2)?.into_buf().get_u16_le();
-                let length = header >> 1;
-                if header & 1 == 1 {
-                    last = true;
-                }
-                let mut cbuff = get_bytes(&mut self.socket, length as u64)?;
-                buff.append(&mut cbuff);
+            #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+            let blocks = framing::read_message(&mut self.socket, buff, self.endianness)?;
+            observe!(self, observer => {
+                observer.message_received(buff.len() - start + 2 * blocks, blocks)
+            });
+        }
+        if let Some(dump) = self.wire_dump.as_mut() {
+            dump.record(Direction::Received, &buff[start..], self.endianness);
+        }
+        Ok(())
+    }
+
+    fn put_block(&mut self, message: &[u8]) -> Result<()> {
+        match self.wire_dump {
+            Some(_) => {
+                let text = String::from_utf8_lossy(message);
+                let dumped = trace::redact_sql(&text);
+                self.send_block(message, dumped.as_bytes())
             }
+            None => self.send_block(message, message),
         }
-        Ok(buff)
     }
 
-    fn put_block(&mut self, message: Vec<u8>) -> Result<()> {
-        if self.language == MapiLanguage::Control
-        // && local
-        {
-            // TODO: implement local control
-            return Err(MapiError::UnimplementedError(
-                "E02 (put_block local control language)".to_string(),
-            ));
+    /// Send `message`, but put `dumped` in the wire dump. That is where
+    /// secrets are kept out of the dump.
+    ///
+    /// We write headers in our own byte order, which is what the login
+    /// response advertises.
+    fn send_block(&mut self, message: &[u8], dumped: &[u8]) -> Result<()> {
+        if let Some(dump) = self.wire_dump.as_mut() {
+            dump.record(Direction::Sent, dumped, Endianness::native());
+        }
+        let local_control = self.is_local_control();
+        let writer = self.socket.get_mut();
+        #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+        let blocks = if local_control {
+            // Local control does not use blocks either.
+            writer.write_all(message)?;
+            0
         } else {
-            use bytes::BufMut;
-            let mut sl_start;
-            let mut sl_end = 0;
-            while sl_end + BLOCK_SIZE < message.len() {
-                sl_start = sl_end;
-                sl_end += BLOCK_SIZE;
-                let slice = &message[sl_start..sl_end];
-                let mut header = vec![];
-                header.put_u16_le((slice.len() << 1) as u16);
-                self.socket.write_all(header.as_slice())?;
-                self.socket.write_all(slice.as_ref())?;
-            }
+            framing::write_message(writer, message, Endianness::native())?
+        };
+        // Only now does the message go out, in as few packets as possible
+        writer.flush()?;
+        observe!(self, observer => observer.message_sent(message.len() + 2 * blocks, blocks));
+        Ok(())
+    }
 
-            if message.len() - sl_end > 0 {
-                sl_start = sl_end;
-                let slice = &message[sl_start..];
-                let mut header = vec![];
-                header.put_u16_le(((slice.len() << 1) + 1) as u16);
-                self.socket.write_all(header.as_slice())?;
-                self.socket.write_all(slice.as_ref())?;
-            }
+    fn transport(&mut self) -> &mut dyn Transport {
+        self.socket.get_mut().get_mut().as_mut()
+    }
+
+    fn is_local_control(&self) -> bool {
+        match self.address {
+            None | Some(SocketAddress::Tcp(_)) => false,
+            #[cfg(target_family = "unix")]
+            Some(SocketAddress::Unix(_)) => self.handshake.language == MapiLanguage::Control,
         }
+    }
+
+    /// Give up on reads and writes that take longer than `timeout`, or wait
+    /// forever if it is `None`. This lasts across reconnects.
+    ///
+    /// A command that timed out leaves the connection in an unknown state:
+    /// `reconnect` before sending the next one.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        set_timeouts(self.transport(), timeout)?;
+        self.timeout = timeout;
         Ok(())
     }
 
     pub fn close(&mut self) -> Result<()> {
-        match self.socket.shutdown(Shutdown::Both) {
+        match self.transport().shutdown() {
             Ok(()) => Ok(()),
             Err(e) => Err(MapiError::IOError(e)),
         }
     }
 }
 
-fn bytes_to_hex(bts: &[u8]) -> Result<String> {
-    let mut hex_str = String::with_capacity(2 * bts.len());
-    for byte in bts.iter() {
-        write!(hex_str, "{:02x}", byte)?;
-    }
-
-    Ok(hex_str)
-}
-
 pub fn get_bytes<R>(stream: R, limit: u64) -> Result<Vec<u8>>
 where
     R: io::Read,
 {
-    let mut buff = vec![];
-    let mut chunk = stream.take(limit);
-    let mut count = limit;
-    while count > 0 {
-        let mut cbuff = vec![];
-        let recv = chunk.read_to_end(&mut cbuff)?;
-        if recv == 0 {
-            return Err(MapiError::ConnectionError(
-                "Server closed the connection".to_string(),
-            ));
-        }
-        count -= recv as u64;
-        buff.append(&mut cbuff);
-    }
-
+    let mut buff = vec![0; limit as usize];
+    read_exactly(stream, &mut buff)?;
     Ok(buff)
 }
 
-enum MapiSocket {
-    Tcp(TcpStream),
-    #[cfg(target_family = "unix")]
-    Unix(UnixStream),
+/// Where the server sends us during login.
+#[derive(Debug, PartialEq)]
+pub enum Redirect {
+    /// Log in again on the same connection, which monetdbd now proxies to
+    /// the database.
+    Proxy,
+    /// Connect to another server, given as a `monetdb://` URL.
+    Server(String),
 }
 
-impl std::io::Read for MapiSocket {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match *self {
-            MapiSocket::Tcp(ref mut s) => s.read(buf),
-            #[cfg(target_family = "unix")]
-            MapiSocket::Unix(ref mut s) => s.read(buf),
-        }
+/// Parse a redirect, e.g. `mapi:merovingian://proxy?database=demo` or
+/// `mapi:monetdb://host:50000/demo`. Only the first one counts if the server
+/// sends several.
+pub fn parse_redirect(redirect: &[u8]) -> Result<Redirect> {
+    let unknown = || {
+        MapiError::UnknownServerResponse(format!(
+            "Unknown redirect: {}",
+            String::from_utf8_lossy(redirect)
+        ))
+    };
+    let redirect = std::str::from_utf8(redirect).map_err(|_| unknown())?;
+    let first = redirect.lines().next().unwrap_or_default().trim_end();
+    let (scheme, _) = first
+        .strip_prefix("mapi:")
+        .and_then(|r| r.split_once("://"))
+        .ok_or_else(unknown)?;
+    debug!("redirect = {}", first);
+    match scheme {
+        "merovingian" => Ok(Redirect::Proxy),
+        "monetdb" => Ok(Redirect::Server(first["mapi:".len()..].to_string())),
+        _ => Err(unknown()),
     }
 }
 
-impl std::io::Write for MapiSocket {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match *self {
-            MapiSocket::Tcp(ref mut s) => s.write(buf),
-            #[cfg(target_family = "unix")]
-            MapiSocket::Unix(ref mut s) => s.write(buf),
+/// Where the server for `hostname` and `port` listens. A hostname that is
+/// a directory means the Unix domain socket in that directory.
+pub(crate) fn socket_address(
+    hostname: Option<&str>,
+    port: u16,
+    language: &MapiLanguage,
+) -> Result<SocketAddress> {
+    match hostname {
+        #[cfg(target_family = "unix")]
+        Some(h) if h.starts_with('/') => {
+            // The control language talks to monetdbd, which listens on a
+            // differently named socket.
+            let socket_name = if *language == MapiLanguage::Control {
+                "merovingian"
+            } else {
+                "monetdb"
+            };
+            Ok(SocketAddress::Unix(format!(
+                "{}/.s.{}.{}",
+                h, socket_name, port
+            )))
+        }
+        #[cfg(not(target_family = "unix"))]
+        Some(h) if h.starts_with('/') => {
+            let _ = language;
+            Err(MapiError::ConnectionError(
+                "Hostname must be specified".to_string(),
+            ))
         }
+        Some(h) => Ok(SocketAddress::Tcp(format!("{}:{}", h, port))),
+        None => Ok(SocketAddress::Tcp(format!("localhost:{}", port))),
     }
-    fn flush(&mut self) -> io::Result<()> {
-        match *self {
-            MapiSocket::Tcp(ref mut s) => s.flush(),
-            #[cfg(target_family = "unix")]
-            MapiSocket::Unix(ref mut s) => s.flush(),
+}
+
+/// Parse the login challenge, and check that we can log in to the server
+/// that sent it.
+pub(crate) fn parse_challenge(challenge: &[u8]) -> Result<Challenge> {
+    let challenge = Challenge::parse(challenge)?;
+
+    if challenge.protocol != "9" {
+        return Err(MapiError::ConnectionError(format!(
+            "Unsupported protocol version: {}",
+            challenge.protocol
+        )));
+    }
+
+    if challenge.identity != "mserver" && challenge.identity != "merovingian" {
+        return Err(MapiError::ConnectionError(format!(
+            "Unknown server type: {}",
+            challenge.identity
+        )));
+    }
+
+    Ok(challenge)
+}
+
+/// The response to `challenge`, and the same with the password redacted.
+///
+/// Do not keep the plain text password around longer than needed: unless it
+/// can be requested again (`renewable`), in which case the caller clears it
+/// once logged in, it is replaced by the hashed version, which is enough to
+/// log in again.
+pub(crate) fn login_response(
+    challenge: &Challenge,
+    authenticator: &dyn Authenticator,
+    username: &str,
+    password: &mut Zeroizing<String>,
+    renewable: bool,
+    language: &MapiLanguage,
+    database: &str,
+) -> Result<(Vec<u8>, String)> {
+    let answer = Zeroizing::new(authenticator.respond(challenge, password)?);
+
+    if !renewable {
+        let hashed = hash_password(&challenge.algorithm, password);
+        password.zeroize();
+        if let Ok(hashed) = hashed {
+            *password = Zeroizing::new(hashed);
         }
     }
+
+    // The server reads our block headers in the byte order we advertise
+    let response = |password: &str| {
+        format!(
+            "{}:{}:{}:{}:{}:",
+            Endianness::native(),
+            username,
+            password,
+            language,
+            database
+        )
+    };
+    let redacted = response(REDACTED);
+    debug!(response = %redacted, "responding to challenge");
+
+    Ok((response(answer.as_str()).into_bytes(), redacted))
 }
 
-impl MapiSocket {
-    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
-        match *self {
-            MapiSocket::Tcp(ref s) => s.shutdown(how),
-            #[cfg(target_family = "unix")]
-            MapiSocket::Unix(ref s) => s.shutdown(how),
+/// Open a socket to the server at `address`, or connect to the replay if
+/// there is one.
+fn open_transport(
+    address: &SocketAddress,
+    language: &MapiLanguage,
+    replay: &Option<ReplaySocket>,
+) -> Result<Box<dyn Transport>> {
+    if let Some(replay) = replay {
+        return Ok(Box::new(replay.connect()?));
+    }
+    match address {
+        SocketAddress::Tcp(h) => {
+            let stream = TcpStream::connect(h)?;
+            // We flush complete messages ourselves, waiting for more
+            // data only delays them.
+            stream.set_nodelay(true)?;
+            Ok(Box::new(stream))
         }
+        #[cfg(target_family = "unix")]
+        SocketAddress::Unix(path) => {
+            let sbuf = [b'0'; 1];
+            let mut c = UnixStream::connect(Path::new(path))?;
+            // We need to send b'0' to initialize the connection
+            if *language != MapiLanguage::Control {
+                c.write_all(&sbuf)?;
+            }
+            Ok(Box::new(c))
+        }
+    }
+}
+
+/// Put the buffers on top of `transport`, recording to `transcript` if
+/// there is one.
+fn buffered(
+    transport: Box<dyn Transport>,
+    transcript: &Option<Transcript>,
+    timeout: Option<Duration>,
+) -> Result<BufferedSocket> {
+    let mut transport = match transcript {
+        Some(transcript) => Box::new(RecordingSocket::new(transport, transcript.clone())?),
+        None => transport,
+    };
+    if timeout.is_some() {
+        set_timeouts(&mut transport, timeout)?;
+    }
+    Ok(BufReader::with_capacity(
+        BUFFER_SIZE,
+        WriteBuffer(BufWriter::with_capacity(BUFFER_SIZE, transport)),
+    ))
+}
+
+fn set_timeouts<T: Transport + ?Sized>(transport: &mut T, timeout: Option<Duration>) -> Result<()> {
+    transport.set_read_timeout(timeout)?;
+    transport.set_write_timeout(timeout)?;
+    Ok(())
+}
+
+/// Where the server is listening.
+pub(crate) enum SocketAddress {
+    Tcp(String),
+    #[cfg(target_family = "unix")]
+    Unix(String),
+}
+
+/// A socket that buffers reads and writes. Writes go out when the socket is
+/// flushed, which `put_block` does once per message.
+type BufferedSocket = BufReader<WriteBuffer<Box<dyn Transport>>>;
+
+/// Buffers writes while passing reads through, so that a `BufReader` can be
+/// put on top of it.
+struct WriteBuffer<S: Write>(BufWriter<S>);
+
+impl<S: Write> WriteBuffer<S> {
+    fn get_mut(&mut self) -> &mut S {
+        self.0.get_mut()
+    }
+}
+
+impl<S: Read + Write> Read for WriteBuffer<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.get_mut().read(buf)
+    }
+}
+
+impl<S: Write> Write for WriteBuffer<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.0.write_vectored(bufs)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
     }
 }
 
+/// The kinds of response, as told by the first bytes of a message.
 #[derive(Debug)]
-enum ServerResponsePrompt {
+pub enum ServerResponsePrompt {
     MsgPrompt,
     MsgMore,
     MsgInfo,
@@ -576,8 +914,9 @@ enum ServerResponsePrompt {
     MsgOk,
 }
 
+/// The kinds of query response, `&1` to `&6`.
 #[derive(Debug)]
-enum QResponse {
+pub enum QResponse {
     Table,
     Update,
     Schema,
@@ -590,3 +929,545 @@ enum MapiConnectionState {
     StateReady,
     StateInit,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{read_block, write_block, MockServer};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// A `MockServer` for `sessions` sessions that answers every command
+    /// with an update of one row.
+    fn mock_server(
+        endianness: Endianness,
+        sessions: usize,
+    ) -> (u16, thread::JoinHandle<Vec<Vec<u8>>>) {
+        mock_server_with_challenge(MockServer::new(endianness).challenge, endianness, sessions)
+    }
+
+    /// `mock_server` that sends `challenge` with headers in `endianness`.
+    fn mock_server_with_challenge(
+        challenge: String,
+        endianness: Endianness,
+        sessions: usize,
+    ) -> (u16, thread::JoinHandle<Vec<Vec<u8>>>) {
+        MockServer {
+            challenge,
+            sessions,
+            default_response: String::from("&2 1 -1\n"),
+            ..MockServer::new(endianness)
+        }
+        .start()
+    }
+
+    fn mock_params(port: u16) -> MapiConnectionParams {
+        let mut params = MapiConnectionParams::new(
+            "demo",
+            "monetdb",
+            Some("monetdb"),
+            None,
+            Some("127.0.0.1"),
+            Some(port),
+        );
+        // Keep the server defaults, so that the only commands are our own
+        params.session = SessionOptions {
+            timezone: None,
+            schema: None,
+            role: None,
+            client_info: None,
+            size_header: false,
+        };
+        params
+    }
+
+    fn run_session(challenge: String, endianness: Endianness) {
+        let (port, server) = mock_server_with_challenge(challenge, endianness, 1);
+        let params = mock_params(port);
+        let mut connection = MapiConnection::connect(params).unwrap();
+        assert_eq!(connection.endianness, endianness);
+        let response = connection.cmd("sINSERT INTO foo VALUES (1);").unwrap();
+        assert_eq!(response, "&2 1 -1\n");
+        drop(connection);
+
+        let received = server.join().unwrap();
+        let login = String::from_utf8(received[0].clone()).unwrap();
+        // The client advertises its own byte order, not that of the server
+        assert!(login.starts_with(&format!("{}:monetdb:{{", Endianness::native())));
+        assert!(login.ends_with(":sql:demo:"));
+        assert_eq!(received[1], b"sINSERT INTO foo VALUES (1);");
+    }
+
+    /// `mock_params` for recording and replaying: the session time zone is
+    /// part of the transcript, so it must not depend on where we run.
+    fn replay_params(port: u16) -> MapiConnectionParams {
+        let mut params = mock_params(port);
+        params.session.timezone = Some(60);
+        params
+    }
+
+    #[test]
+    fn pipeline() {
+        // A server that only answers once it has all the commands
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let challenge = default_challenge(Endianness::Little);
+            write_block(&mut stream, challenge.as_bytes(), Endianness::Little);
+            read_block(&mut stream, Endianness::native()).unwrap();
+            write_block(&mut stream, b"", Endianness::Little);
+            let commands: Vec<Vec<u8>> = (0..3)
+                .map(|_| read_block(&mut stream, Endianness::native()).unwrap())
+                .collect();
+            for response in ["", "!42000!no such result set\n", ""] {
+                write_block(&mut stream, response.as_bytes(), Endianness::Little);
+            }
+            commands
+        });
+
+        let mut params = mock_params(port);
+        // Fail instead of hanging if we wait for a response too early
+        params.timeout = Some(Duration::from_secs(5));
+        let mut connection = MapiConnection::connect(params).unwrap();
+        let responses = connection
+            .pipeline(&["Xclose 1", "Xclose 2", "Xclose 3"])
+            .unwrap();
+        assert_eq!(responses, vec!["", "!42000!no such result set\n", ""]);
+        drop(connection);
+        assert_eq!(
+            server.join().unwrap(),
+            vec![b"Xclose 1", b"Xclose 2", b"Xclose 3"]
+        );
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("mapi-transcript-{}", std::process::id()));
+        let (port, server) = mock_server(Endianness::Big, 2);
+        let mut params = replay_params(port);
+        params.record = Some(path.clone());
+        let mut connection = MapiConnection::connect(params).unwrap();
+        let recorded = connection.cmd("sINSERT INTO foo VALUES (1);").unwrap();
+        connection.reconnect().unwrap();
+        connection.cmd("sSELECT 1;").unwrap();
+        drop(connection);
+        let received = server.join().unwrap();
+        assert_eq!(
+            received[1],
+            b"sSET TIME ZONE INTERVAL '+01:00' HOUR TO MINUTE\n;"
+        );
+
+        // No server this time
+        let replay = ReplaySocket::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut params = replay_params(port);
+        params.replay = Some(replay.clone());
+        let mut connection = MapiConnection::connect(params).unwrap();
+        assert_eq!(connection.endianness, Endianness::Big);
+        let replayed = connection.cmd("sINSERT INTO foo VALUES (1);").unwrap();
+        assert_eq!(replayed, recorded);
+        connection.reconnect().unwrap();
+        assert!(!replay.is_finished());
+        let error = connection.cmd("sSELECT 2;").unwrap_err();
+        assert!(error.to_string().contains("sSELECT 2;"), "{}", error);
+    }
+
+    /// A transport the connection knows nothing about.
+    struct Tunnel(TcpStream);
+
+    impl Read for Tunnel {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Tunnel {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Transport for Tunnel {}
+
+    #[test]
+    fn custom_transport() {
+        let (port, server) = mock_server(Endianness::Little, 1);
+        let tunnel = Tunnel(TcpStream::connect(("127.0.0.1", port)).unwrap());
+        // Nothing listens here, the tunnel is used instead
+        let params = mock_params(1);
+        let mut connection = MapiConnection::connect_with_transport(params, tunnel).unwrap();
+        let response = connection.cmd("sINSERT INTO foo VALUES (1);").unwrap();
+        assert_eq!(response, "&2 1 -1\n");
+        assert!(connection.set_timeout(None).is_err());
+        assert!(connection.reconnect().is_err());
+        connection.close().unwrap();
+        drop(connection);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[1], b"sINSERT INTO foo VALUES (1);");
+    }
+
+    #[test]
+    fn timeouts() {
+        // A server that never says anything
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut params = mock_params(listener.local_addr().unwrap().port());
+        params.timeout = Some(Duration::from_millis(50));
+        let started = Instant::now();
+        let result = MapiConnection::connect(params);
+        assert!(matches!(result, Err(MapiError::IOError(_))));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let (port, server) = mock_server(Endianness::Little, 1);
+        let mut connection = MapiConnection::connect(mock_params(port)).unwrap();
+        connection
+            .set_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        connection.set_timeout(None).unwrap();
+        assert!(connection.set_timeout(Some(Duration::ZERO)).is_err());
+        drop(connection);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn large_messages_are_split() {
+        let (port, server) = mock_server(Endianness::Little, 1);
+        let mut connection = MapiConnection::connect(mock_params(port)).unwrap();
+        for size in [BLOCK_SIZE, 2 * BLOCK_SIZE + 10] {
+            let command: Vec<u8> = (0..size).map(|i| b'a' + (i % 26) as u8).collect();
+            connection.put_block(&command).unwrap();
+            connection.get_block().unwrap();
+        }
+        drop(connection);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[1].len(), BLOCK_SIZE);
+        assert_eq!(received[2].len(), 2 * BLOCK_SIZE + 10);
+        assert!(received[2].ends_with(b"ghij"));
+    }
+
+    #[test]
+    fn malformed_prompts() {
+        for response in [
+            &b"&"[..],
+            b"&9",
+            b"\x01",
+            b"\x01\x02",
+            b"\x01\x02x",
+            b"x",
+            b"\xff",
+        ] {
+            match MapiConnection::parse_prompt(response) {
+                Err(MapiError::UnknownServerResponse(_)) => (),
+                r => panic!("unexpected result for {:?}: {:?}", response, r),
+            }
+        }
+        assert!(matches!(
+            MapiConnection::parse_prompt(b"="),
+            Ok((ServerResponsePrompt::MsgTupleNoSclice, 1))
+        ));
+        assert!(matches!(
+            MapiConnection::parse_prompt(b"=OK"),
+            Ok((ServerResponsePrompt::MsgOk, 3))
+        ));
+    }
+
+    #[test]
+    fn redirects() {
+        assert_eq!(
+            parse_redirect(b"mapi:merovingian://proxy?database=demo\n").unwrap(),
+            Redirect::Proxy
+        );
+        assert_eq!(
+            parse_redirect(b"mapi:monetdb://db.example.com:50001/demo\n^mapi:x\n").unwrap(),
+            Redirect::Server(String::from("monetdb://db.example.com:50001/demo"))
+        );
+        for redirect in [&b""[..], b"mapi", b"mapi:ftp://x", b"merovingian", b"\xff"] {
+            match parse_redirect(redirect) {
+                Err(MapiError::UnknownServerResponse(_)) => (),
+                r => panic!("unexpected result for {:?}: {:?}", redirect, r),
+            }
+        }
+    }
+
+    /// Collects what a `tracing_subscriber` writes.
+    #[derive(Clone, Default)]
+    struct Captured(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn secrets_are_not_traced() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_writer(move || writer.clone())
+            .finish();
+        let dump_path = std::env::temp_dir().join(format!("mapi-dump-{}", std::process::id()));
+        let server = tracing::subscriber::with_default(subscriber, || {
+            let (port, server) = mock_server(Endianness::Little, 1);
+            let mut params = mock_params(port);
+            params.password = Some(String::from("hunter2"));
+            params.wire_dump = Some(dump_path.clone());
+            let mut connection = MapiConnection::connect(params).unwrap();
+            connection
+                .cmd("sALTER USER \"me\" SET PASSWORD 'hunter3' USING OLD PASSWORD 'hunter2'\n;")
+                .unwrap();
+            server
+        });
+        let received = server.join().unwrap();
+        assert!(String::from_utf8_lossy(&received[1]).contains("hunter3"));
+
+        let traced = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let dumped = std::fs::read(&dump_path).unwrap();
+        std::fs::remove_file(&dump_path).unwrap();
+        let dumped = String::from_utf8_lossy(&dumped);
+        for output in [&traced[..], &dumped[..]] {
+            assert!(output.contains(REDACTED), "{}", output);
+            assert!(!output.contains("hunter"), "{}", output);
+            assert!(!output.contains("{SHA512}"), "{}", output);
+        }
+        // Not "block sent": the mock server sends first, from a thread
+        // without a subscriber, and tracing caches that it is not wanted.
+        for expected in [
+            "connect",
+            "login",
+            "command",
+            "block received",
+            "database=demo",
+        ] {
+            assert!(traced.contains(expected), "no {} in {}", expected, traced);
+        }
+    }
+
+    fn default_challenge(endianness: Endianness) -> String {
+        format!("somesalt:mserver:9:SHA512:{}:SHA512:sql=6:", endianness)
+    }
+
+    #[test]
+    fn little_endian_server() {
+        run_session(default_challenge(Endianness::Little), Endianness::Little);
+    }
+
+    #[test]
+    fn big_endian_server() {
+        run_session(default_challenge(Endianness::Big), Endianness::Big);
+    }
+
+    #[test]
+    fn challenge_lengths() {
+        // The header of a 140 byte challenge is also a valid header in the
+        // other byte order, that of a 20 byte one only in its own
+        for endianness in [Endianness::Little, Endianness::Big] {
+            let mut long = default_challenge(endianness);
+            long.insert_str(0, &"s".repeat(140 - long.len()));
+            run_session(long, endianness);
+
+            // Too short to log in with, but read in full all the same
+            let short = format!("s:mserver:9:MD5:{}:", endianness);
+            assert_eq!(short.len(), 20);
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                write_block(&mut stream, short.as_bytes(), endianness);
+                read_block(&mut stream, Endianness::native())
+            });
+            let error = match MapiConnection::connect(mock_params(port)) {
+                Err(error) => error,
+                Ok(_) => panic!("logged in with {:?}", endianness),
+            };
+            let expected = format!(
+                "missing password hash algorithm in \"s:mserver:9:MD5:{}:\"",
+                endianness
+            );
+            assert!(error.to_string().contains(&expected), "{}", error);
+            assert_eq!(server.join().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn password_is_not_kept() {
+        let (port, server) = mock_server(Endianness::Little, 2);
+        let mut connection = MapiConnection::connect(mock_params(port)).unwrap();
+        assert!(connection.handshake.password.starts_with("{SHA512}"));
+        connection.cmd("sSELECT 1;").unwrap();
+        connection.reconnect().unwrap();
+        connection.cmd("sSELECT 1;").unwrap();
+        drop(connection);
+
+        // Logging in with the hashed password gives the same response
+        let received = server.join().unwrap();
+        assert_eq!(received[0], received[2]);
+    }
+
+    #[test]
+    fn credential_provider_on_reconnect() {
+        let (port, server) = mock_server(Endianness::Little, 2);
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        let mut params = mock_params(port);
+        params.password = None;
+        params.credential_provider = Some(Box::new(move || {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(String::from("monetdb"))
+        }));
+
+        let mut connection = MapiConnection::connect(params).unwrap();
+        assert!(connection.handshake.password.is_empty());
+        connection.cmd("sSELECT 1;").unwrap();
+        connection.reconnect().unwrap();
+        connection.cmd("sSELECT 1;").unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+        drop(connection);
+
+        let received = server.join().unwrap();
+        let login = String::from_utf8(received[0].clone()).unwrap();
+        assert!(login.contains(":{SHA512}f15f4633c278512d1d0a144f72e87f6b8542ba15dca446d395b262650db0c2d8d29682bcb5e7b9c082581540f1a4968398f6f21296942cfc6966da2a6426a8c7:"));
+        assert_eq!(received[0], received[2]);
+    }
+
+    #[test]
+    fn credential_provider_behind_proxy() {
+        // monetdbd asks for the password, then has the client log in again
+        // to the proxied server
+        let (port, server) = MockServer {
+            proxied: true,
+            ..MockServer::new(Endianness::Little)
+        }
+        .start();
+        let mut params = mock_params(port);
+        params.password = None;
+        params.credential_provider = Some(Box::new(|| Ok(String::from("monetdb"))));
+
+        let connection = MapiConnection::connect(params).unwrap();
+        assert!(connection.handshake.password.is_empty());
+        drop(connection);
+
+        let logins = server.join().unwrap();
+        let login = String::from_utf8(logins[0].clone()).unwrap();
+        assert!(login.contains(":{SHA512}f15f4633c278512d1d0a144f72e87f6b8542ba15dca446d395b262650db0c2d8d29682bcb5e7b9c082581540f1a4968398f6f21296942cfc6966da2a6426a8c7:"));
+        assert_eq!(logins[0], logins[1]);
+    }
+
+    #[test]
+    fn session_options_on_reconnect() {
+        let (port, server) = mock_server(Endianness::Little, 2);
+        let mut params = mock_params(port);
+        params.session = SessionOptions {
+            timezone: Some(120),
+            schema: Some(String::from("sales")),
+            role: None,
+            client_info: Some(crate::session::ClientInfo {
+                application_name: Some(String::from("tests")),
+                remark: None,
+            }),
+            size_header: true,
+        };
+
+        let mut connection = MapiConnection::connect(params).unwrap();
+        connection.reconnect().unwrap();
+        drop(connection);
+
+        let received: Vec<String> = server
+            .join()
+            .unwrap()
+            .into_iter()
+            .map(|r| String::from_utf8(r).unwrap())
+            .collect();
+        assert_eq!(received.len(), 8);
+        for session in received.chunks(4) {
+            assert!(session[0].ends_with(":sql:demo:"));
+            assert_eq!(session[1], "Xsizeheader 1");
+            assert_eq!(
+                session[2],
+                "sSET TIME ZONE INTERVAL '+02:00' HOUR TO MINUTE;\nSET SCHEMA \"sales\"\n;"
+            );
+            assert!(session[3].starts_with("Xclientinfo "));
+            assert!(session[3].contains("\nApplicationName=tests\n"));
+        }
+    }
+
+    /// Writes down the events it is told about, without the durations.
+    #[cfg(feature = "metrics")]
+    #[derive(Clone, Default)]
+    struct Events(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    #[cfg(feature = "metrics")]
+    impl Observer for Events {
+        fn message_sent(&mut self, bytes: usize, blocks: usize) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("sent {} {}", bytes, blocks));
+        }
+        fn message_received(&mut self, bytes: usize, blocks: usize) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("received {} {}", bytes, blocks));
+        }
+        fn logged_in(&mut self, _duration: std::time::Duration) {
+            self.0.lock().unwrap().push(String::from("logged in"));
+        }
+        fn query(&mut self, _duration: std::time::Duration) {
+            self.0.lock().unwrap().push(String::from("query"));
+        }
+        fn server_error(&mut self, sqlstate: Option<&str>) {
+            self.0.lock().unwrap().push(format!("error {:?}", sqlstate));
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn observer() {
+        let (port, server) = MockServer {
+            challenge: String::from("somesalt:mserver:9:SHA512:LIT:SHA512:"),
+            responses: vec![
+                String::from("!42000!syntax error\n!42000!in: x\n&2 0 -1\n!no state\n"),
+                "[".repeat(BLOCK_SIZE + 1),
+            ],
+            ..MockServer::new(Endianness::Little)
+        }
+        .start();
+
+        let events = Events::default();
+        let mut params = mock_params(port);
+        params.observer = Some(Box::new(events.clone()));
+        let mut connection = MapiConnection::connect(params).unwrap();
+        let query = "sSELECT x;";
+        assert!(connection.cmd(query).is_err());
+        connection.cmd("Xreply_size 10").unwrap();
+        drop(connection);
+        server.join().unwrap();
+
+        let login = 2 + format!("LIT:monetdb:{{SHA512}}{}:sql:demo:", "0".repeat(128)).len();
+        let expected = [
+            String::from("received 39 1"),
+            format!("sent {} 1", login),
+            String::from("received 2 1"),
+            String::from("logged in"),
+            format!("sent {} 1", query.len() + 2),
+            String::from("received 53 1"),
+            String::from("query"),
+            String::from("error Some(\"42000\")"),
+            String::from("error None"),
+            format!("sent {} 1", "Xreply_size 10".len() + 2),
+            format!("received {} 2", BLOCK_SIZE + 1 + 4),
+        ];
+        assert_eq!(*events.0.lock().unwrap(), expected);
+    }
+}
@@ -0,0 +1,81 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Hooks for connection and query statistics, with the `metrics` feature.
+//!
+//! An `Observer` set on a `MapiConnection`, either in the connection
+//! parameters or with `set_observer`, is told about every message exchanged
+//! with the server. What to count, and where to export it, is up to the
+//! observer.
+//!
+//! ```
+//! use mapi::metrics::Observer;
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use std::sync::Arc;
+//!
+//! #[derive(Clone, Default)]
+//! struct BytesSent(Arc<AtomicUsize>);
+//!
+//! impl Observer for BytesSent {
+//!     fn message_sent(&mut self, bytes: usize, _blocks: usize) {
+//!         self.0.fetch_add(bytes, Ordering::Relaxed);
+//!     }
+//! }
+//! ```
+use std::time::Duration;
+
+/// Notified of the events on a connection. Every method does nothing by
+/// default, so implement only those you need.
+///
+/// The methods are called on the thread that uses the connection, while it
+/// waits: keep them quick.
+pub trait Observer: Send {
+    /// A message of `bytes` bytes, block headers included, was sent in
+    /// `blocks` blocks. The local control socket does not use blocks.
+    fn message_sent(&mut self, _bytes: usize, _blocks: usize) {}
+
+    /// Like `message_sent`, for a message from the server.
+    fn message_received(&mut self, _bytes: usize, _blocks: usize) {}
+
+    /// The server accepted our login after `duration`, counting from the
+    /// challenge and including any redirects.
+    fn logged_in(&mut self, _duration: Duration) {}
+
+    /// The response to an SQL query was complete after `duration`.
+    fn query(&mut self, _duration: Duration) {}
+
+    /// The server reported an error, with this SQLSTATE if it sent one.
+    fn server_error(&mut self, _sqlstate: Option<&str>) {}
+}
+
+/// The SQLSTATE of an error message, e.g. `42000` for
+/// `!42000!syntax error...`. The leading `!` is optional.
+pub fn sqlstate(message: &str) -> Option<&str> {
+    let message = message.strip_prefix('!').unwrap_or(message);
+    let (code, _) = message.split_once('!')?;
+    if code.len() == 5 && code.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        Some(code)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sqlstates() {
+        assert_eq!(sqlstate("!42000!syntax error"), Some("42000"));
+        assert_eq!(sqlstate("M0M29!duplicate key"), Some("M0M29"));
+        assert_eq!(sqlstate("!syntax error!"), None);
+        assert_eq!(
+            sqlstate("!InvalidCredentialsException:checkCredentials"),
+            None
+        );
+        assert_eq!(sqlstate(""), None);
+    }
+}
@@ -0,0 +1,105 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! A scripted MAPI server for unit tests, here and in the crates on top of
+//! this one. Enabled with the `mock` feature.
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use crate::framing;
+use crate::mapi::Endianness;
+
+/// Write `message` to `stream` in blocks with headers in `endianness`.
+pub fn write_block(stream: &mut TcpStream, message: &[u8], endianness: Endianness) {
+    framing::write_message(stream, message, endianness).unwrap();
+}
+
+/// Read a complete message from `stream`, or `None` once the other side
+/// has gone.
+pub fn read_block(stream: &mut TcpStream, endianness: Endianness) -> Option<Vec<u8>> {
+    let mut message = vec![];
+    framing::read_message(stream, &mut message, endianness).ok()?;
+    Some(message)
+}
+
+/// A server that accepts `sessions` connections, each consisting of a login
+/// and any number of commands until the client disconnects. It writes
+/// headers in `endianness` and reads those of the client in the client's
+/// byte order.
+pub struct MockServer {
+    pub challenge: String,
+    pub endianness: Endianness,
+    pub sessions: usize,
+    /// Redirect the client to log in again, as monetdbd does when it
+    /// proxies the connection.
+    pub proxied: bool,
+    /// The responses to the commands, in order, over all sessions.
+    pub responses: Vec<String>,
+    /// The response once `responses` runs out.
+    pub default_response: String,
+}
+
+impl MockServer {
+    /// One session, with a challenge that accepts SHA512 and SHA256 hashes
+    /// and client info.
+    pub fn new(endianness: Endianness) -> MockServer {
+        MockServer {
+            challenge: format!(
+                "somesalt:mserver:9:SHA512,SHA256:{}:SHA512:sql=6:CLIENTINFO:",
+                endianness
+            ),
+            endianness,
+            sessions: 1,
+            proxied: false,
+            responses: vec![],
+            default_response: String::new(),
+        }
+    }
+
+    /// Listen on a free port. The handle returns everything the client
+    /// sent, the logins included.
+    pub fn start(self) -> (u16, thread::JoinHandle<Vec<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut received = vec![];
+            let mut responses = self.responses.iter();
+            for _ in 0..self.sessions {
+                let (mut stream, _) = listener.accept().unwrap();
+                if !self.login(&mut stream, &mut received) {
+                    break;
+                }
+                while let Some(command) = read_block(&mut stream, Endianness::native()) {
+                    received.push(command);
+                    let response = responses.next().unwrap_or(&self.default_response);
+                    write_block(&mut stream, response.as_bytes(), self.endianness);
+                }
+            }
+            received
+        });
+
+        (port, handle)
+    }
+
+    /// Let the client in. Returns false if it disconnected instead.
+    fn login(&self, stream: &mut TcpStream, received: &mut Vec<Vec<u8>>) -> bool {
+        let challenges = if self.proxied { 2 } else { 1 };
+        for challenge in 0..challenges {
+            write_block(stream, self.challenge.as_bytes(), self.endianness);
+            match read_block(stream, Endianness::native()) {
+                Some(response) => received.push(response),
+                None => return false,
+            }
+            let verdict: &[u8] = if challenge + 1 < challenges {
+                b"^mapi:merovingian://proxy?database=demo\n"
+            } else {
+                b""
+            };
+            write_block(stream, verdict, self.endianness);
+        }
+        true
+    }
+}
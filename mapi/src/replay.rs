@@ -0,0 +1,437 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Record a session with a server once, and replay it without one.
+//!
+//! A `RecordingSocket` copies everything that goes over a socket to a
+//! transcript. A `ReplaySocket` plays the server's part of a transcript back
+//! and checks that the client sends exactly what it sent when the transcript
+//! was recorded. Set `record` or `replay` in the `MapiConnectionParams` to
+//! use them for a connection.
+//!
+//! A transcript is a sequence of entries. A line `connect` starts a new
+//! connection. A line `> <length>` for bytes sent to the server, or
+//! `< <length>` for bytes received from it, is followed by that many bytes as
+//! they were on the wire, block headers included, and a newline.
+//!
+//! Unlike a wire dump, a transcript contains the login response with the
+//! hashed password, so keep it as safe as the password. Replaying requires
+//! the client to send the same as when recording: the same credentials, no
+//! `Xclientinfo`, which contains the process id, and a pinned
+//! `session.timezone`, as the default is the offset of the local time zone,
+//! which changes with daylight saving time and from machine to machine.
+use std::fs::{self, File};
+use std::io::{self, BufWriter, IoSlice, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::trace::Direction;
+use crate::transport::Transport;
+
+// How much of the bytes to show when the client does not send what it should.
+const EXCERPT_LENGTH: usize = 200;
+
+/// Where a `RecordingSocket` writes to. Clones share the file, so that the
+/// connections after a reconnect end up in the same transcript.
+#[derive(Clone)]
+pub struct Transcript {
+    file: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl Transcript {
+    /// Create a transcript in `path`, replacing the file if it exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Transcript> {
+        Ok(Transcript {
+            file: Arc::new(Mutex::new(BufWriter::new(File::create(path)?))),
+        })
+    }
+
+    fn connect(&self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(file, "connect")?;
+        file.flush()
+    }
+
+    fn record(&self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(file, "{} {}", direction.arrow(), bytes.len())?;
+        file.write_all(bytes)?;
+        writeln!(file)?;
+        // A transcript is most useful when something went wrong, so do not
+        // keep anything in the buffer.
+        file.flush()
+    }
+}
+
+/// Copies the traffic over `T`, a new connection, to a transcript.
+pub struct RecordingSocket<T> {
+    inner: T,
+    transcript: Transcript,
+}
+
+impl<T> RecordingSocket<T> {
+    pub fn new(inner: T, transcript: Transcript) -> io::Result<RecordingSocket<T>> {
+        transcript.connect()?;
+        Ok(RecordingSocket { inner, transcript })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for RecordingSocket<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.transcript.record(Direction::Received, &buf[..n])?;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for RecordingSocket<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.transcript.record(Direction::Sent, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let n = self.inner.write_vectored(bufs)?;
+        let mut left = n;
+        for buf in bufs {
+            let written = left.min(buf.len());
+            self.transcript.record(Direction::Sent, &buf[..written])?;
+            left -= written;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for RecordingSocket<T> {
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+}
+
+/// Plays back the server's part of a transcript, and fails as soon as the
+/// client deviates from its part.
+///
+/// Clones share their position in the transcript. A connection gets its
+/// socket from `connect`, also when it reconnects, and a test can keep a
+/// clone to check that the whole transcript was played.
+#[derive(Clone)]
+pub struct ReplaySocket {
+    replay: Arc<Mutex<Replay>>,
+}
+
+enum Entry {
+    Connect,
+    // Consecutive bytes in the same direction, whatever the reads and writes
+    // that transferred them
+    Bytes(Direction, Vec<u8>),
+}
+
+struct Replay {
+    entries: Vec<Entry>,
+    // The current entry, and how much of its bytes have been played
+    entry: usize,
+    offset: usize,
+}
+
+impl Replay {
+    fn current(&self) -> Option<(Direction, &[u8])> {
+        match self.entries.get(self.entry)? {
+            Entry::Bytes(direction, bytes) => Some((*direction, &bytes[self.offset..])),
+            Entry::Connect => None,
+        }
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.offset += n;
+        if let Some(Entry::Bytes(_, bytes)) = self.entries.get(self.entry) {
+            if self.offset == bytes.len() {
+                self.entry += 1;
+                self.offset = 0;
+            }
+        }
+    }
+}
+
+impl ReplaySocket {
+    /// Replay the transcript in `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ReplaySocket> {
+        ReplaySocket::parse(&fs::read(path)?)
+    }
+
+    /// Replay a transcript that is already in memory.
+    pub fn parse(transcript: &[u8]) -> io::Result<ReplaySocket> {
+        let mut entries: Vec<Entry> = vec![];
+        let mut rest = transcript;
+        while !rest.is_empty() {
+            let invalid = || invalid_transcript(rest);
+            let line_end = rest.iter().position(|&b| b == b'\n').ok_or_else(invalid)?;
+            let line = std::str::from_utf8(&rest[..line_end]).map_err(|_| invalid())?;
+            let (direction, length) = match line.split_once(' ') {
+                _ if line == "connect" => {
+                    entries.push(Entry::Connect);
+                    rest = &rest[line_end + 1..];
+                    continue;
+                }
+                Some((">", length)) => (Direction::Sent, length),
+                Some(("<", length)) => (Direction::Received, length),
+                _ => return Err(invalid()),
+            };
+            let length: usize = length.parse().map_err(|_| invalid())?;
+            let start = line_end + 1;
+            let bytes = rest
+                .get(start..start + length)
+                .filter(|_| rest.get(start + length) == Some(&b'\n'))
+                .ok_or_else(invalid)?;
+            match entries.last_mut() {
+                Some(Entry::Bytes(last, run)) if *last == direction => run.extend_from_slice(bytes),
+                _ => entries.push(Entry::Bytes(direction, bytes.to_vec())),
+            }
+            rest = &rest[start + length + 1..];
+        }
+        Ok(ReplaySocket {
+            replay: Arc::new(Mutex::new(Replay {
+                entries,
+                entry: 0,
+                offset: 0,
+            })),
+        })
+    }
+
+    /// The socket for the next connection in the transcript. Fails if the
+    /// client did not do everything it did on the previous connection.
+    pub fn connect(&self) -> io::Result<ReplaySocket> {
+        let mut replay = self.lock();
+        match replay.entries.get(replay.entry) {
+            Some(Entry::Connect) => {
+                replay.entry += 1;
+                Ok(self.clone())
+            }
+            None => Err(deviation(String::from(
+                "the client connects after the end of the transcript",
+            ))),
+            Some(Entry::Bytes(direction, _)) => Err(deviation(format!(
+                "the client connects, but should still have {} {}",
+                match direction {
+                    Direction::Sent => "sent",
+                    Direction::Received => "received",
+                },
+                excerpt(replay.current().map_or(&[][..], |(_, bytes)| bytes))
+            ))),
+        }
+    }
+
+    /// Whether the whole transcript has been played.
+    pub fn is_finished(&self) -> bool {
+        let replay = self.lock();
+        replay.entry == replay.entries.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Replay> {
+        self.replay.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Read for ReplaySocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut replay = self.lock();
+        match replay.current() {
+            // The server hung up here when recording
+            None => Ok(0),
+            Some((Direction::Received, bytes)) => {
+                let n = bytes.len().min(buf.len());
+                buf[..n].copy_from_slice(&bytes[..n]);
+                replay.advance(n);
+                Ok(n)
+            }
+            Some((Direction::Sent, expected)) => Err(deviation(format!(
+                "the client waits for the server, but should send {}",
+                excerpt(expected)
+            ))),
+        }
+    }
+}
+
+impl Write for ReplaySocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut replay = self.lock();
+        match replay.current() {
+            None => Err(deviation(format!(
+                "the client sends {} after the end of the connection",
+                excerpt(buf)
+            ))),
+            Some((Direction::Received, _)) => Err(deviation(format!(
+                "the client sends {}, but should wait for the server",
+                excerpt(buf)
+            ))),
+            Some((Direction::Sent, expected)) => {
+                let n = expected.len().min(buf.len());
+                if buf[..n] != expected[..n] {
+                    return Err(deviation(format!(
+                        "the client sends {}, but should send {}",
+                        excerpt(buf),
+                        excerpt(expected)
+                    )));
+                }
+                replay.advance(n);
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for ReplaySocket {
+    // The replay never waits, so there is nothing to time out
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn excerpt(bytes: &[u8]) -> String {
+    format!(
+        "{:?}",
+        String::from_utf8_lossy(&bytes[..bytes.len().min(EXCERPT_LENGTH)])
+    )
+}
+
+fn deviation(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("replay: {}", message))
+}
+
+fn invalid_transcript(rest: &[u8]) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid transcript entry: {}", excerpt(rest)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads from `input` and collects what is written.
+    struct Pipe {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.output.write_vectored(bufs)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("transcript-{}", std::process::id()));
+        let pipe = Pipe {
+            input: io::Cursor::new(b"challenge\nresponse".to_vec()),
+            output: vec![],
+        };
+        let transcript = Transcript::create(&path).unwrap();
+        let mut socket = RecordingSocket::new(pipe, transcript).unwrap();
+        let mut buf = [0; 10];
+        socket.read_exact(&mut buf).unwrap();
+        socket.write_all(b"login").unwrap();
+        let bufs = [IoSlice::new(b"\n"), IoSlice::new(b"> 1\n")];
+        assert_eq!(socket.write_vectored(&bufs).unwrap(), 5);
+        socket.read_to_end(&mut vec![]).unwrap();
+        assert_eq!(socket.into_inner().output, b"login\n> 1\n");
+
+        let transcript = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            transcript,
+            b"connect\n< 10\nchallenge\n\n> 5\nlogin\n> 1\n\n\n> 4\n> 1\n\n< 8\nresponse\n"
+        );
+
+        let replay = ReplaySocket::parse(&transcript).unwrap();
+        let mut socket = replay.connect().unwrap();
+        let mut received = [0; 10];
+        socket.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"challenge\n");
+        socket.write_all(b"login\n> 1\n").unwrap();
+        let mut response = vec![];
+        socket.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"response");
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn deviations() {
+        let transcript = b"connect\n< 2\nhi\n> 5\nhello\n< 3\nbye\nconnect\n< 2\nhi\n";
+        let replay = ReplaySocket::parse(transcript).unwrap();
+        let mut socket = replay.connect().unwrap();
+        assert!(socket.write(b"hello").is_err());
+        socket.read_exact(&mut [0; 2]).unwrap();
+        assert!(socket.read(&mut [0; 3]).is_err());
+        assert!(socket.write_all(b"help!").is_err());
+        assert!(replay.connect().is_err());
+
+        let replay = ReplaySocket::parse(transcript).unwrap();
+        let mut socket = replay.connect().unwrap();
+        socket.read_exact(&mut [0; 2]).unwrap();
+        socket.write_all(b"hello").unwrap();
+        // The server hangs up after its last response
+        let mut response = vec![];
+        socket.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"bye");
+        assert!(socket.write(b"more").is_err());
+
+        let mut socket = replay.connect().unwrap();
+        socket.read_exact(&mut [0; 2]).unwrap();
+        assert!(replay.is_finished());
+        assert!(replay.connect().is_err());
+
+        for invalid in [&b"< 2\nhi"[..], b"< 3\nhi\n", b"? 2\nhi\n", b"<2\nhi\n"] {
+            assert!(ReplaySocket::parse(invalid).is_err());
+        }
+    }
+}
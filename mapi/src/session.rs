@@ -0,0 +1,194 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! SQL session settings that are applied right after logging in, and again
+//! after every reconnect.
+//!
+//! The names follow the MonetDB URL conventions: `timezone` is the offset
+//! from UTC in minutes, `schema` and `role` are set with `SET SCHEMA` and
+//! `SET ROLE`, and `client_info` is sent to servers that announce support
+//! for it in their challenge, so that it shows up in `sys.sessions`.
+//! `size_header` has the server send the precision and scale of every
+//! column along with a result set, which exact decimals need.
+use std::env;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::sys;
+
+/// Settings for a new SQL session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionOptions {
+    /// The session time zone in minutes east of UTC. Defaults to the offset
+    /// of the local time zone. `None` keeps the server default.
+    pub timezone: Option<i32>,
+    /// The initial schema. `None` keeps the default schema of the user.
+    pub schema: Option<String>,
+    /// The initial role. `None` keeps the default role of the user.
+    pub role: Option<String>,
+    /// Information about this client. `None` sends nothing.
+    pub client_info: Option<ClientInfo>,
+    /// Ask for the `typesizes` header of result sets with `Xsizeheader 1`.
+    pub size_header: bool,
+}
+
+impl Default for SessionOptions {
+    fn default() -> SessionOptions {
+        SessionOptions {
+            timezone: Some(local_utc_offset()),
+            schema: None,
+            role: None,
+            client_info: Some(ClientInfo::default()),
+            size_header: true,
+        }
+    }
+}
+
+impl SessionOptions {
+    /// The SQL statements that apply these settings, in order.
+    pub fn statements(&self) -> Vec<String> {
+        let mut statements = vec![];
+        if let Some(minutes) = self.timezone {
+            let sign = if minutes < 0 { '-' } else { '+' };
+            let minutes = minutes.abs();
+            statements.push(format!(
+                "SET TIME ZONE INTERVAL '{}{:02}:{:02}' HOUR TO MINUTE",
+                sign,
+                minutes / 60,
+                minutes % 60
+            ));
+        }
+        if let Some(ref schema) = self.schema {
+            statements.push(format!("SET SCHEMA {}", quote_identifier(schema)));
+        }
+        if let Some(ref role) = self.role {
+            statements.push(format!("SET ROLE {}", quote_identifier(role)));
+        }
+        statements
+    }
+
+    /// The command that applies all `statements` in one round trip, if
+    /// there are any.
+    pub fn command(&self) -> Option<String> {
+        let statements = self.statements();
+        if statements.is_empty() {
+            return None;
+        }
+        Some(format!("s{}\n;", statements.join(";\n")))
+    }
+}
+
+/// What we tell the server about ourselves with `Xclientinfo`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    /// Defaults to the name of the running executable.
+    pub application_name: Option<String>,
+    /// A free form remark.
+    pub remark: Option<String>,
+}
+
+impl Default for ClientInfo {
+    fn default() -> ClientInfo {
+        let application_name = env::current_exe()
+            .ok()
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()));
+        ClientInfo {
+            application_name,
+            remark: None,
+        }
+    }
+}
+
+impl ClientInfo {
+    /// The `Xclientinfo` command, one `key=value` line per field.
+    pub fn command(&self) -> String {
+        let fields = [
+            ("ClientHostname", sys::hostname()),
+            ("ApplicationName", self.application_name.clone()),
+            (
+                "ClientLibrary",
+                Some(format!("rust-monetdb {}", env!("CARGO_PKG_VERSION"))),
+            ),
+            ("ClientRemark", self.remark.clone()),
+            ("ClientPid", Some(process::id().to_string())),
+        ];
+        let mut command = String::from("Xclientinfo ");
+        for (key, value) in fields.iter() {
+            if let Some(value) = value {
+                // The value ends at the newline
+                let value = value.replace(['\n', '\r'], " ");
+                command.push_str(&format!("{}={}\n", key, value));
+            }
+        }
+        command
+    }
+}
+
+/// Quote an SQL identifier, doubling embedded double quotes.
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// The current offset of the local time zone from UTC, in minutes. Only
+/// known on unix; elsewhere this is UTC.
+pub fn local_utc_offset() -> i32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    sys::utc_offset(now).map_or(0, |seconds| (seconds / 60) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements() {
+        let options = SessionOptions {
+            timezone: Some(-330),
+            schema: Some(String::from("my \"schema\"")),
+            role: Some(String::from("analyst")),
+            client_info: None,
+            size_header: true,
+        };
+        assert_eq!(
+            options.statements(),
+            vec![
+                "SET TIME ZONE INTERVAL '-05:30' HOUR TO MINUTE",
+                "SET SCHEMA \"my \"\"schema\"\"\"",
+                "SET ROLE \"analyst\"",
+            ]
+        );
+        assert_eq!(
+            options.command().unwrap(),
+            "sSET TIME ZONE INTERVAL '-05:30' HOUR TO MINUTE;\n\
+             SET SCHEMA \"my \"\"schema\"\"\";\n\
+             SET ROLE \"analyst\"\n;"
+        );
+
+        let options = SessionOptions {
+            timezone: None,
+            ..SessionOptions::default()
+        };
+        assert!(options.statements().is_empty());
+        assert_eq!(options.command(), None);
+    }
+
+    #[test]
+    fn client_info() {
+        let info = ClientInfo {
+            application_name: Some(String::from("report")),
+            remark: Some(String::from("two\nlines")),
+        };
+        let command = info.command();
+        assert!(command.starts_with("Xclientinfo "));
+        assert!(command.contains("\nApplicationName=report\n"));
+        assert!(command.contains("\nClientRemark=two lines\n"));
+        assert!(command.contains(&format!("\nClientPid={}\n", process::id())));
+        assert!(command.ends_with('\n'));
+    }
+}
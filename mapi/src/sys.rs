@@ -0,0 +1,64 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! What the standard library does not tell us about the system: the local
+//! time zone and the host name. This is the only unsafe code in the crate.
+#![allow(unsafe_code)]
+
+/// The offset of the local time zone from UTC in seconds, at `time` seconds
+/// since the epoch.
+#[cfg(target_family = "unix")]
+pub(crate) fn utc_offset(time: u64) -> Option<i64> {
+    let time = libc::time_t::try_from(time).ok()?;
+    // SAFETY: tm is a plain C struct, for which all zeroes is a valid value.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers come from references that are valid for the
+    // duration of the call. Unlike localtime, localtime_r writes only to the
+    // tm we pass in, so it is thread safe.
+    let result = unsafe { libc::localtime_r(&time, &mut tm) };
+    if result.is_null() {
+        return None;
+    }
+    Some(tm.tm_gmtoff as i64)
+}
+
+/// Only known on unix.
+#[cfg(not(target_family = "unix"))]
+pub(crate) fn utc_offset(_time: u64) -> Option<i64> {
+    None
+}
+
+/// The name of the machine we are running on.
+#[cfg(target_family = "unix")]
+pub(crate) fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the pointer and length describe `buffer`, and gethostname
+    // writes at most that many bytes.
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+    if result != 0 {
+        return None;
+    }
+    // A truncated name need not be NUL terminated
+    let end = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    Some(String::from_utf8_lossy(&buffer[..end]).into_owned())
+}
+
+#[cfg(not(target_family = "unix"))]
+pub(crate) fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system() {
+        let offset = utc_offset(0).unwrap_or(0);
+        assert!(offset.abs() <= 14 * 3600, "{}", offset);
+        assert!(hostname().is_some_and(|name| !name.is_empty()));
+    }
+}
@@ -0,0 +1,219 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Support for tracing the protocol: keeping secrets out of the traces, and
+//! dumping the raw blocks exchanged with the server to a file.
+//!
+//! Connecting, logging in and every command are `tracing` spans, and every
+//! block sent or received is an event at the `TRACE` level. Passwords never
+//! appear in them: the login response is redacted, and so are the string
+//! literals following `PASSWORD` in SQL statements.
+//!
+//! A wire dump is a sequence of records, one for every message. A record is
+//! a line `<direction> <milliseconds since the epoch> <endianness>`, where
+//! direction is `>` for messages to the server and `<` for messages from the
+//! server, followed by the message as it was framed in blocks on the wire,
+//! and a newline. Secrets are redacted like in traces. A connection only
+//! writes one when `MapiConnectionParams::wire_dump` is set.
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::framing;
+use crate::mapi::Endianness;
+
+/// Shown instead of a secret.
+pub const REDACTED: &str = "<redacted>";
+
+// Commands are cut off at this length in traces.
+const MAX_COMMAND_LENGTH: usize = 1000;
+
+/// Replace the string literal following every `PASSWORD` keyword in `sql`,
+/// as in `ALTER USER "alice" WITH PASSWORD 'secret'`.
+pub fn redact_sql(sql: &str) -> Cow<'_, str> {
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    let bytes = sql.as_bytes();
+    let mut redacted = String::new();
+    let mut copied = 0;
+    let mut after_keyword = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            quote @ (b'\'' | b'"') => {
+                let end = quoted_end(&bytes[i..], quote).map_or(bytes.len(), |e| i + e);
+                if quote == b'\'' && after_keyword {
+                    redacted.push_str(&sql[copied..i]);
+                    redacted.push('\'');
+                    redacted.push_str(REDACTED);
+                    redacted.push('\'');
+                    copied = end;
+                }
+                after_keyword = false;
+                i = end;
+            }
+            b if is_word(b) => {
+                let start = i;
+                while i < bytes.len() && is_word(bytes[i]) {
+                    i += 1;
+                }
+                after_keyword = sql[start..i].eq_ignore_ascii_case("password");
+            }
+            b if b.is_ascii_whitespace() => i += 1,
+            _ => {
+                after_keyword = false;
+                i += 1;
+            }
+        }
+    }
+    if copied == 0 {
+        Cow::Borrowed(sql)
+    } else {
+        redacted.push_str(&sql[copied..]);
+        Cow::Owned(redacted)
+    }
+}
+
+/// A command as it appears in traces: redacted, and cut off if it is long.
+pub fn summary(command: &str) -> Cow<'_, str> {
+    match redact_sql(command) {
+        Cow::Borrowed(c) if c.len() <= MAX_COMMAND_LENGTH => Cow::Borrowed(c),
+        c => {
+            let mut end = MAX_COMMAND_LENGTH.min(c.len());
+            while !c.is_char_boundary(end) {
+                end -= 1;
+            }
+            Cow::Owned(c[..end].to_string())
+        }
+    }
+}
+
+/// The length of the quoted string or identifier at the start of `bytes`,
+/// including the quotes. Quotes are escaped by doubling them or, in string
+/// literals, with a backslash.
+fn quoted_end(bytes: &[u8], quote: u8) -> Option<usize> {
+    let mut i = 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if quote == b'\'' => i += 2,
+            b if b == quote && bytes.get(i + 1) == Some(&quote) => i += 2,
+            b if b == quote => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Which way a message went.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    /// How the direction is written in dumps.
+    pub(crate) fn arrow(self) -> char {
+        match self {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        }
+    }
+}
+
+/// Writes the raw blocks of every message to a file, see the module
+/// documentation for the format.
+pub struct WireDump {
+    file: BufWriter<File>,
+}
+
+impl WireDump {
+    /// Append to the dump in `path`, creating it if needed.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<WireDump> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(WireDump {
+            file: BufWriter::new(file),
+        })
+    }
+
+    /// Add a message. A dump that cannot be written must not break the
+    /// connection, so errors are only traced.
+    pub fn record(&mut self, direction: Direction, message: &[u8], endianness: Endianness) {
+        if let Err(e) = self.write(direction, message, endianness) {
+            tracing::warn!(error = %e, "cannot write wire dump");
+        }
+    }
+
+    fn write(
+        &mut self,
+        direction: Direction,
+        message: &[u8],
+        endianness: Endianness,
+    ) -> io::Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        writeln!(self.file, "{} {} {}", direction.arrow(), millis, endianness)?;
+        framing::write_message(&mut self.file, message, endianness)?;
+        writeln!(self.file)?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_are_redacted() {
+        assert_eq!(
+            redact_sql("sALTER USER \"a\" WITH PASSWORD 'it''s \\'secret'\n;"),
+            "sALTER USER \"a\" WITH PASSWORD '<redacted>'\n;"
+        );
+        assert_eq!(
+            redact_sql("CREATE USER b WITH UNENCRYPTED password\n  'x' NAME 'B'; SET Password 'y'"),
+            "CREATE USER b WITH UNENCRYPTED password\n  '<redacted>' NAME 'B'; SET Password '<redacted>'"
+        );
+        assert_eq!(
+            redact_sql("SELECT passwords, 'password' FROM t"),
+            "SELECT passwords, 'password' FROM t"
+        );
+        assert_eq!(
+            redact_sql("SELECT \"password\" 'x', password || 'y'"),
+            "SELECT \"password\" 'x', password || 'y'"
+        );
+        assert_eq!(
+            redact_sql("PASSWORD 'unterminated"),
+            "PASSWORD '<redacted>'"
+        );
+        assert!(matches!(redact_sql("SELECT 1"), Cow::Borrowed(_)));
+
+        let long = "x".repeat(2 * MAX_COMMAND_LENGTH);
+        assert_eq!(summary(&long).len(), MAX_COMMAND_LENGTH);
+    }
+
+    #[test]
+    fn wire_dump() {
+        let path = std::env::temp_dir().join(format!("wire-dump-{}", std::process::id()));
+        let mut dump = WireDump::create(&path).unwrap();
+        dump.record(Direction::Sent, b"sSELECT 1\n;", Endianness::Little);
+        dump.record(Direction::Received, b"", Endianness::Big);
+        drop(dump);
+
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let first = contents.iter().position(|&b| b == b'\n').unwrap();
+        assert!(contents.starts_with(b"> "));
+        assert!(contents[..first].ends_with(b" LIT"));
+        let mut rest = &contents[first + 1..];
+        let mut message = vec![];
+        framing::read_message(&mut rest, &mut message, Endianness::Little).unwrap();
+        assert_eq!(message, b"sSELECT 1\n;");
+        assert!(rest.starts_with(b"\n< "));
+        assert!(rest.ends_with(b" BIG\n\x00\x01\n"));
+    }
+}
@@ -0,0 +1,84 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! What a `MapiConnection` talks to the server over.
+//!
+//! TCP and Unix domain sockets are built in. Any other stream, e.g. an SSH
+//! channel, an in-memory pipe or a stream through a proxy, can be used by
+//! implementing `Transport` for it and connecting with
+//! `MapiConnection::connect_with_transport`.
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(target_family = "unix")]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// A stream to the server.
+///
+/// The connection buffers reads and writes itself, and flushes after every
+/// message.
+pub trait Transport: Read + Write + Send {
+    /// Close the stream in both directions. Called by
+    /// `MapiConnection::close`, and before reconnecting. Dropping the
+    /// transport should close it too, so doing nothing is the default.
+    fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Fail reads that wait longer than `timeout`, or wait forever if it is
+    /// `None`. Fails with `Unsupported` by default.
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Like `set_read_timeout`, for writes.
+    fn set_write_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Transport for TcpStream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl Transport for UnixStream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn shutdown(&mut self) -> io::Result<()> {
+        (**self).shutdown()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+}
@@ -0,0 +1,183 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Response parsing, value decoding and parameter binding, and fetching a
+//! result set from a loopback mock server.
+use std::io::{BufReader, BufWriter, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use mapi::framing::{read_message, write_message};
+use mapi::mapi::{Endianness, MapiConnection, MapiConnectionParams};
+use mapi::session::SessionOptions;
+use monetdb::connection::Connection;
+use monetdb::monetizer::{apply_parameters, to_sqlparameter, FromSql, SqlType};
+use monetdb::response::{parse_response, parse_tuple, RawRow};
+
+const ROWS: usize = 1000;
+const TUPLE: &str = "[ 42,\t\"a \\\"quoted\\\" name\",\t3.14159,\ttrue,\tNULL\t]";
+
+/// A result set of `ROWS` rows, all sent along with the header.
+fn result_set() -> String {
+    let mut response = format!(
+        "&1 1 {0} 3 {0}\n\
+         % sys.t,\tsys.t,\tsys.t # table_name\n\
+         % id,\tname,\tprice # name\n\
+         % int,\tvarchar,\tdouble # type\n\
+         % 4,\t12,\t24 # length\n",
+        ROWS
+    );
+    for i in 0..ROWS {
+        response.push_str(&format!("[ {},\t\"item {}\",\t{}.25\t]\n", i, i, i));
+    }
+    response
+}
+
+fn tuples(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tuple");
+    group.throughput(Throughput::Bytes(TUPLE.len() as u64));
+    group.bench_function("parse_tuple", |b| {
+        b.iter(|| parse_tuple(black_box(TUPLE), 5).unwrap())
+    });
+    group.bench_function("raw_row", |b| {
+        b.iter(|| {
+            let row = RawRow::parse(black_box(TUPLE)).unwrap();
+            for i in 0..5 {
+                black_box(row.get_str(i).unwrap());
+            }
+        })
+    });
+    group.finish();
+
+    let response = result_set();
+    let mut group = c.benchmark_group("response");
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.bench_function("parse_response", |b| {
+        b.iter(|| parse_response(black_box(&response)).unwrap())
+    });
+    group.finish();
+}
+
+fn decoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("from_sql");
+    let int = SqlType::new("int");
+    let double = SqlType::new("double");
+    let boolean = SqlType::new("boolean");
+    let varchar = SqlType::new("varchar");
+    group.bench_function("i64", |b| {
+        b.iter(|| i64::from_sql(black_box("-1234567890"), &int).unwrap())
+    });
+    group.bench_function("f64", |b| {
+        b.iter(|| f64::from_sql(black_box("3.14159e10"), &double).unwrap())
+    });
+    group.bench_function("bool", |b| {
+        b.iter(|| bool::from_sql(black_box("true"), &boolean).unwrap())
+    });
+    group.bench_function("string", |b| {
+        b.iter(|| String::from_sql(black_box("some text"), &varchar).unwrap())
+    });
+    group.bench_function("nullable", |b| {
+        b.iter(|| Option::<i64>::from_nullable_sql(black_box(None), &int).unwrap())
+    });
+    group.finish();
+}
+
+fn binding(c: &mut Criterion) {
+    let query = "SELECT * FROM t WHERE a = ? AND b = ? AND c = ? AND d = ? -- ?";
+    let mut group = c.benchmark_group("apply_parameters");
+    group.bench_function("positional", |b| {
+        b.iter(|| {
+            let params = vec![
+                to_sqlparameter(42),
+                to_sqlparameter("it's"),
+                to_sqlparameter(2.5),
+                to_sqlparameter(Some(true)),
+            ];
+            apply_parameters(black_box(query), params).unwrap()
+        })
+    });
+    group.finish();
+}
+
+/// Logs in every client and answers every command with `result_set()`.
+fn mock_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let response = result_set();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let response = response.clone();
+            thread::spawn(move || {
+                let little = Endianness::Little;
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = BufWriter::new(stream);
+                let mut send = |message: &[u8]| {
+                    write_message(&mut writer, message, little).unwrap();
+                    writer.flush().unwrap();
+                };
+                send(b"somesalt:mserver:9:SHA512:LIT:SHA512:");
+                let mut buffer = vec![];
+                read_message(&mut reader, &mut buffer, little).unwrap();
+                send(b"");
+                loop {
+                    buffer.clear();
+                    if read_message(&mut reader, &mut buffer, little).is_err() {
+                        break;
+                    }
+                    send(response.as_bytes());
+                }
+            });
+        }
+    });
+    port
+}
+
+fn connect(port: u16) -> Connection {
+    let mut params = MapiConnectionParams::new(
+        "demo",
+        "monetdb",
+        Some("monetdb"),
+        None,
+        Some("127.0.0.1"),
+        Some(port),
+    );
+    params.session = SessionOptions {
+        timezone: None,
+        schema: None,
+        role: None,
+        client_info: None,
+        size_header: false,
+    };
+    Connection::from(MapiConnection::connect(params).unwrap())
+}
+
+fn loopback(c: &mut Criterion) {
+    let mut connection = connect(mock_server());
+    let mut group = c.benchmark_group("loopback");
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.bench_function("query", |b| {
+        b.iter(|| {
+            let rows = connection.query("SELECT * FROM t", vec![]).unwrap();
+            rows.map(|r| r.unwrap().len()).sum::<usize>()
+        })
+    });
+    group.bench_function("query_raw", |b| {
+        b.iter(|| {
+            let mut rows = connection.query_raw("SELECT * FROM t", vec![]).unwrap();
+            let mut total = 0;
+            while let Some(row) = rows.next() {
+                total += row.unwrap().raw(1).unwrap().len();
+            }
+            total
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, tuples, decoding, binding, loopback);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! `ToSql` and `FromSql` for `bigdecimal::BigDecimal`.
+//!
+//! A `BigDecimal` can hold any MonetDB decimal. Binding a value with more
+//! than 38 digits, the largest precision MonetDB supports, is an error.
+use std::result;
+use std::str::FromStr;
+
+use bigdecimal::num_bigint::{BigInt, Sign};
+use bigdecimal::BigDecimal;
+
+use crate::decimal;
+use crate::monetizer::{conversion_error, FromSql, SQLParameter, SqlType, ToSql};
+use mapi::errors::MonetDBError;
+
+type Result<T> = result::Result<T, MonetDBError>;
+
+impl ToSql for BigDecimal {
+    fn to_sql(&self) -> SQLParameter {
+        let (mantissa, scale) = self.as_bigint_and_exponent();
+        match decimal::format_decimal(
+            mantissa.sign() == Sign::Minus,
+            &mantissa.magnitude().to_string(),
+            scale,
+        ) {
+            Ok(literal) => SQLParameter::literal(literal),
+            Err(reason) => SQLParameter::invalid(reason),
+        }
+    }
+}
+
+impl FromSql for BigDecimal {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let unscaled = decimal::parse_decimal(value, sql_type)?;
+        let mantissa = BigInt::from_str(&unscaled.digits)
+            .map_err(|_| conversion_error(value, sql_type, "BigDecimal"))?;
+        let mantissa = if unscaled.negative {
+            -mantissa
+        } else {
+            mantissa
+        };
+
+        Ok(BigDecimal::new(mantissa, unscaled.scale as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monetizer::{apply_parameters, to_sqlparameter};
+
+    fn read(value: &str, digits: u32, scale: u32) -> Result<BigDecimal> {
+        BigDecimal::from_sql(value, &SqlType::with_size("decimal", digits, scale))
+    }
+
+    #[test]
+    fn decimals() {
+        let max = format!("-{}.{}", "9".repeat(20), "9".repeat(18));
+        let d = BigDecimal::from_str(&max).unwrap();
+        assert_eq!(to_sqlparameter(&d).to_string(), max);
+        assert_eq!(read(&max, 38, 18).unwrap(), d);
+        assert_eq!(read("0.50", 3, 2).unwrap().as_bigint_and_exponent().1, 2);
+
+        let big = BigDecimal::from_str("1e38").unwrap();
+        assert_eq!(
+            to_sqlparameter(&big / 10).to_string(),
+            format!("1{}", "0".repeat(37))
+        );
+        assert!(apply_parameters("SELECT ?", vec![to_sqlparameter(big)]).is_err());
+        let tiny = BigDecimal::from_str("1e-39").unwrap();
+        assert!(apply_parameters("SELECT ?", vec![to_sqlparameter(tiny)]).is_err());
+        assert!(read("1.2345", 10, 2).is_err());
+    }
+}
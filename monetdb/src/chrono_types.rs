@@ -0,0 +1,248 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! `ToSql` and `FromSql` for the date and time types of the `chrono` crate.
+//!
+//! | chrono                  | MonetDB                       |
+//! |-------------------------|-------------------------------|
+//! | `NaiveDate`             | `date`                        |
+//! | `NaiveTime`             | `time(p)`, `timetz(p)`        |
+//! | `NaiveDateTime`         | `timestamp(p)`                |
+//! | `DateTime<Utc>`         | `timestamptz(p)`              |
+//! | `DateTime<FixedOffset>` | `timestamptz(p)`              |
+//! | `Duration`              | `sec_interval`, `day_interval`|
+//!
+//! Values are sent with microsecond precision, the highest MonetDB supports.
+//! A `timestamptz` is returned by the server in the session time zone; it
+//! keeps that offset when read as `DateTime<FixedOffset>`, and reading it as
+//! `NaiveDateTime` gives the wall clock time in the session time zone.
+use std::result;
+
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone,
+    Timelike, Utc,
+};
+
+use crate::monetizer::{conversion_error, FromSql, SQLParameter, SqlType, ToSql};
+use crate::temporal::{self, TimeOfDay};
+use mapi::errors::MonetDBError;
+
+type Result<T> = result::Result<T, MonetDBError>;
+
+fn date_string(date: &NaiveDate) -> String {
+    temporal::format_date(date.year(), date.month(), date.day())
+}
+
+fn time_string(time: &NaiveTime) -> String {
+    temporal::format_time(TimeOfDay {
+        hour: time.hour(),
+        minute: time.minute(),
+        second: time.second(),
+        // chrono represents leap seconds with more than 10^9 nanoseconds
+        nanosecond: time.nanosecond().min(999_999_999),
+    })
+}
+
+fn to_date(value: &str, sql_type: &SqlType, (y, m, d): (i32, u32, u32)) -> Result<NaiveDate> {
+    NaiveDate::from_ymd_opt(y, m, d).ok_or_else(|| conversion_error(value, sql_type, "NaiveDate"))
+}
+
+fn to_time(value: &str, sql_type: &SqlType, t: TimeOfDay) -> Result<NaiveTime> {
+    NaiveTime::from_hms_nano_opt(t.hour, t.minute, t.second, t.nanosecond)
+        .ok_or_else(|| conversion_error(value, sql_type, "NaiveTime"))
+}
+
+impl ToSql for NaiveDate {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(format!("DATE '{}'", date_string(self)))
+    }
+}
+
+impl FromSql for NaiveDate {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        to_date(value, sql_type, temporal::parse_date(value)?)
+    }
+}
+
+impl ToSql for NaiveTime {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(format!("TIME '{}'", time_string(self)))
+    }
+}
+
+impl FromSql for NaiveTime {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let (time, _) = temporal::parse_time_with_offset(value)?;
+        to_time(value, sql_type, time)
+    }
+}
+
+impl ToSql for NaiveDateTime {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(format!(
+            "TIMESTAMP '{} {}'",
+            date_string(&self.date()),
+            time_string(&self.time())
+        ))
+    }
+}
+
+impl FromSql for NaiveDateTime {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let ts = temporal::parse_timestamp(value)?;
+        Ok(NaiveDateTime::new(
+            to_date(value, sql_type, ts.date)?,
+            to_time(value, sql_type, ts.time)?,
+        ))
+    }
+}
+
+impl ToSql for DateTime<FixedOffset> {
+    fn to_sql(&self) -> SQLParameter {
+        let local = self.naive_local();
+        SQLParameter::literal(format!(
+            "TIMESTAMPTZ '{} {}{}'",
+            date_string(&local.date()),
+            time_string(&local.time()),
+            temporal::format_offset(self.offset().local_minus_utc())
+        ))
+    }
+}
+
+impl FromSql for DateTime<FixedOffset> {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let ts = temporal::parse_timestamp(value)?;
+        let offset = ts
+            .offset
+            .and_then(FixedOffset::east_opt)
+            .ok_or_else(|| conversion_error(value, sql_type, "DateTime"))?;
+        let local = NaiveDateTime::new(
+            to_date(value, sql_type, ts.date)?,
+            to_time(value, sql_type, ts.time)?,
+        );
+        offset
+            .from_local_datetime(&local)
+            .single()
+            .ok_or_else(|| conversion_error(value, sql_type, "DateTime"))
+    }
+}
+
+impl ToSql for DateTime<Utc> {
+    fn to_sql(&self) -> SQLParameter {
+        self.fixed_offset().to_sql()
+    }
+}
+
+impl FromSql for DateTime<Utc> {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        Ok(DateTime::<FixedOffset>::from_sql(value, sql_type)?.with_timezone(&Utc))
+    }
+}
+
+impl ToSql for Duration {
+    fn to_sql(&self) -> SQLParameter {
+        let magnitude = self.abs();
+        SQLParameter::literal(temporal::format_seconds(
+            *self < Duration::zero(),
+            magnitude.num_seconds() as u64,
+            magnitude.subsec_nanos() as u32,
+        ))
+    }
+}
+
+impl FromSql for Duration {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let (negative, seconds, nanos) = temporal::parse_seconds(value)?;
+        let seconds = if sql_type.name == "day_interval" {
+            seconds.checked_mul(86400)
+        } else {
+            Some(seconds)
+        };
+        let duration = seconds
+            .and_then(|s| i64::try_from(s).ok())
+            .and_then(|s| Duration::new(s, nanos))
+            .ok_or_else(|| conversion_error(value, sql_type, "Duration"))?;
+
+        Ok(if negative { -duration } else { duration })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monetizer::to_sqlparameter;
+
+    fn read<T: FromSql>(value: &str, name: &str) -> Result<T> {
+        T::from_sql(value, &SqlType::new(name))
+    }
+
+    #[test]
+    fn dates_and_times() {
+        let date = NaiveDate::from_ymd_opt(2022, 6, 23).unwrap();
+        assert_eq!(to_sqlparameter(date).to_string(), "DATE '2022-06-23'");
+        assert_eq!(read::<NaiveDate>("2022-06-23", "date").unwrap(), date);
+
+        let time = NaiveTime::from_hms_micro_opt(13, 45, 7, 123_456).unwrap();
+        assert_eq!(to_sqlparameter(time).to_string(), "TIME '13:45:07.123456'");
+        assert_eq!(read::<NaiveTime>("13:45:07.123456", "time").unwrap(), time);
+        assert_eq!(
+            read::<NaiveTime>("13:45:07.123456+02:00", "timetz").unwrap(),
+            time
+        );
+
+        let ts = NaiveDateTime::new(date, time);
+        assert_eq!(
+            to_sqlparameter(ts).to_string(),
+            "TIMESTAMP '2022-06-23 13:45:07.123456'"
+        );
+        assert_eq!(
+            read::<NaiveDateTime>("2022-06-23 13:45:07.123456", "timestamp").unwrap(),
+            ts
+        );
+        assert!(read::<NaiveDate>("2022-02-30", "date").is_err());
+    }
+
+    #[test]
+    fn time_zones() {
+        let offset = FixedOffset::east_opt(2 * 3600).unwrap();
+        let dt = offset.with_ymd_and_hms(2022, 6, 23, 18, 0, 0).unwrap();
+        assert_eq!(
+            to_sqlparameter(dt).to_string(),
+            "TIMESTAMPTZ '2022-06-23 18:00:00.000000+02:00'"
+        );
+        let utc = dt.with_timezone(&Utc);
+        assert_eq!(
+            to_sqlparameter(utc).to_string(),
+            "TIMESTAMPTZ '2022-06-23 16:00:00.000000+00:00'"
+        );
+
+        let read_dt =
+            read::<DateTime<FixedOffset>>("2022-06-23 18:00:00.000000+02:00", "timestamptz")
+                .unwrap();
+        assert_eq!(read_dt, dt);
+        assert_eq!(read_dt.offset(), &offset);
+        let read_utc =
+            read::<DateTime<Utc>>("2022-06-23 18:00:00.000000+02:00", "timestamptz").unwrap();
+        assert_eq!(read_utc, utc);
+
+        // A timestamp without time zone is not a point in time
+        assert!(read::<DateTime<Utc>>("2022-06-23 18:00:00", "timestamp").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        let d = Duration::milliseconds(-90_500);
+        assert_eq!(
+            to_sqlparameter(d).to_string(),
+            "INTERVAL '-90.500000' SECOND"
+        );
+        assert_eq!(read::<Duration>("-90.500", "sec_interval").unwrap(), d);
+        assert_eq!(
+            read::<Duration>("3", "day_interval").unwrap(),
+            Duration::days(3)
+        );
+    }
+}
@@ -1,10 +1,18 @@
-use log::debug;
+use std::mem;
 use std::result;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::{debug, debug_span, trace, Span};
 use url::Url;
 
+use crate::cursor::Cursor;
 use crate::monetizer;
-use mapi::errors::MonetDBError;
-use mapi::mapi::{MapiConnection, MapiConnectionParams, MapiLanguage};
+use crate::response::{self, QueryResult, ResultKind};
+use crate::rows::{RawRows, Rows};
+use mapi::config::Config;
+use mapi::errors::{MapiError, MonetDBError};
+use mapi::mapi::{MapiConnection, MapiLanguage};
+use mapi::trace::{self as redaction, REDACTED};
 
 pub type Result<T> = result::Result<T, MonetDBError>;
 
@@ -12,6 +20,28 @@ pub type Result<T> = result::Result<T, MonetDBError>;
 pub struct Connection {
     _server_url: String,
     connection: MapiConnection,
+    // Result sets the server keeps for us
+    open_results: Vec<u64>,
+    // Result sets we are done with. They are closed before the next command.
+    pending_closes: Vec<u64>,
+    // The number of rows the server sends with a result set, if we set it
+    reply_size: Option<u64>,
+    // Receive buffer for RawRows, kept to reuse its allocation
+    buffer: Vec<u8>,
+}
+
+impl From<MapiConnection> for Connection {
+    /// Use an already established SQL connection.
+    fn from(connection: MapiConnection) -> Connection {
+        Connection {
+            _server_url: String::new(),
+            connection,
+            open_results: vec![],
+            pending_closes: vec![],
+            reply_size: None,
+            buffer: vec![],
+        }
+    }
 }
 
 impl Connection {
@@ -20,24 +50,19 @@ impl Connection {
         debug!("parsed url {} to", url);
         debug!("  scheme: {}", parsed.scheme());
         debug!("  username: {}", parsed.username());
-        debug!("  password: {:?}", parsed.password());
+        debug!("  password: {:?}", parsed.password().map(|_| REDACTED));
         debug!("  host: {:?}", parsed.host_str());
-        debug!("  host: {:?}", parsed.port());
+        debug!("  port: {:?}", parsed.port());
         debug!("  path: {:?}", parsed.path());
 
-        // Remove the initial '/'
-        let db = parsed.path().get(1..).unwrap();
-        let mapi_params = MapiConnectionParams::new(
-            db,
-            parsed.username(),
-            parsed.password(),
-            Some(MapiLanguage::Sql),
-            parsed.host_str(),
-            parsed.port(),
-        );
+        // Anything not in the URL is taken from the environment or the
+        // .monetdb dotfile. This is always an SQL connection though.
+        let mut config = Config::load(Some(url))?;
+        config.language = Some(MapiLanguage::Sql.to_string());
+        let mapi_params = config.into_params()?;
         Ok(Connection {
             _server_url: String::from(url),
-            connection: MapiConnection::connect(mapi_params)?,
+            ..Connection::from(MapiConnection::connect(mapi_params)?)
         })
     }
 
@@ -45,19 +70,239 @@ impl Connection {
         &mut self.connection
     }
 
-    pub fn execute(&mut self, query: &str, params: Vec<monetizer::SQLParameter>) -> Result<u64> {
-        let escaped_query = monetizer::apply_parameters(query, params);
+    /// Execute a statement and return its result. If `query` contains
+    /// several statements this is the result of the last one. Only the rows
+    /// sent along with the response are included, use `query` for all of
+    /// them. Errors reported by the server are `MonetDBError::StatementError`.
+    pub fn execute(
+        &mut self,
+        query: &str,
+        params: Vec<monetizer::SQLParameter>,
+    ) -> Result<QueryResult> {
+        let result = self.execute_open(query, params)?;
+        self.discard(&result);
+        Ok(result)
+    }
+
+    /// Like `execute`, but the server keeps the rows it did not send yet.
+    /// The caller takes over the result set, see `ResultSet`.
+    pub(crate) fn execute_open(
+        &mut self,
+        query: &str,
+        params: Vec<monetizer::SQLParameter>,
+    ) -> Result<QueryResult> {
+        let escaped_query = monetizer::apply_parameters(query, params)?;
+        self.run(query, &escaped_query)
+    }
+
+    /// Like `execute`, but with `:name` placeholders.
+    pub fn execute_named(
+        &mut self,
+        query: &str,
+        params: &[(&str, monetizer::SQLParameter)],
+    ) -> Result<QueryResult> {
+        let escaped_query = monetizer::apply_named_parameters(query, params)?;
+        let result = self.run(query, &escaped_query)?;
+        self.discard(&result);
+        Ok(result)
+    }
+
+    /// Execute a query and iterate over the rows it returns. Rows that were
+    /// not sent along with the response are fetched as needed.
+    pub fn query(&mut self, query: &str, params: Vec<monetizer::SQLParameter>) -> Result<Rows<'_>> {
+        let result = self.execute_open(query, params)?;
+        Ok(Rows::new(self, result))
+    }
+
+    /// Like `query`, but the rows borrow their values from the receive
+    /// buffer instead of being copied. The buffer is reused for every page
+    /// of rows, and for the next `query_raw`.
+    pub fn query_raw(
+        &mut self,
+        query: &str,
+        params: Vec<monetizer::SQLParameter>,
+    ) -> Result<RawRows<'_>> {
+        let escaped_query = monetizer::apply_parameters(query, params)?;
+        let span = query_span(query);
+        let _entered = span.enter();
+        let started = Instant::now();
+        self.flush_closes()?;
         let command = String::from("s") + &escaped_query + "\n;";
-        let resp = self.connection.cmd(&command[..])?;
+        let mut buffer = std::mem::take(&mut self.buffer);
+        self.connection.query_into(&command, &mut buffer)?;
+        let page = String::from_utf8(buffer).map_err(MapiError::from)?;
+
+        let rows = RawRows::new(self, page)?;
+        span.record("rows", rows.tuple_count());
+        span.record("duration_us", started.elapsed().as_micros() as u64);
+        Ok(rows)
+    }
+
+    pub(crate) fn return_buffer(&mut self, buffer: Vec<u8>) {
+        self.buffer = buffer;
+    }
+
+    /// A DB-API style cursor.
+    pub fn cursor(&mut self) -> Cursor<'_> {
+        Cursor::new(self)
+    }
+
+    /// Set the number of rows the server sends along with the response to a
+    /// query. The rest are fetched later.
+    pub fn set_reply_size(&mut self, rows: u64) -> Result<()> {
+        self.flush_closes()?;
+        self.connection.cmd(&format!("Xreply_size {}", rows))?;
+        self.reply_size = Some(rows);
+        Ok(())
+    }
+
+    /// The reply size set with `set_reply_size`.
+    pub fn reply_size(&self) -> Option<u64> {
+        self.reply_size
+    }
+
+    /// The number of result sets the server keeps for this connection.
+    pub fn open_results(&self) -> usize {
+        self.open_results.len()
+    }
 
-        debug!("Query:\n{}\nResponse:\n{}", query, resp);
+    pub(crate) fn open_result(&mut self, id: u64) {
+        self.open_results.push(id);
+    }
+
+    /// Release result set `id` on the server. This happens when the next
+    /// command is sent, so that dropping a `Rows` does not block.
+    pub(crate) fn close_result(&mut self, id: u64) {
+        if self.open_results.contains(&id) && !self.pending_closes.contains(&id) {
+            self.pending_closes.push(id);
+        }
+    }
 
-        let insertions = resp
-            .split_whitespace()
-            .nth(1)
-            .unwrap()
-            .parse::<u64>()
-            .unwrap();
-        Ok(insertions)
+    /// Release the rest of a result set nobody is going to fetch.
+    fn discard(&mut self, result: &QueryResult) {
+        if let (ResultKind::Table, Some(id), Some(count)) =
+            (&result.kind, result.result_id, result.tuple_count)
+        {
+            if count > result.rows.len() as u64 {
+                self.open_result(id);
+                self.close_result(id);
+            }
+        }
     }
+
+    /// Send the pending closes. MAPI has no way to close several result sets
+    /// with one command, so send them all before reading the responses.
+    fn flush_closes(&mut self) -> Result<()> {
+        if self.pending_closes.is_empty() {
+            return Ok(());
+        }
+        let ids = mem::take(&mut self.pending_closes);
+        self.open_results.retain(|r| !ids.contains(r));
+        let commands: Vec<String> = ids.iter().map(|id| format!("Xclose {}", id)).collect();
+        let responses = self.connection.pipeline(&commands)?;
+        for (id, response) in ids.iter().zip(responses) {
+            // The result set is gone anyway, e.g. after a reconnect
+            if response.starts_with('!') {
+                debug!("Xclose {}: {}", id, response.trim_end());
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch `count` rows of result set `id`, starting at row `offset`.
+    pub(crate) fn export(&mut self, id: u64, offset: u64, count: u64) -> Result<String> {
+        let mut buffer = vec![];
+        self.export_into(id, offset, count, &mut buffer)?;
+        Ok(String::from_utf8(buffer).map_err(MapiError::from)?)
+    }
+
+    /// Like `export`, but the response replaces the contents of `buffer`.
+    pub(crate) fn export_into(
+        &mut self,
+        id: u64,
+        offset: u64,
+        count: u64,
+        buffer: &mut Vec<u8>,
+    ) -> Result<()> {
+        self.flush_closes()?;
+        let command = format!("Xexport {} {} {}", id, offset, count);
+        Ok(self.connection.query_into(&command, buffer)?)
+    }
+
+    /// Run several `;` separated statements at once. Returns the result of
+    /// every statement, or `MonetDBError::StatementError` with the index of
+    /// the first statement that failed. Statements before it have been
+    /// executed. As with `execute`, result sets only include the rows sent
+    /// along with the response.
+    pub fn execute_script(&mut self, sql: &str) -> Result<Vec<QueryResult>> {
+        let span = debug_span!(
+            "script",
+            script = %redaction::summary(sql),
+            statements = Empty,
+            duration_us = Empty
+        );
+        let _entered = span.enter();
+        let started = Instant::now();
+        self.flush_closes()?;
+        let command = String::from("s") + sql + "\n;";
+        let resp = self.connection.query(&command)?;
+        trace!(response = %resp);
+
+        let results = response::parse_response(&resp)?;
+        for result in &results {
+            self.discard(result);
+        }
+        span.record("statements", results.len());
+        span.record("duration_us", started.elapsed().as_micros() as u64);
+        Ok(results)
+    }
+
+    fn run(&mut self, query: &str, escaped_query: &str) -> Result<QueryResult> {
+        let span = query_span(query);
+        let _entered = span.enter();
+        let started = Instant::now();
+        self.flush_closes()?;
+        let command = String::from("s") + escaped_query + "\n;";
+        let resp = self.connection.query(&command[..])?;
+        trace!(response = %resp);
+
+        let mut results = response::parse_response(&resp)?;
+        let result = results.pop().ok_or_else(|| {
+            MonetDBError::UnimplementedError(format!("no result for query {:?}", query))
+        })?;
+        // Only the last result is returned
+        for result in &results {
+            self.discard(result);
+        }
+        span.record("kind", tracing::field::debug(&result.kind));
+        if let Some(id) = result.query_id {
+            span.record("query_id", id);
+        }
+        if let Some(rows) = result.tuple_count {
+            span.record("rows", rows);
+        }
+        if let Some(rows) = result.affected_rows {
+            span.record("affected_rows", rows);
+        }
+        if let Some(exec) = result.exec_time {
+            span.record("exec_us", exec.as_micros() as u64);
+        }
+        span.record("duration_us", started.elapsed().as_micros() as u64);
+        Ok(result)
+    }
+}
+
+/// The span of a query. The query is redacted, the other fields are filled
+/// in from the result.
+fn query_span(query: &str) -> Span {
+    debug_span!(
+        "query",
+        query = %redaction::summary(query),
+        kind = Empty,
+        query_id = Empty,
+        rows = Empty,
+        affected_rows = Empty,
+        exec_us = Empty,
+        duration_us = Empty
+    )
 }
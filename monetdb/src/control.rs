@@ -0,0 +1,386 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! A client for the control protocol of monetdbd (merovingian).
+//!
+//! This offers the functionality of the `monetdb` command line tool: listing,
+//! creating, starting and stopping databases and managing their properties.
+use std::collections::HashMap;
+use std::result;
+
+use mapi::errors::{MapiError, MonetDBError};
+use mapi::mapi::{MapiConnection, MapiConnectionParams, MapiLanguage};
+use tracing::debug;
+
+pub type Result<T> = result::Result<T, MonetDBError>;
+
+/// The state of a database as reported by monetdbd.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DatabaseState {
+    Illegal,
+    Running,
+    Crashed,
+    Inactive,
+    Starting,
+}
+
+impl DatabaseState {
+    fn from_code(code: i64) -> Result<DatabaseState> {
+        match code {
+            0 => Ok(DatabaseState::Illegal),
+            1 => Ok(DatabaseState::Running),
+            2 => Ok(DatabaseState::Crashed),
+            3 => Ok(DatabaseState::Inactive),
+            4 => Ok(DatabaseState::Starting),
+            _ => Err(unknown_response(format!("unknown database state {}", code))),
+        }
+    }
+}
+
+/// The status of a single database, as described by a sabdb status line.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DatabaseStatus {
+    pub name: String,
+    pub path: String,
+    pub locked: bool,
+    pub state: DatabaseState,
+    pub scenarios: Vec<String>,
+    pub start_counter: i64,
+    pub stop_counter: i64,
+    pub crash_counter: i64,
+    pub avg_uptime: i64,
+    pub max_uptime: i64,
+    pub min_uptime: i64,
+    pub last_crash: i64,
+    pub last_start: i64,
+    /// Only reported by version 2 of the sabdb protocol.
+    pub last_stop: Option<i64>,
+    pub crash_avg1: bool,
+    pub crash_avg10: f64,
+    pub crash_avg30: f64,
+}
+
+impl DatabaseStatus {
+    /// Parse a status line of the form `sabdb:<version>:<fields>`, with or
+    /// without the leading '='.
+    pub fn parse(line: &str) -> Result<DatabaseStatus> {
+        let line = line.strip_prefix('=').unwrap_or(line);
+        let mut parts = line.splitn(3, ':');
+        if parts.next() != Some("sabdb") {
+            return Err(unknown_response(format!("not a status line: {}", line)));
+        }
+        let version = match parts.next() {
+            Some("1") => 1,
+            Some("2") => 2,
+            v => {
+                return Err(unknown_response(format!(
+                    "unsupported sabdb protocol version: {:?}",
+                    v
+                )))
+            }
+        };
+        let mut fields = parts.next().unwrap_or("").split(',');
+        let mut next = || {
+            fields
+                .next()
+                .ok_or_else(|| unknown_response(format!("truncated status line: {}", line)))
+        };
+
+        let name = next()?.to_string();
+        let path = next()?.to_string();
+        let locked = next()? == "1";
+        let state = DatabaseState::from_code(parse_number(next()?)?)?;
+        let scenarios = next()?
+            .split('\'')
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect();
+        if version == 1 {
+            // The connections field was dropped in version 2
+            next()?;
+        }
+        let start_counter = parse_number(next()?)?;
+        let stop_counter = parse_number(next()?)?;
+        let crash_counter = parse_number(next()?)?;
+        let avg_uptime = parse_number(next()?)?;
+        let max_uptime = parse_number(next()?)?;
+        let min_uptime = parse_number(next()?)?;
+        let last_crash = parse_number(next()?)?;
+        let last_start = parse_number(next()?)?;
+        let last_stop = if version > 1 {
+            Some(parse_number(next()?)?)
+        } else {
+            None
+        };
+        let crash_avg1 = next()? == "1";
+        let crash_avg10 = parse_number(next()?)?;
+        let crash_avg30 = parse_number(next()?)?;
+
+        Ok(DatabaseStatus {
+            name,
+            path,
+            locked,
+            state,
+            scenarios,
+            start_counter,
+            stop_counter,
+            crash_counter,
+            avg_uptime,
+            max_uptime,
+            min_uptime,
+            last_crash,
+            last_start,
+            last_stop,
+            crash_avg1,
+            crash_avg10,
+            crash_avg30,
+        })
+    }
+}
+
+/// A client for monetdbd.
+///
+/// Every command opens a new connection, since monetdbd closes the local
+/// control socket after each reply.
+pub struct Control {
+    hostname: String,
+    port: u16,
+    passphrase: Option<String>,
+}
+
+impl Control {
+    /// Create a new control client.
+    ///
+    /// If `hostname` is `None` or starts with a '/' the Unix control socket
+    /// (`<hostname or /tmp>/.s.merovingian.<port>`) is used and no passphrase
+    /// is needed. Otherwise the client connects over TCP and authenticates
+    /// using `passphrase`.
+    pub fn new(hostname: Option<&str>, port: Option<u16>, passphrase: Option<&str>) -> Control {
+        Control {
+            hostname: hostname.unwrap_or("/tmp").to_string(),
+            port: port.unwrap_or(50000),
+            passphrase: passphrase.map(String::from),
+        }
+    }
+
+    /// The status of all the databases monetdbd knows about.
+    pub fn status(&self) -> Result<Vec<DatabaseStatus>> {
+        payload_lines(&self.send_command("#all", "status")?)
+            .map(DatabaseStatus::parse)
+            .collect()
+    }
+
+    /// The status of a single database.
+    pub fn database_status(&self, database: &str) -> Result<DatabaseStatus> {
+        let response = self.send_command(database, "status")?;
+        let line = payload_lines(&response).next().ok_or_else(|| {
+            unknown_response(format!("no status returned for database {}", database))
+        })?;
+        DatabaseStatus::parse(line)
+    }
+
+    /// Create a new database. It is created in maintenance mode.
+    pub fn create(&self, database: &str) -> Result<()> {
+        self.expect_empty(database, "create")
+    }
+
+    /// Remove a database and all its data.
+    pub fn destroy(&self, database: &str) -> Result<()> {
+        self.expect_empty(database, "destroy")
+    }
+
+    /// Start a database.
+    pub fn start(&self, database: &str) -> Result<()> {
+        self.expect_empty(database, "start")
+    }
+
+    /// Stop a database.
+    pub fn stop(&self, database: &str) -> Result<()> {
+        self.expect_empty(database, "stop")
+    }
+
+    /// Kill a database, without giving it time to shut down cleanly.
+    pub fn kill(&self, database: &str) -> Result<()> {
+        self.expect_empty(database, "kill")
+    }
+
+    /// Put a database in maintenance mode.
+    pub fn lock(&self, database: &str) -> Result<()> {
+        self.expect_empty(database, "lock")
+    }
+
+    /// Bring a database back from maintenance mode.
+    pub fn release(&self, database: &str) -> Result<()> {
+        self.expect_empty(database, "release")
+    }
+
+    /// Get the properties of a database.
+    pub fn get(&self, database: &str) -> Result<HashMap<String, String>> {
+        let response = self.send_command(database, "get")?;
+        let mut properties = HashMap::new();
+        for line in payload_lines(&response) {
+            if let Some((key, value)) = line.split_once('=') {
+                properties.insert(key.to_string(), value.trim().to_string());
+            }
+        }
+        Ok(properties)
+    }
+
+    /// Get the default properties of new databases.
+    pub fn defaults(&self) -> Result<HashMap<String, String>> {
+        self.get("#defaults")
+    }
+
+    /// Set a property of a database.
+    pub fn set(&self, database: &str, property: &str, value: &str) -> Result<()> {
+        self.expect_empty(database, &format!("{}={}", property, value))
+    }
+
+    /// Reset a property of a database to the monetdbd default.
+    pub fn inherit(&self, database: &str, property: &str) -> Result<()> {
+        self.expect_empty(database, &format!("{}=", property))
+    }
+
+    /// Rename a database.
+    pub fn rename(&self, database: &str, new_name: &str) -> Result<()> {
+        self.set(database, "name", new_name)
+    }
+
+    fn expect_empty(&self, database: &str, command: &str) -> Result<()> {
+        let response = self.send_command(database, command)?;
+        if payload_lines(&response).next().is_none() {
+            Ok(())
+        } else {
+            Err(MonetDBError::ConnectionError(MapiError::OperationError(
+                response.trim().to_string(),
+            )))
+        }
+    }
+
+    fn send_command(&self, database: &str, command: &str) -> Result<String> {
+        let mut params = MapiConnectionParams::new(
+            "merovingian",
+            "monetdb",
+            None,
+            Some(MapiLanguage::Control),
+            Some(&self.hostname),
+            Some(self.port),
+        );
+        params.password = self.passphrase.clone();
+        let mut connection = MapiConnection::connect(params)?;
+        debug!("control: {} {}", database, command);
+        let response = connection.cmd(&format!("{} {}\n", database, command));
+        let _ = connection.close();
+
+        Ok(response?)
+    }
+}
+
+/// The meaningful lines of a control response, stripped of prompts.
+fn payload_lines(response: &str) -> impl Iterator<Item = &str> {
+    response
+        .lines()
+        .map(|l| l.strip_prefix('=').unwrap_or(l).trim())
+        .filter(|l| !l.is_empty() && *l != "OK" && !l.starts_with('#'))
+}
+
+fn parse_number<T: std::str::FromStr>(field: &str) -> Result<T> {
+    field
+        .parse::<T>()
+        .map_err(|_| unknown_response(format!("invalid number in status line: {}", field)))
+}
+
+fn unknown_response(message: String) -> MonetDBError {
+    MonetDBError::ConnectionError(MapiError::UnknownServerResponse(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS_V2: &str = "=sabdb:2:demo,/var/monetdb5/dbfarm/demo,0,1,sql'mal,3,1,0,120,200,60,0,1650000000,1650000100,0,0.0,0.0";
+    const STATUS_V1: &str =
+        "sabdb:1:test,/dbfarm/test,1,3,sql,0,2,2,0,10,20,5,0,1650000000,0,0.5,1.5";
+
+    #[test]
+    fn parse_status_v2() {
+        let status = DatabaseStatus::parse(STATUS_V2).unwrap();
+        assert_eq!(status.name, "demo");
+        assert_eq!(status.path, "/var/monetdb5/dbfarm/demo");
+        assert!(!status.locked);
+        assert_eq!(status.state, DatabaseState::Running);
+        assert_eq!(status.scenarios, vec!["sql", "mal"]);
+        assert_eq!(status.start_counter, 3);
+        assert_eq!(status.last_stop, Some(1650000100));
+        assert!(!status.crash_avg1);
+    }
+
+    #[test]
+    fn parse_status_v1() {
+        let status = DatabaseStatus::parse(STATUS_V1).unwrap();
+        assert_eq!(status.name, "test");
+        assert!(status.locked);
+        assert_eq!(status.state, DatabaseState::Inactive);
+        assert_eq!(status.start_counter, 2);
+        assert_eq!(status.last_stop, None);
+        assert_eq!(status.crash_avg10, 0.5);
+        assert_eq!(status.crash_avg30, 1.5);
+    }
+
+    #[test]
+    fn parse_status_errors() {
+        assert!(DatabaseStatus::parse("=OK").is_err());
+        assert!(DatabaseStatus::parse("sabdb:3:demo").is_err());
+        assert!(DatabaseStatus::parse("sabdb:2:demo,/path,0").is_err());
+        assert!(DatabaseStatus::parse("sabdb:2:demo,/path,0,x,sql").is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn local_control_socket() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixListener;
+        use std::thread;
+
+        let dir = std::env::temp_dir().join(format!("monetdb-control-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join(".s.merovingian.50000");
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let replies = vec![
+            (
+                "#all status\n",
+                format!("OK\n{}\n{}\n", STATUS_V2, STATUS_V1),
+            ),
+            ("demo stop\n", String::from("OK\n")),
+            ("demo get\n", String::from("OK\nname=demo\nreadonly=no\n")),
+            ("demo frobnicate\n", String::from("unknown command\n")),
+        ];
+        let server = thread::spawn(move || {
+            for (expected, reply) in replies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0u8; 128];
+                let n = stream.read(&mut buf).unwrap();
+                assert_eq!(&buf[..n], expected.as_bytes());
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let control = Control::new(dir.to_str(), Some(50000), None);
+        let status = control.status().unwrap();
+        assert_eq!(status.len(), 2);
+        assert_eq!(status[0].name, "demo");
+        assert_eq!(status[1].name, "test");
+        control.stop("demo").unwrap();
+        let properties = control.get("demo").unwrap();
+        assert_eq!(properties.get("readonly").map(String::as_str), Some("no"));
+        assert!(control.expect_empty("demo", "frobnicate").is_err());
+
+        server.join().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,194 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! A cursor in the style of the Python DB-API, so that code written for
+//! pymonetdb translates directly.
+//!
+//! ```no_run
+//! # use monetdb::connection::Connection;
+//! let mut connection = Connection::connect("mapi://localhost:50000/demo").unwrap();
+//! let mut cursor = connection.cursor();
+//! cursor.arraysize = 1000;
+//! cursor.execute("SELECT * FROM tables", vec![]).unwrap();
+//! while let Some(row) = cursor.fetchone().unwrap() {
+//!     println!("{:?}", row);
+//! }
+//! ```
+use crate::connection::{Connection, Result};
+use crate::monetizer::SQLParameter;
+use crate::response::{Column, ResultKind};
+use crate::rows::{ResultSet, Row, DEFAULT_FETCH_SIZE};
+use mapi::errors::MonetDBError;
+
+pub struct Cursor<'a> {
+    connection: &'a mut Connection,
+    /// The number of rows fetched from the server at a time, and the
+    /// default number of rows for `fetchmany`. The first rows come with the
+    /// response to the query, as many as the reply size of the connection
+    /// (`Connection::set_reply_size`), which the cursor leaves alone.
+    pub arraysize: usize,
+    result: Option<ResultSet>,
+    rowcount: Option<u64>,
+    lastrowid: Option<i64>,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(connection: &'a mut Connection) -> Cursor<'a> {
+        Cursor {
+            connection,
+            arraysize: DEFAULT_FETCH_SIZE as usize,
+            result: None,
+            rowcount: None,
+            lastrowid: None,
+        }
+    }
+
+    /// Execute a query. Any rows of a previous query that were not fetched
+    /// are discarded.
+    pub fn execute(&mut self, query: &str, params: Vec<SQLParameter>) -> Result<()> {
+        self.close();
+        let result = self.connection.execute_open(query, params)?;
+        self.lastrowid = result.last_id;
+        match result.kind {
+            ResultKind::Table | ResultKind::Prepare => {
+                self.rowcount = result.tuple_count;
+                self.result = Some(ResultSet::new(self.connection, result));
+            }
+            ResultKind::Update => self.rowcount = result.affected_rows,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The next row, or `None` if all rows have been fetched.
+    pub fn fetchone(&mut self) -> Result<Option<Row>> {
+        let arraysize = self.arraysize as u64;
+        match self.result {
+            Some(ref mut result) => result.next_row(self.connection, arraysize),
+            None => Err(no_result_set()),
+        }
+    }
+
+    /// Up to `size` rows. Fewer are returned if there are no more.
+    pub fn fetchmany(&mut self, size: usize) -> Result<Vec<Row>> {
+        let mut rows = Vec::with_capacity(size.min(self.arraysize));
+        while rows.len() < size {
+            match self.fetchone()? {
+                Some(row) => rows.push(row),
+                None => break,
+            }
+        }
+        Ok(rows)
+    }
+
+    /// All remaining rows.
+    pub fn fetchall(&mut self) -> Result<Vec<Row>> {
+        let mut rows = vec![];
+        while let Some(row) = self.fetchone()? {
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
+    /// The columns of the last query, if it returned rows.
+    pub fn description(&self) -> Option<&[Column]> {
+        self.result.as_ref().map(|r| r.columns.as_slice())
+    }
+
+    /// The number of rows the last query returned or changed, if known.
+    pub fn rowcount(&self) -> Option<u64> {
+        self.rowcount
+    }
+
+    /// The id generated for an auto increment column by the last `INSERT`.
+    pub fn lastrowid(&self) -> Option<i64> {
+        self.lastrowid
+    }
+
+    /// Discard the current result set.
+    pub fn close(&mut self) {
+        if let Some(mut result) = self.result.take() {
+            result.close(self.connection);
+        }
+        self.rowcount = None;
+        self.lastrowid = None;
+    }
+}
+
+impl<'a> Drop for Cursor<'a> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn no_result_set() -> MonetDBError {
+    MonetDBError::InvalidParameters(String::from(
+        "no result set: the last query did not return rows",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{mock_connection, mock_server};
+
+    const HEADER: &str = "% t # table_name\n% i # name\n% int # type\n% 1 # length\n";
+
+    fn values(rows: Vec<Vec<Option<String>>>) -> Vec<String> {
+        rows.into_iter().map(|r| r[0].clone().unwrap()).collect()
+    }
+
+    #[test]
+    fn fetching() {
+        let (port, server) = mock_server(vec![
+            format!("&1 9 5 1 2\n{}[ 1\t]\n[ 2\t]\n", HEADER),
+            String::from("&6 9 1 2 2\n[ 3\t]\n[ 4\t]\n"),
+            String::from("&6 9 1 1 4\n[ 5\t]\n"),
+            String::new(),
+            String::from("&2 3 17\n"),
+            format!("&1 10 1 1 1\n{}[ 6\t]\n", HEADER),
+        ]);
+        let mut connection = mock_connection(port);
+        let mut cursor = connection.cursor();
+        assert!(cursor.fetchone().is_err());
+        cursor.arraysize = 2;
+
+        cursor.execute("SELECT i FROM t", vec![]).unwrap();
+        assert_eq!(cursor.rowcount(), Some(5));
+        assert_eq!(cursor.description().unwrap()[0].name, "i");
+        assert_eq!(
+            cursor.fetchone().unwrap(),
+            Some(vec![Some(String::from("1"))])
+        );
+        assert_eq!(values(cursor.fetchmany(3).unwrap()), vec!["2", "3", "4"]);
+        assert_eq!(values(cursor.fetchall().unwrap()), vec!["5"]);
+        assert_eq!(cursor.fetchone().unwrap(), None);
+
+        cursor.execute("UPDATE t SET i = 0", vec![]).unwrap();
+        assert_eq!(cursor.rowcount(), Some(3));
+        assert_eq!(cursor.lastrowid(), Some(17));
+        assert!(cursor.description().is_none());
+        drop(cursor);
+
+        // The arraysize of the cursor is not the reply size of the connection
+        assert_eq!(connection.reply_size(), None);
+        let result = connection.execute("SELECT i FROM t", vec![]).unwrap();
+        assert_eq!(values(result.rows), vec!["6"]);
+        drop(connection);
+
+        let received = server.join().unwrap();
+        assert_eq!(
+            received,
+            vec![
+                "sSELECT i FROM t\n;",
+                "Xexport 9 2 2",
+                "Xexport 9 4 1",
+                "Xclose 9",
+                "sUPDATE t SET i = 0\n;",
+                "sSELECT i FROM t\n;",
+            ]
+        );
+    }
+}
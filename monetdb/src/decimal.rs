@@ -0,0 +1,162 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Parsing and formatting of the textual representation of MonetDB
+//! decimals. Shared by the `ToSql` and `FromSql` implementations of the
+//! different decimal libraries, which only have to deal with an integer
+//! mantissa and a scale.
+use std::result;
+
+use crate::monetizer::{conversion_error, SqlType};
+use mapi::errors::MonetDBError;
+
+type Result<T> = result::Result<T, MonetDBError>;
+
+/// The largest precision of a MonetDB `decimal`.
+pub(crate) const MAX_PRECISION: usize = 38;
+
+/// A decimal as an unscaled integer and a number of fractional digits.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Unscaled {
+    pub negative: bool,
+    /// The decimal digits of the mantissa, without a sign.
+    pub digits: String,
+    pub scale: u32,
+}
+
+/// Parse a decimal value, e.g. `-12.340`. If the column type has a scale the
+/// result has that scale, otherwise the scale is the number of fractional
+/// digits in `value`. A value with more fractional digits than the column
+/// scale is an error, since it can't be represented without rounding.
+pub(crate) fn parse_decimal(value: &str, sql_type: &SqlType) -> Result<Unscaled> {
+    let (negative, rest) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let (integer, fraction) = rest.split_once('.').unwrap_or((rest, ""));
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if integer.len() + fraction.len() == 0 || !is_digits(integer) || !is_digits(fraction) {
+        return Err(conversion_error(value, sql_type, "decimal"));
+    }
+    let scale = (sql_type.scale as usize).max(fraction.len());
+    if sql_type.scale > 0 && fraction.len() > sql_type.scale as usize {
+        return Err(conversion_error(value, sql_type, "decimal"));
+    }
+
+    let mut digits = String::with_capacity(integer.len() + scale);
+    digits.push_str(integer);
+    digits.push_str(fraction);
+    for _ in fraction.len()..scale {
+        digits.push('0');
+    }
+    let digits = match digits.trim_start_matches('0') {
+        "" => String::from("0"),
+        trimmed => trimmed.to_string(),
+    };
+
+    Ok(Unscaled {
+        negative,
+        digits,
+        scale: scale as u32,
+    })
+}
+
+/// Format `digits * 10^-scale` as an exact decimal literal. Trailing zeros
+/// in the fraction are dropped; a negative `scale` appends zeros. Values
+/// that need more than 38 digits are an error.
+pub(crate) fn format_decimal(
+    negative: bool,
+    digits: &str,
+    scale: i64,
+) -> result::Result<String, String> {
+    let mut digits = digits.trim_start_matches('0').to_string();
+    let mut scale = scale;
+    while scale > 0 && digits.ends_with('0') {
+        digits.pop();
+        scale -= 1;
+    }
+    if digits.is_empty() {
+        return Ok(String::from("0"));
+    }
+    if scale < 0 {
+        let zeros = (-scale) as usize;
+        if digits.len() + zeros > MAX_PRECISION {
+            return Err(overflow(negative, &digits, scale));
+        }
+        digits.extend(std::iter::repeat_n('0', zeros));
+        scale = 0;
+    }
+    let scale = scale as usize;
+    if digits.len().max(scale) > MAX_PRECISION {
+        return Err(overflow(negative, &digits, scale as i64));
+    }
+
+    let mut literal = String::with_capacity(digits.len() + 3);
+    if negative {
+        literal.push('-');
+    }
+    if digits.len() > scale {
+        let (integer, fraction) = digits.split_at(digits.len() - scale);
+        literal.push_str(integer);
+        if !fraction.is_empty() {
+            literal.push('.');
+            literal.push_str(fraction);
+        }
+    } else {
+        literal.push_str("0.");
+        literal.extend(std::iter::repeat_n('0', scale - digits.len()));
+        literal.push_str(&digits);
+    }
+
+    Ok(literal)
+}
+
+fn overflow(negative: bool, digits: &str, scale: i64) -> String {
+    format!(
+        "{}{}E{} does not fit in a DECIMAL({})",
+        if negative { "-" } else { "" },
+        digits,
+        -scale,
+        MAX_PRECISION
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unscaled(value: &str, scale: u32) -> Result<(bool, String, u32)> {
+        let d = parse_decimal(value, &SqlType::with_size("decimal", 18, scale))?;
+        Ok((d.negative, d.digits, d.scale))
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(unscaled("12.340", 3).unwrap(), (false, "12340".into(), 3));
+        assert_eq!(unscaled("-0.5", 3).unwrap(), (true, "500".into(), 3));
+        assert_eq!(unscaled("7", 2).unwrap(), (false, "700".into(), 2));
+        assert_eq!(unscaled("0.000", 3).unwrap(), (false, "0".into(), 3));
+        assert_eq!(unscaled("1.25", 0).unwrap(), (false, "125".into(), 2));
+        for bad in ["", "-", ".", "1.2.3", "1e5", "12.3456", "abc", "1,5"] {
+            assert!(unscaled(bad, 3).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(format_decimal(false, "12340", 3).unwrap(), "12.34");
+        assert_eq!(format_decimal(true, "5", 3).unwrap(), "-0.005");
+        assert_eq!(format_decimal(false, "12", -3).unwrap(), "12000");
+        assert_eq!(format_decimal(true, "000", 2).unwrap(), "0");
+        assert_eq!(format_decimal(false, "100", 2).unwrap(), "1");
+
+        let max = "9".repeat(38);
+        assert_eq!(format_decimal(false, &max, 0).unwrap(), max);
+        assert!(format_decimal(false, &max, -1).is_err());
+        assert!(format_decimal(false, &"1".repeat(39), 20).is_err());
+        assert!(format_decimal(false, "1", 39).is_err());
+    }
+}
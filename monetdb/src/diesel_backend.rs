@@ -0,0 +1,931 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! A diesel backend for MonetDB, enabled with the `diesel` feature.
+//!
+//! `MonetDBConnection` wraps a `Connection` and implements diesel's
+//! `Connection` and `LoadConnection`, so the usual query DSL and
+//! `sql_query` work:
+//!
+//! ```no_run
+//! use diesel::prelude::*;
+//! use monetdb::diesel_backend::MonetDBConnection;
+//!
+//! diesel::table! {
+//!     users (id) {
+//!         id -> Integer,
+//!         name -> Text,
+//!     }
+//! }
+//!
+//! let mut conn = MonetDBConnection::establish("mapi:monetdb://localhost/demo").unwrap();
+//! let names: Vec<String> = users::table
+//!     .select(users::name)
+//!     .order(users::id)
+//!     .limit(10)
+//!     .load(&mut conn)
+//!     .unwrap();
+//! ```
+//!
+//! Bind parameters are rendered as MonetDB literals with `monetizer` and
+//! substituted in the query text before it is sent, like the parameters of
+//! `Connection::execute`. The following types are mapped:
+//!
+//! | diesel      | Rust      | MonetDB            |
+//! |-------------|-----------|--------------------|
+//! | `Bool`      | `bool`    | `boolean`          |
+//! | `TinyInt`   | `i8`      | `tinyint`          |
+//! | `SmallInt`  | `i16`     | `smallint`         |
+//! | `Integer`   | `i32`     | `int`              |
+//! | `BigInt`    | `i64`     | `bigint`           |
+//! | `Float`     | `f32`     | `real`             |
+//! | `Double`    | `f64`     | `double`           |
+//! | `Text`      | `String`  | `varchar`, `clob`  |
+//! | `Binary`    | `Vec<u8>` | `blob`             |
+//!
+//! With the `chrono` or `time` features `Date`, `Time` and `Timestamp` map
+//! to `NaiveDate`, `NaiveTime` and `NaiveDateTime`, or to `Date`, `Time` and
+//! `PrimitiveDateTime`. They are sent as typed literals, e.g.
+//! `DATE '2024-01-31'`.
+//!
+//! MonetDB has no `RETURNING` or `ON CONFLICT` clauses, so the diesel
+//! methods that need them are not available for this backend.
+use std::ops::Range;
+use std::rc::Rc;
+
+use diesel::backend::sql_dialect;
+use diesel::backend::{Backend, DieselReserveSpecialization, SqlDialect, TrustedBackend};
+use diesel::connection::{
+    get_default_instrumentation, AnsiTransactionManager, CacheSize, ConnectionSealed,
+    DefaultLoadingMode, Instrumentation, InstrumentationEvent, LoadConnection, SimpleConnection,
+};
+use diesel::deserialize::{self, FromSql};
+use diesel::expression::QueryMetadata;
+use diesel::query_builder::bind_collector::RawBytesBindCollector;
+use diesel::query_builder::{
+    AstPass, BoxedLimitOffsetClause, IntoBoxedClause, LimitClause, LimitOffsetClause,
+    NoLimitClause, NoOffsetClause, OffsetClause, Query, QueryBuilder, QueryFragment, QueryId,
+};
+use diesel::result::{
+    ConnectionError, ConnectionResult, DatabaseErrorInformation, DatabaseErrorKind, Error,
+    QueryResult,
+};
+use diesel::row::{Field, PartialRow, Row, RowIndex, RowSealed};
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::{self, HasSqlType, TypeMetadata};
+
+use crate::connection::Connection;
+use crate::monetizer::{self, SQLParameter, SqlType};
+use crate::response::{split_error, Column, Constraint};
+use mapi::errors::{MapiError, MonetDBError};
+use mapi::session::quote_identifier;
+
+/// The MonetDB backend.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Default)]
+pub struct MonetDB;
+
+/// The type of a bind parameter. Text and binary parameters are quoted when
+/// they are rendered, the others are sent as written by `ToSql`.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub enum MonetDBType {
+    Boolean,
+    TinyInt,
+    SmallInt,
+    Int,
+    BigInt,
+    Real,
+    Double,
+    Varchar,
+    Blob,
+    Date,
+    Time,
+    Timestamp,
+}
+
+/// A value received from the server, as text, with the type of its column.
+#[derive(Debug, Clone, Copy)]
+pub struct MonetDBValue<'a> {
+    value: &'a str,
+    sql_type: &'a SqlType,
+}
+
+impl<'a> MonetDBValue<'a> {
+    /// The value as the server sent it.
+    pub fn as_str(&self) -> &'a str {
+        self.value
+    }
+
+    /// The type of the column the value is from.
+    pub fn sql_type(&self) -> &'a SqlType {
+        self.sql_type
+    }
+}
+
+impl Backend for MonetDB {
+    type QueryBuilder = MonetDBQueryBuilder;
+    type RawValue<'a> = MonetDBValue<'a>;
+    type BindCollector<'a> = RawBytesBindCollector<MonetDB>;
+}
+
+impl TypeMetadata for MonetDB {
+    type TypeMetadata = MonetDBType;
+    type MetadataLookup = ();
+}
+
+impl SqlDialect for MonetDB {
+    type ReturningClause = sql_dialect::returning_clause::DoesNotSupportReturningClause;
+    type OnConflictClause = sql_dialect::on_conflict_clause::DoesNotSupportOnConflictClause;
+    type InsertWithDefaultKeyword = sql_dialect::default_keyword_for_insert::IsoSqlDefaultKeyword;
+    type BatchInsertSupport = sql_dialect::batch_insert_support::PostgresLikeBatchInsertSupport;
+    type ConcatClause = sql_dialect::concat_clause::ConcatWithPipesClause;
+    type DefaultValueClauseForInsert = sql_dialect::default_value_clause::AnsiDefaultValueClause;
+    type EmptyFromClauseSyntax = sql_dialect::from_clause_syntax::AnsiSqlFromClauseSyntax;
+    type ExistsSyntax = sql_dialect::exists_syntax::AnsiSqlExistsSyntax;
+    type ArrayComparison = sql_dialect::array_comparison::AnsiSqlArrayComparison;
+    type SelectStatementSyntax = sql_dialect::select_statement_syntax::AnsiSqlSelectStatement;
+    type AliasSyntax = sql_dialect::alias_syntax::AsAliasSyntax;
+    type WindowFrameClauseGroupSupport =
+        sql_dialect::window_frame_clause_group_support::IsoGroupWindowFrameUnit;
+    type WindowFrameExclusionSupport =
+        sql_dialect::window_frame_exclusion_support::NoFrameFrameExclusionSupport;
+    type AggregateFunctionExpressions =
+        sql_dialect::aggregate_function_expressions::NoAggregateFunctionExpressions;
+    type BuiltInWindowFunctionRequireOrder =
+        sql_dialect::built_in_window_function_require_order::NoOrderRequired;
+}
+
+impl DieselReserveSpecialization for MonetDB {}
+impl TrustedBackend for MonetDB {}
+
+macro_rules! has_sql_type {
+    ($($sql_type:ident => $metadata:ident),*) => {
+        $(
+            impl HasSqlType<sql_types::$sql_type> for MonetDB {
+                fn metadata(_: &mut ()) -> MonetDBType {
+                    MonetDBType::$metadata
+                }
+            }
+        )*
+    };
+}
+
+has_sql_type!(
+    Bool => Boolean,
+    TinyInt => TinyInt,
+    SmallInt => SmallInt,
+    Integer => Int,
+    BigInt => BigInt,
+    Float => Real,
+    Double => Double,
+    Text => Varchar,
+    Binary => Blob,
+    Date => Date,
+    Time => Time,
+    Timestamp => Timestamp
+);
+
+/// Builds MonetDB SQL. Identifiers are always quoted, bind parameters are
+/// `?` placeholders.
+#[derive(Debug, Default)]
+pub struct MonetDBQueryBuilder {
+    sql: String,
+}
+
+impl MonetDBQueryBuilder {
+    pub fn new() -> MonetDBQueryBuilder {
+        MonetDBQueryBuilder::default()
+    }
+}
+
+impl QueryBuilder<MonetDB> for MonetDBQueryBuilder {
+    fn push_sql(&mut self, sql: &str) {
+        self.sql.push_str(sql);
+    }
+
+    fn push_identifier(&mut self, identifier: &str) -> QueryResult<()> {
+        self.sql.push_str(&quote_identifier(identifier));
+        Ok(())
+    }
+
+    fn push_bind_param(&mut self) {
+        self.sql.push('?');
+    }
+
+    fn finish(self) -> String {
+        self.sql
+    }
+}
+
+// MonetDB accepts LIMIT and OFFSET on their own, in that order.
+
+impl QueryFragment<MonetDB> for LimitOffsetClause<NoLimitClause, NoOffsetClause> {
+    fn walk_ast<'b>(&'b self, _out: AstPass<'_, 'b, MonetDB>) -> QueryResult<()> {
+        Ok(())
+    }
+}
+
+impl<L> QueryFragment<MonetDB> for LimitOffsetClause<LimitClause<L>, NoOffsetClause>
+where
+    LimitClause<L>: QueryFragment<MonetDB>,
+{
+    fn walk_ast<'b>(&'b self, out: AstPass<'_, 'b, MonetDB>) -> QueryResult<()> {
+        self.limit_clause.walk_ast(out)
+    }
+}
+
+impl<O> QueryFragment<MonetDB> for LimitOffsetClause<NoLimitClause, OffsetClause<O>>
+where
+    OffsetClause<O>: QueryFragment<MonetDB>,
+{
+    fn walk_ast<'b>(&'b self, out: AstPass<'_, 'b, MonetDB>) -> QueryResult<()> {
+        self.offset_clause.walk_ast(out)
+    }
+}
+
+impl<L, O> QueryFragment<MonetDB> for LimitOffsetClause<LimitClause<L>, OffsetClause<O>>
+where
+    LimitClause<L>: QueryFragment<MonetDB>,
+    OffsetClause<O>: QueryFragment<MonetDB>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, MonetDB>) -> QueryResult<()> {
+        self.limit_clause.walk_ast(out.reborrow())?;
+        self.offset_clause.walk_ast(out)
+    }
+}
+
+impl QueryFragment<MonetDB> for BoxedLimitOffsetClause<'_, MonetDB> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, MonetDB>) -> QueryResult<()> {
+        if let Some(limit) = &self.limit {
+            limit.walk_ast(out.reborrow())?;
+        }
+        if let Some(offset) = &self.offset {
+            offset.walk_ast(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> IntoBoxedClause<'a, MonetDB> for LimitOffsetClause<NoLimitClause, NoOffsetClause> {
+    type BoxedClause = BoxedLimitOffsetClause<'a, MonetDB>;
+
+    fn into_boxed(self) -> Self::BoxedClause {
+        BoxedLimitOffsetClause {
+            limit: None,
+            offset: None,
+        }
+    }
+}
+
+impl<'a, L> IntoBoxedClause<'a, MonetDB> for LimitOffsetClause<LimitClause<L>, NoOffsetClause>
+where
+    L: QueryFragment<MonetDB> + Send + 'a,
+{
+    type BoxedClause = BoxedLimitOffsetClause<'a, MonetDB>;
+
+    fn into_boxed(self) -> Self::BoxedClause {
+        BoxedLimitOffsetClause {
+            limit: Some(Box::new(self.limit_clause)),
+            offset: None,
+        }
+    }
+}
+
+impl<'a, O> IntoBoxedClause<'a, MonetDB> for LimitOffsetClause<NoLimitClause, OffsetClause<O>>
+where
+    O: QueryFragment<MonetDB> + Send + 'a,
+{
+    type BoxedClause = BoxedLimitOffsetClause<'a, MonetDB>;
+
+    fn into_boxed(self) -> Self::BoxedClause {
+        BoxedLimitOffsetClause {
+            limit: None,
+            offset: Some(Box::new(self.offset_clause)),
+        }
+    }
+}
+
+impl<'a, L, O> IntoBoxedClause<'a, MonetDB> for LimitOffsetClause<LimitClause<L>, OffsetClause<O>>
+where
+    L: QueryFragment<MonetDB> + Send + 'a,
+    O: QueryFragment<MonetDB> + Send + 'a,
+{
+    type BoxedClause = BoxedLimitOffsetClause<'a, MonetDB>;
+
+    fn into_boxed(self) -> Self::BoxedClause {
+        BoxedLimitOffsetClause {
+            limit: Some(Box::new(self.limit_clause)),
+            offset: Some(Box::new(self.offset_clause)),
+        }
+    }
+}
+
+// Values are converted with the `monetizer` traits. `ToSql` writes the
+// literal, except for text and binary data, which diesel writes as raw bytes
+// and which are quoted in `parameters`.
+macro_rules! mapping {
+    ($($sql_type:ident => $t:ty),*) => {
+        $(
+            impl ToSql<sql_types::$sql_type, MonetDB> for $t {
+                fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, MonetDB>) -> serialize::Result {
+                    let literal = monetizer::ToSql::to_sql(self).to_string();
+                    std::io::Write::write_all(out, literal.as_bytes())?;
+                    Ok(IsNull::No)
+                }
+            }
+
+            impl FromSql<sql_types::$sql_type, MonetDB> for $t {
+                fn from_sql(value: MonetDBValue<'_>) -> deserialize::Result<Self> {
+                    Ok(monetizer::FromSql::from_sql(value.value, value.sql_type)?)
+                }
+            }
+        )*
+    };
+}
+
+mapping!(
+    Bool => bool,
+    TinyInt => i8,
+    SmallInt => i16,
+    Integer => i32,
+    BigInt => i64,
+    Float => f32,
+    Double => f64
+);
+
+#[cfg(feature = "chrono")]
+mapping!(
+    Date => chrono::NaiveDate,
+    Time => chrono::NaiveTime,
+    Timestamp => chrono::NaiveDateTime
+);
+
+#[cfg(feature = "time")]
+mapping!(
+    Date => time::Date,
+    Time => time::Time,
+    Timestamp => time::PrimitiveDateTime
+);
+
+impl FromSql<sql_types::Text, MonetDB> for String {
+    fn from_sql(value: MonetDBValue<'_>) -> deserialize::Result<Self> {
+        Ok(value.value.to_string())
+    }
+}
+
+impl FromSql<sql_types::Binary, MonetDB> for Vec<u8> {
+    fn from_sql(value: MonetDBValue<'_>) -> deserialize::Result<Self> {
+        Ok(monetizer::FromSql::from_sql(value.value, value.sql_type)?)
+    }
+}
+
+/// Render the collected bind parameters as MonetDB literals.
+fn parameters(binds: RawBytesBindCollector<MonetDB>) -> QueryResult<Vec<SQLParameter>> {
+    binds
+        .binds
+        .into_iter()
+        .zip(binds.metadata)
+        .map(|(bytes, sql_type)| {
+            let bytes = match bytes {
+                Some(bytes) => bytes,
+                None => return Ok(SQLParameter::literal(String::from("NULL"))),
+            };
+            Ok(match sql_type {
+                MonetDBType::Blob => monetizer::ToSql::to_sql(&bytes),
+                MonetDBType::Varchar => {
+                    let text = std::str::from_utf8(&bytes)
+                        .map_err(|e| Error::SerializationError(Box::new(e)))?;
+                    monetizer::ToSql::to_sql(text)
+                }
+                _ => SQLParameter::literal(
+                    String::from_utf8(bytes).map_err(|e| Error::SerializationError(Box::new(e)))?,
+                ),
+            })
+        })
+        .collect()
+}
+
+fn query_error(error: MonetDBError) -> Error {
+    match error {
+        MonetDBError::InvalidParameters(_) => Error::SerializationError(Box::new(error)),
+        MonetDBError::StatementError(_, ref message)
+        | MonetDBError::ConnectionError(MapiError::OperationError(ref message)) => {
+            let error = MonetDBDatabaseError::parse(message);
+            Error::DatabaseError(error.kind(), Box::new(error))
+        }
+        _ => Error::DatabaseError(DatabaseErrorKind::Unknown, Box::new(error.to_string())),
+    }
+}
+
+/// An error reported by the server.
+#[derive(Debug)]
+pub struct MonetDBDatabaseError {
+    code: Option<String>,
+    message: String,
+}
+
+impl MonetDBDatabaseError {
+    /// Split `!42000!syntax error` in the SQLSTATE and the message.
+    fn parse(error: &str) -> MonetDBDatabaseError {
+        let (code, message) = split_error(error);
+        MonetDBDatabaseError {
+            code: code.map(String::from),
+            message: message.to_string(),
+        }
+    }
+
+    /// The SQLSTATE of the error, e.g. `42000` for a syntax error.
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    fn kind(&self) -> DatabaseErrorKind {
+        match self.code() {
+            // A concurrent transaction changed the same data
+            Some("40001") => DatabaseErrorKind::SerializationFailure,
+            Some("25006") => DatabaseErrorKind::ReadOnlyTransaction,
+            _ => match Constraint::violated(&self.message) {
+                Some(Constraint::Unique) => DatabaseErrorKind::UniqueViolation,
+                Some(Constraint::ForeignKey) => DatabaseErrorKind::ForeignKeyViolation,
+                Some(Constraint::NotNull) => DatabaseErrorKind::NotNullViolation,
+                Some(Constraint::Check) => DatabaseErrorKind::CheckViolation,
+                None => DatabaseErrorKind::Unknown,
+            },
+        }
+    }
+}
+
+impl DatabaseErrorInformation for MonetDBDatabaseError {
+    fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn details(&self) -> Option<&str> {
+        None
+    }
+
+    fn hint(&self) -> Option<&str> {
+        None
+    }
+
+    fn table_name(&self) -> Option<&str> {
+        None
+    }
+
+    fn column_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// The name in e.g. `PRIMARY KEY constraint 't.t_id_pkey' violated`.
+    fn constraint_name(&self) -> Option<&str> {
+        Constraint::violated(&self.message)?;
+        let (_, rest) = self.message.split_once("constraint '")?;
+        rest.split_once('\'').map(|(name, _)| name)
+    }
+
+    fn statement_position(&self) -> Option<i32> {
+        None
+    }
+}
+
+/// A diesel connection to MonetDB.
+pub struct MonetDBConnection {
+    connection: Connection,
+    transaction_state: AnsiTransactionManager,
+    instrumentation: Option<Box<dyn Instrumentation>>,
+}
+
+impl From<Connection> for MonetDBConnection {
+    /// Use an already established connection.
+    fn from(connection: Connection) -> MonetDBConnection {
+        MonetDBConnection {
+            connection,
+            transaction_state: AnsiTransactionManager::default(),
+            instrumentation: get_default_instrumentation(),
+        }
+    }
+}
+
+impl MonetDBConnection {
+    /// The underlying connection, e.g. to run a query with `monetizer`
+    /// parameters.
+    pub fn get_connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    /// The SQL of `source`, with `?` placeholders, and its bind parameters.
+    fn render<T: QueryFragment<MonetDB>>(source: &T) -> QueryResult<(String, Vec<SQLParameter>)> {
+        let mut builder = MonetDBQueryBuilder::new();
+        source.to_sql(&mut builder, &MonetDB)?;
+        let sql = builder.finish();
+        let mut binds = RawBytesBindCollector::new();
+        source.collect_binds(&mut binds, &mut (), &MonetDB)?;
+        Ok((sql, parameters(binds)?))
+    }
+
+    /// Run `f` on the query and its parameters, reporting it to the
+    /// instrumentation.
+    fn instrumented<T, R, F>(&mut self, source: &T, f: F) -> QueryResult<R>
+    where
+        T: QueryFragment<MonetDB>,
+        F: FnOnce(&mut Connection, &str, Vec<SQLParameter>) -> crate::connection::Result<R>,
+    {
+        let debug = diesel::debug_query::<MonetDB, _>(source);
+        self.instrumentation
+            .on_connection_event(InstrumentationEvent::start_query(&debug));
+        let result = Self::render(source).and_then(|(sql, parameters)| {
+            f(&mut self.connection, &sql, parameters).map_err(query_error)
+        });
+        self.instrumentation
+            .on_connection_event(InstrumentationEvent::finish_query(
+                &debug,
+                result.as_ref().err(),
+            ));
+        result
+    }
+}
+
+impl SimpleConnection for MonetDBConnection {
+    fn batch_execute(&mut self, query: &str) -> QueryResult<()> {
+        // The transaction manager starts transactions with BEGIN, which
+        // MonetDB does not know.
+        let query = if query == "BEGIN" {
+            "START TRANSACTION"
+        } else {
+            query
+        };
+        self.connection
+            .execute_script(query)
+            .map(|_| ())
+            .map_err(query_error)
+    }
+}
+
+impl ConnectionSealed for MonetDBConnection {}
+
+impl diesel::Connection for MonetDBConnection {
+    type Backend = MonetDB;
+    type TransactionManager = AnsiTransactionManager;
+
+    fn establish(database_url: &str) -> ConnectionResult<MonetDBConnection> {
+        let mut instrumentation = get_default_instrumentation();
+        instrumentation.on_connection_event(InstrumentationEvent::start_establish_connection(
+            database_url,
+        ));
+        let result = Connection::connect(database_url)
+            .map_err(|e| ConnectionError::BadConnection(e.to_string()));
+        instrumentation.on_connection_event(InstrumentationEvent::finish_establish_connection(
+            database_url,
+            result.as_ref().err(),
+        ));
+        Ok(MonetDBConnection {
+            instrumentation,
+            ..MonetDBConnection::from(result?)
+        })
+    }
+
+    fn execute_returning_count<T>(&mut self, source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<MonetDB> + QueryId,
+    {
+        let result = self.instrumented(source, |connection, sql, parameters| {
+            connection.execute(sql, parameters)
+        })?;
+        Ok(result.affected_rows.unwrap_or(0) as usize)
+    }
+
+    fn transaction_state(&mut self) -> &mut AnsiTransactionManager {
+        &mut self.transaction_state
+    }
+
+    fn instrumentation(&mut self) -> &mut dyn Instrumentation {
+        &mut self.instrumentation
+    }
+
+    fn set_instrumentation(&mut self, instrumentation: impl Instrumentation) {
+        self.instrumentation = Some(Box::new(instrumentation));
+    }
+
+    fn set_prepared_statement_cache_size(&mut self, _size: CacheSize) {
+        // Queries are sent as text, there are no prepared statements
+    }
+}
+
+impl LoadConnection<DefaultLoadingMode> for MonetDBConnection {
+    type Cursor<'conn, 'query> = MonetDBCursor;
+    type Row<'conn, 'query> = MonetDBRow;
+
+    fn load<'conn, 'query, T>(&'conn mut self, source: T) -> QueryResult<MonetDBCursor>
+    where
+        T: Query + QueryFragment<MonetDB> + QueryId + 'query,
+        MonetDB: QueryMetadata<T::SqlType>,
+    {
+        let (columns, rows) = self.instrumented(&source, |connection, sql, parameters| {
+            let rows = connection.query(sql, parameters)?;
+            let columns = Rc::new(rows.columns().to_vec());
+            Ok((
+                columns,
+                rows.collect::<crate::connection::Result<Vec<_>>>()?,
+            ))
+        })?;
+        Ok(MonetDBCursor {
+            columns,
+            rows: rows.into_iter(),
+        })
+    }
+}
+
+/// The rows of a result set. All rows are fetched when the query is run.
+pub struct MonetDBCursor {
+    columns: Rc<Vec<Column>>,
+    rows: std::vec::IntoIter<Vec<Option<String>>>,
+}
+
+impl Iterator for MonetDBCursor {
+    type Item = QueryResult<MonetDBRow>;
+
+    fn next(&mut self) -> Option<QueryResult<MonetDBRow>> {
+        let values = self.rows.next()?;
+        Some(Ok(MonetDBRow {
+            columns: self.columns.clone(),
+            values,
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rows.size_hint()
+    }
+}
+
+/// A row of a result set.
+pub struct MonetDBRow {
+    columns: Rc<Vec<Column>>,
+    values: Vec<Option<String>>,
+}
+
+impl RowSealed for MonetDBRow {}
+
+impl RowIndex<usize> for MonetDBRow {
+    fn idx(&self, idx: usize) -> Option<usize> {
+        (idx < self.values.len()).then_some(idx)
+    }
+}
+
+impl<'a> RowIndex<&'a str> for MonetDBRow {
+    fn idx(&self, name: &'a str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == name)
+    }
+}
+
+impl<'a> Row<'a, MonetDB> for MonetDBRow {
+    type Field<'f>
+        = MonetDBField<'f>
+    where
+        'a: 'f,
+        Self: 'f;
+    type InnerPartialRow = Self;
+
+    fn field_count(&self) -> usize {
+        self.values.len()
+    }
+
+    fn get<'b, I>(&'b self, idx: I) -> Option<MonetDBField<'b>>
+    where
+        'a: 'b,
+        Self: RowIndex<I>,
+    {
+        let index = self.idx(idx)?;
+        Some(MonetDBField { row: self, index })
+    }
+
+    fn partial_row(&self, range: Range<usize>) -> PartialRow<'_, Self> {
+        PartialRow::new(self, range)
+    }
+}
+
+/// A field of a `MonetDBRow`.
+pub struct MonetDBField<'a> {
+    row: &'a MonetDBRow,
+    index: usize,
+}
+
+impl<'a> Field<'a, MonetDB> for MonetDBField<'a> {
+    fn field_name(&self) -> Option<&str> {
+        Some(&self.row.columns[self.index].name)
+    }
+
+    fn value(&self) -> Option<MonetDBValue<'_>> {
+        let value = self.row.values[self.index].as_deref()?;
+        Some(MonetDBValue {
+            value,
+            sql_type: &self.row.columns[self.index].sql_type,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use diesel::query_builder::QueryBuilder;
+    use diesel::result::{DatabaseErrorKind, Error};
+    use diesel::sql_types;
+
+    use super::{MonetDB, MonetDBConnection, MonetDBQueryBuilder};
+    use crate::mock::{mock_connection, mock_server};
+
+    diesel::table! {
+        users (id) {
+            id -> Integer,
+            name -> Nullable<Text>,
+        }
+    }
+
+    const HEADER: &str = "% users,\tusers # table_name\n% id,\tname # name\n\
+                          % int,\tvarchar # type\n% 1,\t5 # length\n";
+
+    #[test]
+    fn query_builder() {
+        let query = users::table
+            .select(users::name)
+            .filter(users::id.eq(3))
+            .limit(5)
+            .offset(10);
+        assert_eq!(
+            diesel::debug_query::<MonetDB, _>(&query).to_string(),
+            "SELECT \"users\".\"name\" FROM \"users\" WHERE (\"users\".\"id\" = ?) \
+             LIMIT ? OFFSET ? -- binds: [3, 5, 10]"
+        );
+
+        let query = users::table.select(users::id).offset(2).into_boxed();
+        assert_eq!(
+            diesel::debug_query::<MonetDB, _>(&query).to_string(),
+            "SELECT \"users\".\"id\" FROM \"users\" OFFSET ? -- binds: [2]"
+        );
+
+        let mut builder = MonetDBQueryBuilder::new();
+        builder.push_identifier("a \"b\"").unwrap();
+        assert_eq!(builder.finish(), "\"a \"\"b\"\"\"");
+    }
+
+    #[test]
+    fn load() {
+        let (port, server) = mock_server(vec![format!(
+            "&1 0 2 2 2\n{}[ 1,\t\"ann\"\t]\n[ 2,\tNULL\t]\n",
+            HEADER
+        )]);
+        let mut conn = MonetDBConnection::from(mock_connection(port));
+        let rows: Vec<(i32, Option<String>)> = users::table
+            .filter(users::name.ne("it's"))
+            .order(users::id)
+            .limit(2)
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(rows, vec![(1, Some(String::from("ann"))), (2, None)]);
+        drop(conn);
+
+        let received = server.join().unwrap();
+        assert_eq!(
+            received[0],
+            "sSELECT \"users\".\"id\", \"users\".\"name\" FROM \"users\" \
+             WHERE (\"users\".\"name\" != 'it''s') ORDER BY \"users\".\"id\" LIMIT 2\n;"
+        );
+    }
+
+    #[test]
+    fn sql_query() {
+        #[derive(QueryableByName, Debug, PartialEq)]
+        struct Named {
+            #[diesel(sql_type = sql_types::Integer)]
+            id: i32,
+            #[diesel(sql_type = sql_types::Nullable<sql_types::Text>)]
+            name: Option<String>,
+        }
+
+        let (port, server) =
+            mock_server(vec![format!("&1 0 1 2 1\n{}[ 7,\t\"x\\\\y\"\t]\n", HEADER)]);
+        let mut conn = MonetDBConnection::from(mock_connection(port));
+        let rows: Vec<Named> = diesel::sql_query("SELECT id, name FROM users WHERE id > ?")
+            .bind::<sql_types::BigInt, _>(6)
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![Named {
+                id: 7,
+                name: Some(String::from("x\\y"))
+            }]
+        );
+        drop(conn);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[0], "sSELECT id, name FROM users WHERE id > 6\n;");
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn dates() {
+        use chrono::NaiveDate;
+
+        #[derive(QueryableByName, Debug, PartialEq)]
+        struct Dated {
+            #[diesel(sql_type = sql_types::Date)]
+            day: NaiveDate,
+        }
+
+        let (port, server) = mock_server(vec![String::from(
+            "&1 0 1 1 1\n% t # table_name\n% day # name\n% date # type\n% 10 # length\n\
+             [ 2024-02-29\t]\n",
+        )]);
+        let mut conn = MonetDBConnection::from(mock_connection(port));
+        let day = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+        let rows: Vec<Dated> = diesel::sql_query("SELECT day FROM t WHERE day >= ?")
+            .bind::<sql_types::Date, _>(day)
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(rows, vec![Dated { day }]);
+        drop(conn);
+
+        let received = server.join().unwrap();
+        assert_eq!(
+            received[0],
+            "sSELECT day FROM t WHERE day >= DATE '2024-02-29'\n;"
+        );
+    }
+
+    #[test]
+    fn execute_in_transaction() {
+        let (port, server) = mock_server(vec![
+            String::from("&4 f\n"),
+            String::from("&2 1 -1\n"),
+            String::from("&2 2 -1\n"),
+            String::from("&4 t\n"),
+        ]);
+        let mut conn = MonetDBConnection::from(mock_connection(port));
+        let affected = conn
+            .transaction(|conn| {
+                diesel::insert_into(users::table)
+                    .values((users::id.eq(1), users::name.eq("a\\b")))
+                    .execute(conn)?;
+                diesel::update(users::table)
+                    .set(users::name.eq(None::<String>))
+                    .execute(conn)
+            })
+            .unwrap();
+        assert_eq!(affected, 2);
+        drop(conn);
+
+        let received = server.join().unwrap();
+        assert_eq!(
+            received,
+            vec![
+                "sSTART TRANSACTION\n;",
+                "sINSERT INTO \"users\" (\"id\", \"name\") VALUES (1, E'a\\\\b')\n;",
+                "sUPDATE \"users\" SET \"name\" = NULL\n;",
+                "sCOMMIT\n;",
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let (port, server) = mock_server(vec![
+            String::from("!42000!syntax error\n"),
+            String::from(
+                "!40002!INSERT INTO: PRIMARY KEY constraint 'users.users_id_pkey' violated\n",
+            ),
+            String::from(
+                "!40001!COMMIT: transaction is aborted because of concurrency conflicts, \
+                 will ROLLBACK instead\n",
+            ),
+        ]);
+        let mut conn = MonetDBConnection::from(mock_connection(port));
+        let err = diesel::sql_query("SELEC 1").execute(&mut conn).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DatabaseError(DatabaseErrorKind::Unknown, _)
+        ));
+        assert_eq!(err.to_string(), "syntax error");
+
+        let err = diesel::insert_into(users::table)
+            .values(users::id.eq(1))
+            .execute(&mut conn)
+            .unwrap_err();
+        match err {
+            Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                assert_eq!(info.constraint_name(), Some("users.users_id_pkey"));
+                assert!(info.message().starts_with("INSERT INTO: PRIMARY KEY"));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let err = diesel::sql_query("COMMIT").execute(&mut conn).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DatabaseError(DatabaseErrorKind::SerializationFailure, _)
+        ));
+        drop(conn);
+        server.join().unwrap();
+    }
+}
@@ -19,14 +19,14 @@ mod tests {
         monetdb.execute("CREATE TABLE foo (i int)", vec![])?;
         let result = monetdb.execute("INSERT INTO foo VALUES (1), (2)", vec![])?;
 
-        assert_eq!(result, 2);
+        assert_eq!(result.affected_rows, Some(2));
         let result = monetdb.execute(
             "INSERT INTO foo VALUES ({}), ({})",
             vec![to_sqlparameter(1), to_sqlparameter(2)],
         )?;
-        assert_eq!(result, 2);
+        assert_eq!(result.affected_rows, Some(2));
         let result = monetdb.execute("SELECT * FROM foo", vec![])?;
-        assert_eq!(result, 0); // ! Not correct. The execute function needs work.
+        assert_eq!(result.tuple_count, Some(4));
 
         Ok(())
     }
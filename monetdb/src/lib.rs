@@ -6,6 +6,29 @@
 //
 
 pub mod connection;
+pub mod control;
+pub mod cursor;
 pub mod monetizer;
+pub mod response;
+pub mod rows;
+pub mod sql;
+
+#[cfg(feature = "bigdecimal")]
+mod bigdecimal_types;
+#[cfg(feature = "chrono")]
+mod chrono_types;
+#[cfg(any(feature = "rust_decimal", feature = "bigdecimal"))]
+mod decimal;
+#[cfg(feature = "diesel")]
+pub mod diesel_backend;
+#[cfg(feature = "rust_decimal")]
+mod rust_decimal_types;
+#[cfg(feature = "sqlx")]
+pub mod sqlx_backend;
+mod temporal;
+#[cfg(feature = "time")]
+mod time_types;
 
 mod integration_tests;
+#[cfg(test)]
+mod mock;
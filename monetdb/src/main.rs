@@ -4,16 +4,15 @@
 //
 // Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
 //
-extern crate monetdb;
-#[macro_use]
-extern crate log;
 extern crate env_logger;
+extern crate monetdb;
 
 //use log::LogLevel;
 
 // use monetdb_rust::mapi::*;
 use monetdb::connection::Connection;
 use monetdb::monetizer;
+use tracing::info;
 
 fn main() {
     env_logger::init().unwrap();
@@ -21,18 +20,17 @@ fn main() {
     let res = c
         .execute("CREATE TABLE IF NOT EXISTS foo (i int)", vec![])
         .unwrap();
-    info!("Result = {}", res);
+    info!("Result = {:?}", res);
     let res = c
         .execute("INSERT INTO foo VALUES (1), (2)", vec![])
         .unwrap();
-    info!("Result = {}", res);
-    let mut params: Vec<monetizer::SQLParameter> = vec![];
-    params.push(monetizer::to_sqlparameter(3));
-    params.push(monetizer::to_sqlparameter(4));
+    info!("Result = {:?}", res);
+    let params: Vec<monetizer::SQLParameter> =
+        vec![monetizer::to_sqlparameter(3), monetizer::to_sqlparameter(4)];
     let res = c
         .execute("INSERT INTO foo VALUES ({}), ({})", params)
         .unwrap();
-    info!("Result = {}", res);
+    info!("Result = {:?}", res);
     let res = c.execute("SELECT * from foo", vec![]).unwrap();
-    info!("Result = {}", res);
+    info!("Result = {:?}", res);
 }
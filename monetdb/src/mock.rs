@@ -0,0 +1,52 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! A scripted MAPI server for unit tests.
+use std::thread;
+
+use crate::connection::Connection;
+use mapi::mapi::{Endianness, MapiConnection, MapiConnectionParams};
+use mapi::mock::MockServer;
+use mapi::session::SessionOptions;
+
+/// Log in a single client, then answer its commands with `responses`, in
+/// order. Returns the commands the client sent once it disconnects.
+pub fn mock_server(responses: Vec<String>) -> (u16, thread::JoinHandle<Vec<String>>) {
+    // Without CLIENTINFO, so that the client sends no Xclientinfo
+    let (port, server) = MockServer {
+        challenge: String::from("salt:mserver:9:SHA512:LIT:SHA512:"),
+        responses,
+        ..MockServer::new(Endianness::Little)
+    }
+    .start();
+    let handle = thread::spawn(move || {
+        // Leave out the login
+        let received = server.join().unwrap().into_iter().skip(1);
+        received.map(|c| String::from_utf8(c).unwrap()).collect()
+    });
+
+    (port, handle)
+}
+
+/// Connect to a `mock_server`.
+pub fn mock_connection(port: u16) -> Connection {
+    let mut params = MapiConnectionParams::new(
+        "demo",
+        "monetdb",
+        Some("monetdb"),
+        None,
+        Some("127.0.0.1"),
+        Some(port),
+    );
+    params.session = SessionOptions {
+        timezone: None,
+        schema: None,
+        role: None,
+        client_info: None,
+        size_header: false,
+    };
+    Connection::from(MapiConnection::connect(params).unwrap())
+}
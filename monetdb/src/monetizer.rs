@@ -1,79 +1,200 @@
 use std::fmt;
+use std::result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::sql;
+use crate::temporal;
+use mapi::errors::MonetDBError;
+
+type Result<T> = result::Result<T, MonetDBError>;
+
+/// A value, rendered as a MonetDB literal, ready to be substituted in a
+/// query. Create one with `to_sqlparameter` from anything that implements
+/// `ToSql`.
 #[derive(Debug)]
 pub struct SQLParameter {
     value: String,
+    error: Option<String>,
 }
 
-impl From<&str> for SQLParameter {
-    fn from(input: &str) -> Self {
+impl SQLParameter {
+    /// Wrap an already rendered literal. The value is used verbatim, so it is
+    /// up to the caller to quote and escape it correctly.
+    pub fn literal(value: String) -> SQLParameter {
+        SQLParameter { value, error: None }
+    }
+
+    /// A value that cannot be represented in MonetDB, e.g. a decimal with
+    /// more than 38 digits. Binding it fails with `InvalidParameters`.
+    pub fn invalid(reason: String) -> SQLParameter {
         SQLParameter {
-            value: format!("'{}'", String::from(input).replace('\'', "''")),
+            value: String::new(),
+            error: Some(reason),
         }
     }
 }
 
-impl From<i8> for SQLParameter {
-    fn from(input: i8) -> Self {
-        SQLParameter {
-            value: int_to_string(input),
-        }
+/// Conversion of a Rust value to a MonetDB literal.
+pub trait ToSql {
+    fn to_sql(&self) -> SQLParameter;
+}
+
+impl<T: ToSql> From<T> for SQLParameter {
+    fn from(input: T) -> Self {
+        input.to_sql()
     }
 }
 
-impl From<u8> for SQLParameter {
-    fn from(input: u8) -> Self {
-        SQLParameter {
-            value: int_to_string(input),
-        }
+impl<T: ToSql + ?Sized> ToSql for &T {
+    fn to_sql(&self) -> SQLParameter {
+        (**self).to_sql()
     }
 }
 
-impl From<i16> for SQLParameter {
-    fn from(input: i16) -> Self {
-        SQLParameter {
-            value: int_to_string(input),
+impl<T: ToSql> ToSql for Option<T> {
+    fn to_sql(&self) -> SQLParameter {
+        match self {
+            Some(v) => v.to_sql(),
+            None => SQLParameter::literal(String::from("NULL")),
         }
     }
 }
 
-impl From<u16> for SQLParameter {
-    fn from(input: u16) -> Self {
-        SQLParameter {
-            value: int_to_string(input),
-        }
+impl ToSql for str {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(sql::quote_literal(self))
     }
 }
 
-impl From<i32> for SQLParameter {
-    fn from(input: i32) -> Self {
-        SQLParameter {
-            value: int_to_string(input),
-        }
+impl ToSql for String {
+    fn to_sql(&self) -> SQLParameter {
+        self.as_str().to_sql()
     }
 }
 
-impl From<u32> for SQLParameter {
-    fn from(input: u32) -> Self {
-        SQLParameter {
-            value: int_to_string(input),
+impl ToSql for char {
+    fn to_sql(&self) -> SQLParameter {
+        self.to_string().to_sql()
+    }
+}
+
+impl ToSql for bool {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(String::from(if *self { "true" } else { "false" }))
+    }
+}
+
+macro_rules! int_to_sql {
+    ($($t:ty),*) => {
+        $(
+            impl ToSql for $t {
+                fn to_sql(&self) -> SQLParameter {
+                    SQLParameter::literal(self.to_string())
+                }
+            }
+        )*
+    };
+}
+
+// i128 is a hugeint
+int_to_sql!(i8, u8, i16, u16, i32, u32, i64, u64, i128);
+
+/// MonetDB floats are always finite.
+fn not_finite<T: std::fmt::Display>(value: T) -> SQLParameter {
+    SQLParameter::invalid(format!("{} does not fit in a floating point column", value))
+}
+
+impl ToSql for f64 {
+    fn to_sql(&self) -> SQLParameter {
+        if self.is_finite() {
+            // The exponent makes this an approximate numeric (double) literal
+            // instead of an integer or decimal one.
+            SQLParameter::literal(format!("{:e}", self))
+        } else {
+            not_finite(self)
         }
     }
 }
 
-impl From<i64> for SQLParameter {
-    fn from(input: i64) -> Self {
-        SQLParameter {
-            value: int_to_string(input),
+impl ToSql for f32 {
+    fn to_sql(&self) -> SQLParameter {
+        if self.is_finite() {
+            SQLParameter::literal(format!("CAST({:e} AS REAL)", self))
+        } else {
+            not_finite(self)
         }
     }
 }
 
-impl From<u64> for SQLParameter {
-    fn from(input: u64) -> Self {
-        SQLParameter {
-            value: int_to_string(input),
+/// Binary data is sent as a blob.
+impl ToSql for [u8] {
+    fn to_sql(&self) -> SQLParameter {
+        let mut hex = String::with_capacity(2 * self.len() + 7);
+        hex.push_str("BLOB '");
+        for byte in self {
+            hex.push_str(&format!("{:02X}", byte));
         }
+        hex.push('\'');
+        SQLParameter::literal(hex)
+    }
+}
+
+impl ToSql for Vec<u8> {
+    fn to_sql(&self) -> SQLParameter {
+        self.as_slice().to_sql()
+    }
+}
+
+/// Points in time are sent as a `TIMESTAMPTZ` in UTC, with microsecond
+/// precision.
+impl ToSql for SystemTime {
+    fn to_sql(&self) -> SQLParameter {
+        let (seconds, nanos) = match self.duration_since(UNIX_EPOCH) {
+            Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+            Err(e) => {
+                // Before 1970: round towards the past
+                let d = e.duration();
+                let nanos = d.subsec_nanos();
+                if nanos == 0 {
+                    (-(d.as_secs() as i64), 0)
+                } else {
+                    (-(d.as_secs() as i64) - 1, 1_000_000_000 - nanos)
+                }
+            }
+        };
+        let (year, month, day) = temporal::civil_from_days(seconds.div_euclid(86400));
+        let time = seconds.rem_euclid(86400) as u32;
+        let time = temporal::TimeOfDay {
+            hour: time / 3600,
+            minute: time % 3600 / 60,
+            second: time % 60,
+            nanosecond: nanos,
+        };
+
+        SQLParameter::literal(format!(
+            "TIMESTAMPTZ '{} {}{}'",
+            temporal::format_date(year, month, day),
+            temporal::format_time(time),
+            temporal::format_offset(0)
+        ))
+    }
+}
+
+/// Durations are sent as a second interval.
+impl ToSql for Duration {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(temporal::format_seconds(
+            false,
+            self.as_secs(),
+            self.subsec_nanos(),
+        ))
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl ToSql for uuid::Uuid {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(format!("'{}'::uuid", self.hyphenated()))
     }
 }
 
@@ -87,31 +208,440 @@ pub fn to_sqlparameter<T: Into<SQLParameter>>(arg: T) -> SQLParameter {
     arg.into()
 }
 
-pub fn apply_parameters(query: &str, parameters: Vec<SQLParameter>) -> String {
+/// The type of a result column, as described by the result set header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlType {
+    /// The MonetDB type name, e.g. `int`, `varchar` or `timestamptz`.
+    pub name: String,
+    /// The number of digits for types that have a precision, e.g. 18 for a
+    /// `decimal(18,3)`.
+    pub digits: u32,
+    /// The number of fractional digits of a `decimal`.
+    pub scale: u32,
+}
+
+impl SqlType {
+    pub fn new(name: &str) -> SqlType {
+        SqlType::with_size(name, 0, 0)
+    }
+
+    pub fn with_size(name: &str, digits: u32, scale: u32) -> SqlType {
+        SqlType {
+            name: name.to_string(),
+            digits,
+            scale,
+        }
+    }
+}
+
+/// Conversion of a value in a result set to a Rust value.
+pub trait FromSql: Sized {
+    /// Convert a non NULL value, given as its (unquoted) text representation.
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self>;
+
+    /// Convert a possibly NULL value. Only `Option` accepts NULLs.
+    fn from_nullable_sql(value: Option<&str>, sql_type: &SqlType) -> Result<Self> {
+        match value {
+            Some(v) => Self::from_sql(v, sql_type),
+            None => Err(MonetDBError::ConversionError(format!(
+                "unexpected NULL in {} column",
+                sql_type.name
+            ))),
+        }
+    }
+}
+
+impl<T: FromSql> FromSql for Option<T> {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        Ok(Some(T::from_sql(value, sql_type)?))
+    }
+
+    fn from_nullable_sql(value: Option<&str>, sql_type: &SqlType) -> Result<Self> {
+        match value {
+            Some(v) => Self::from_sql(v, sql_type),
+            None => Ok(None),
+        }
+    }
+}
+
+pub(crate) fn conversion_error(value: &str, sql_type: &SqlType, target: &str) -> MonetDBError {
+    MonetDBError::ConversionError(format!(
+        "cannot convert {} value {:?} to {}",
+        sql_type.name, value, target
+    ))
+}
+
+impl FromSql for String {
+    fn from_sql(value: &str, _sql_type: &SqlType) -> Result<Self> {
+        Ok(value.to_string())
+    }
+}
+
+impl FromSql for bool {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        match value {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(conversion_error(value, sql_type, "bool")),
+        }
+    }
+}
+
+macro_rules! parse_from_sql {
+    ($($t:ty),*) => {
+        $(
+            impl FromSql for $t {
+                fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+                    value
+                        .parse::<$t>()
+                        .map_err(|_| conversion_error(value, sql_type, stringify!($t)))
+                }
+            }
+        )*
+    };
+}
+
+parse_from_sql!(i8, u8, i16, u16, i32, u32, i64, u64, i128, f32, f64);
+
+/// Blobs are sent as hexadecimal strings.
+impl FromSql for Vec<u8> {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        if !value.len().is_multiple_of(2) {
+            return Err(conversion_error(value, sql_type, "bytes"));
+        }
+        (0..value.len())
+            .step_by(2)
+            .map(|i| {
+                value
+                    .get(i..i + 2)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| conversion_error(value, sql_type, "bytes"))
+            })
+            .collect()
+    }
+}
+
+/// Only values with a time zone (`timestamptz`) identify a point in time.
+impl FromSql for SystemTime {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let ts = temporal::parse_timestamp(value)?;
+        let offset = ts
+            .offset
+            .ok_or_else(|| conversion_error(value, sql_type, "SystemTime"))?;
+        let (year, month, day) = ts.date;
+        let seconds = temporal::days_from_civil(year, month, day) * 86400
+            + (ts.time.hour * 3600 + ts.time.minute * 60 + ts.time.second) as i64
+            - offset as i64;
+        let since_epoch = Duration::from_secs(seconds.unsigned_abs());
+        let time = if seconds >= 0 {
+            UNIX_EPOCH + since_epoch
+        } else {
+            UNIX_EPOCH - since_epoch
+        };
+
+        Ok(time + Duration::from_nanos(ts.time.nanosecond as u64))
+    }
+}
+
+/// Accepts `sec_interval` and `day_interval` values.
+impl FromSql for Duration {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let (negative, seconds, nanos) = temporal::parse_seconds(value)?;
+        if negative {
+            return Err(conversion_error(value, sql_type, "Duration"));
+        }
+        if sql_type.name == "day_interval" {
+            Ok(Duration::from_secs(seconds * 86400))
+        } else {
+            Ok(Duration::new(seconds, nanos))
+        }
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl FromSql for uuid::Uuid {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        uuid::Uuid::parse_str(value).map_err(|_| conversion_error(value, sql_type, "Uuid"))
+    }
+}
+
+/// Substitute the placeholders in `query` with `parameters`, in order.
+///
+/// Placeholders can be written as `?`, `%s` or `{}`, which take the next
+/// parameter, or as `$1`, `$2`, ..., which refer to a parameter by position.
+/// The two kinds cannot be mixed. Placeholders inside string literals, quoted
+/// identifiers and comments are left alone.
+///
+/// If `parameters` is empty the query is returned unchanged, so that e.g.
+/// `PREPARE` statements and function bodies can still use `?`.
+pub fn apply_parameters(query: &str, parameters: Vec<SQLParameter>) -> Result<String> {
+    if parameters.is_empty() {
+        return Ok(query.to_string());
+    }
+
+    let placeholders = find_placeholders(query)?;
+    let mut used = vec![false; parameters.len()];
+    let mut sequential = 0;
+    let mut indexed = false;
+    let mut bound = Vec::with_capacity(placeholders.len());
+
+    for p in placeholders.iter() {
+        let index = match p.kind {
+            Placeholder::Next => {
+                sequential += 1;
+                sequential - 1
+            }
+            Placeholder::Index(i) => {
+                indexed = true;
+                i - 1
+            }
+            Placeholder::Name(n) => {
+                return Err(MonetDBError::InvalidParameters(format!(
+                    "named placeholder :{} used with positional parameters",
+                    n
+                )))
+            }
+        };
+        if sequential > 0 && indexed {
+            return Err(MonetDBError::InvalidParameters(
+                "cannot mix numbered ($1) and sequential placeholders".to_string(),
+            ));
+        }
+        let parameter = parameters.get(index).ok_or_else(|| {
+            MonetDBError::InvalidParameters(format!(
+                "query refers to parameter {} but only {} were given",
+                index + 1,
+                parameters.len()
+            ))
+        })?;
+        used[index] = true;
+        bound.push(parameter);
+    }
+
+    if let Some(unused) = used.iter().position(|u| !u) {
+        return Err(MonetDBError::InvalidParameters(format!(
+            "{} parameters were given but parameter {} is not used by the query",
+            parameters.len(),
+            unused + 1
+        )));
+    }
+
+    substitute(query, &placeholders, &bound)
+}
+
+/// Substitute the `:name` placeholders in `query` with the parameter of that
+/// name. Like `apply_parameters`, placeholders inside string literals, quoted
+/// identifiers and comments are left alone, and every parameter has to be
+/// used. Without parameters the query is returned unchanged.
+pub fn apply_named_parameters(query: &str, parameters: &[(&str, SQLParameter)]) -> Result<String> {
     if parameters.is_empty() {
-        return query.to_string();
+        return Ok(query.to_string());
     }
 
-    let split = query.split_inclusive("{}").collect::<Vec<&str>>();
+    let placeholders = find_placeholders(query)?;
+    let mut bound = Vec::with_capacity(placeholders.len());
 
-    let mut result: Vec<String> = Vec::new();
+    for p in placeholders.iter() {
+        let name = match p.kind {
+            Placeholder::Name(n) => n,
+            _ => {
+                return Err(MonetDBError::InvalidParameters(format!(
+                    "positional placeholder {} used with named parameters",
+                    &query[p.start..p.end]
+                )))
+            }
+        };
+        let parameter = parameters
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, p)| p)
+            .ok_or_else(|| {
+                MonetDBError::InvalidParameters(format!("no value given for :{}", name))
+            })?;
+        bound.push(parameter);
+    }
+
+    if let Some((unused, _)) = parameters.iter().find(|(n, _)| {
+        !placeholders
+            .iter()
+            .any(|p| matches!(p.kind, Placeholder::Name(name) if name == *n))
+    }) {
+        return Err(MonetDBError::InvalidParameters(format!(
+            "parameter :{} is not used by the query",
+            unused
+        )));
+    }
+
+    substitute(query, &placeholders, &bound)
+}
+
+#[derive(Debug, PartialEq)]
+enum Placeholder<'a> {
+    Next,
+    Index(usize),
+    Name(&'a str),
+}
 
-    for (i, s) in split.iter().enumerate() {
-        if s.contains("{}") {
-            let temp = s.replace("{}", &format!("{}", parameters[i]));
-            result.push(temp);
+#[derive(Debug, PartialEq)]
+struct Location<'a> {
+    start: usize,
+    end: usize,
+    kind: Placeholder<'a>,
+}
+
+fn substitute(query: &str, placeholders: &[Location], values: &[&SQLParameter]) -> Result<String> {
+    let mut result = String::with_capacity(query.len());
+    let mut last = 0;
+    for (p, value) in placeholders.iter().zip(values.iter()) {
+        if let Some(reason) = &value.error {
+            return Err(MonetDBError::InvalidParameters(reason.clone()));
+        }
+        result.push_str(&query[last..p.start]);
+        if value.value.starts_with('-') {
+            // x-? must not become the comment x--1
+            result.push('(');
+            result.push_str(&value.value);
+            result.push(')');
         } else {
-            result.push(s.to_string());
+            result.push_str(&value.value);
         }
+        last = p.end;
     }
+    result.push_str(&query[last..]);
 
-    let out = result.iter().map(|x| x.to_owned()).collect::<String>();
+    Ok(result)
+}
 
-    out
+fn is_identifier_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
 }
 
-fn int_to_string<T: fmt::Display>(arg: T) -> String {
-    arg.to_string()
+/// Scan `query` for placeholders, skipping over literals and comments.
+fn find_placeholders(query: &str) -> Result<Vec<Location<'_>>> {
+    let bytes = query.as_bytes();
+    let mut found = vec![];
+    let mut i = 0;
+
+    // `%s`, `{}` and `:name` only count as separate tokens, not e.g. in
+    // `a%size` or the slice `x[1:n]` of a Python function body
+    let starts_token = |i: usize| i == 0 || !is_identifier_byte(bytes[i - 1]);
+    let ends_token = |end: usize| bytes.get(end).is_none_or(|b| !is_identifier_byte(*b));
+
+    while i < bytes.len() {
+        let next = bytes.get(i + 1).copied();
+        match (bytes[i], next) {
+            (b'\'', _) => {
+                // Raw strings (r'...') do not treat backslashes as escapes
+                let raw = i > 0
+                    && matches!(bytes[i - 1], b'r' | b'R')
+                    && (i < 2 || !is_identifier_byte(bytes[i - 2]));
+                i = skip_quoted(bytes, i, !raw)?;
+            }
+            (b'"', _) => i = skip_quoted(bytes, i, false)?,
+            (b'-', Some(b'-')) => {
+                i = match query[i..].find('\n') {
+                    Some(end) => i + end + 1,
+                    None => bytes.len(),
+                }
+            }
+            (b'/', Some(b'*')) => {
+                i = match query[i + 2..].find("*/") {
+                    Some(end) => i + 2 + end + 2,
+                    None => {
+                        return Err(MonetDBError::InvalidParameters(
+                            "unterminated comment".to_string(),
+                        ))
+                    }
+                }
+            }
+            (b'?', _) => {
+                found.push(Location {
+                    start: i,
+                    end: i + 1,
+                    kind: Placeholder::Next,
+                });
+                i += 1;
+            }
+            (b'%', Some(b's')) | (b'{', Some(b'}')) if starts_token(i) && ends_token(i + 2) => {
+                found.push(Location {
+                    start: i,
+                    end: i + 2,
+                    kind: Placeholder::Next,
+                });
+                i += 2;
+            }
+            (b'$', Some(b'0'..=b'9')) => {
+                let end = i
+                    + 1
+                    + bytes[i + 1..]
+                        .iter()
+                        .take_while(|b| b.is_ascii_digit())
+                        .count();
+                let index = query[i + 1..end].parse::<usize>().unwrap_or(0);
+                if index == 0 {
+                    return Err(MonetDBError::InvalidParameters(format!(
+                        "invalid placeholder {}",
+                        &query[i..end]
+                    )));
+                }
+                found.push(Location {
+                    start: i,
+                    end,
+                    kind: Placeholder::Index(index),
+                });
+                i = end;
+            }
+            // A cast, e.g. '...'::uuid
+            (b':', Some(b':')) => i += 2,
+            (b':', Some(b)) if (b.is_ascii_alphabetic() || b == b'_') && starts_token(i) => {
+                let end = i
+                    + 1
+                    + bytes[i + 1..]
+                        .iter()
+                        .take_while(|b| is_identifier_byte(**b))
+                        .count();
+                found.push(Location {
+                    start: i,
+                    end,
+                    kind: Placeholder::Name(&query[i + 1..end]),
+                });
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+
+    Ok(found)
+}
+
+/// Return the position just after the quoted string or identifier that starts
+/// at `start`. A doubled quote character is an escaped quote.
+fn skip_quoted(bytes: &[u8], start: usize, backslash_escapes: bool) -> Result<usize> {
+    let quote = bytes[start];
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if backslash_escapes && bytes[i] == b'\\' {
+            i += 2;
+        } else if bytes[i] == quote {
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return Ok(i + 1);
+            }
+        } else {
+            i += 1;
+        }
+    }
+
+    Err(MonetDBError::InvalidParameters(format!(
+        "unterminated {} starting at position {}",
+        if quote == b'"' {
+            "quoted identifier"
+        } else {
+            "string literal"
+        },
+        start
+    )))
 }
 
 #[cfg(test)]
@@ -146,11 +676,13 @@ mod tests {
         let q1 = apply_parameters(
             "SELECT * FROM foo WHERE bar = {}",
             vec![to_sqlparameter("foobar")],
-        );
+        )
+        .unwrap();
         let q2 = apply_parameters(
             "SELECT * FROM foo WHERE bar = {} AND baz = {}",
             vec![to_sqlparameter("foobar"), to_sqlparameter("something cool")],
-        );
+        )
+        .unwrap();
 
         assert_eq!(q1, String::from("SELECT * FROM foo WHERE bar = 'foobar'"));
         assert_eq!(
@@ -164,19 +696,23 @@ mod tests {
         let q1 = apply_parameters(
             "SELECT * FROM foo WHERE bar = {}",
             vec![to_sqlparameter(10)],
-        );
+        )
+        .unwrap();
         let q2 = apply_parameters(
             "SELECT * FROM foo WHERE bar = {} AND baz = {}",
             vec![to_sqlparameter(1), to_sqlparameter(2)],
-        );
+        )
+        .unwrap();
         let q3 = apply_parameters(
             "SELECT * FROM foo WHERE bar = {} AND baz = {}",
             vec![to_sqlparameter(1), to_sqlparameter("foo")],
-        );
+        )
+        .unwrap();
         let q4 = apply_parameters(
             "INSERT INTO foo VALUES ({}), ({})",
             vec![to_sqlparameter(1), to_sqlparameter(2)],
-        );
+        )
+        .unwrap();
 
         assert_eq!(q1, String::from("SELECT * FROM foo WHERE bar = 10"));
         assert_eq!(
@@ -189,4 +725,193 @@ mod tests {
         );
         assert_eq!(q4, String::from("INSERT INTO foo VALUES (1), (2)"));
     }
+
+    #[test]
+    fn placeholder_styles() {
+        for query in [
+            "SELECT * FROM foo WHERE a = ? AND b = ?",
+            "SELECT * FROM foo WHERE a = %s AND b = %s",
+            "SELECT * FROM foo WHERE a = {} AND b = {}",
+            "SELECT * FROM foo WHERE a = $1 AND b = $2",
+        ] {
+            let q =
+                apply_parameters(query, vec![to_sqlparameter(1), to_sqlparameter("x")]).unwrap();
+            assert_eq!(q, "SELECT * FROM foo WHERE a = 1 AND b = 'x'");
+        }
+
+        let q = apply_parameters(
+            "SELECT $2, $1, $2",
+            vec![to_sqlparameter(1), to_sqlparameter(2)],
+        )
+        .unwrap();
+        assert_eq!(q, "SELECT 2, 1, 2");
+    }
+
+    #[test]
+    fn named_placeholders() {
+        let q = apply_named_parameters(
+            "SELECT * FROM foo WHERE a = :a AND b = :b_2 OR c = :a",
+            &[("a", to_sqlparameter(1)), ("b_2", to_sqlparameter("x"))],
+        )
+        .unwrap();
+        assert_eq!(q, "SELECT * FROM foo WHERE a = 1 AND b = 'x' OR c = 1");
+
+        let q = apply_named_parameters("SELECT '6a1c...'::uuid, :a", &[("a", to_sqlparameter(1))])
+            .unwrap();
+        assert_eq!(q, "SELECT '6a1c...'::uuid, 1");
+        // A cast is not a placeholder
+        assert!(apply_named_parameters(
+            "SELECT '6a1c...'::uuid, :a",
+            &[("a", to_sqlparameter(1)), ("uuid", to_sqlparameter(2))],
+        )
+        .is_err());
+
+        assert!(apply_named_parameters("SELECT :a, :b", &[("a", to_sqlparameter(1))]).is_err());
+        assert!(apply_named_parameters("SELECT :a, ?", &[("a", to_sqlparameter(1))]).is_err());
+        let unused = [("a", to_sqlparameter(1)), ("b", to_sqlparameter(2))];
+        assert!(apply_named_parameters("SELECT :a", &unused).is_err());
+    }
+
+    #[test]
+    fn placeholders_in_literals_and_comments_are_ignored() {
+        let q = apply_parameters(
+            "SELECT '?', 'it''s {}', 'back\\'slash ?', r'raw\\', \"col?\", ? -- what?\n/* $1 :x */ FROM foo",
+            vec![to_sqlparameter(1)],
+        )
+        .unwrap();
+        assert_eq!(
+            q,
+            "SELECT '?', 'it''s {}', 'back\\'slash ?', r'raw\\', \"col?\", 1 -- what?\n/* $1 :x */ FROM foo"
+        );
+    }
+
+    #[test]
+    fn arity_is_checked() {
+        let too_few = apply_parameters("SELECT ?, ?", vec![to_sqlparameter(1)]);
+        let too_many = apply_parameters("SELECT ?", vec![to_sqlparameter(1), to_sqlparameter(2)]);
+        let out_of_range = apply_parameters("SELECT $2", vec![to_sqlparameter(1)]);
+        let zero = apply_parameters("SELECT $0", vec![to_sqlparameter(1)]);
+        let mixed = apply_parameters("SELECT $1, ?", vec![to_sqlparameter(1)]);
+        let named = apply_parameters("SELECT :a", vec![to_sqlparameter(1)]);
+        let unterminated = apply_parameters("SELECT 'foo, ?", vec![to_sqlparameter(1)]);
+
+        for r in [
+            too_few,
+            too_many,
+            out_of_range,
+            zero,
+            mixed,
+            named,
+            unterminated,
+        ] {
+            match r {
+                Err(MonetDBError::InvalidParameters(_)) => (),
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+
+        // Only whole tokens are placeholders
+        let q = apply_parameters("SELECT a%size, b{}c, ?", vec![to_sqlparameter(1)]).unwrap();
+        assert_eq!(q, "SELECT a%size, b{}c, 1");
+        let q = apply_named_parameters("SELECT a%size, x[1:n], :n", &[("n", to_sqlparameter(2))])
+            .unwrap();
+        assert_eq!(q, "SELECT a%size, x[1:n], 2");
+
+        // Without parameters the query is left alone
+        assert_eq!(
+            apply_parameters("PREPARE SELECT ?", vec![]).unwrap(),
+            "PREPARE SELECT ?"
+        );
+        let udf = "CREATE FUNCTION f(x INT, n INT) RETURNS INT LANGUAGE PYTHON { return x[1:n] }";
+        assert_eq!(apply_named_parameters(udf, &[]).unwrap(), udf);
+    }
+
+    #[test]
+    fn negative_values_stay_values() {
+        let q = apply_parameters(
+            "SELECT 10-? WHERE a = b-? AND tenant = 5",
+            vec![to_sqlparameter(-1), to_sqlparameter(-0.5)],
+        )
+        .unwrap();
+        assert_eq!(q, "SELECT 10-(-1) WHERE a = b-(-5e-1) AND tenant = 5");
+    }
+
+    #[test]
+    fn backslashes_are_escaped() {
+        assert_eq!(to_sqlparameter("a\\b").to_string(), r"E'a\\b'");
+        assert_eq!(to_sqlparameter("\\'").to_string(), r"E'\\'''");
+        assert_eq!(to_sqlparameter(String::from("it's")).to_string(), "'it''s'");
+    }
+
+    #[test]
+    fn scalars_to_sql() {
+        assert_eq!(to_sqlparameter(true).to_string(), "true");
+        assert_eq!(to_sqlparameter(false).to_string(), "false");
+        assert_eq!(
+            to_sqlparameter(i128::MIN).to_string(),
+            "-170141183460469231731687303715884105728"
+        );
+        assert_eq!(
+            to_sqlparameter(u64::MAX).to_string(),
+            "18446744073709551615"
+        );
+        assert_eq!(to_sqlparameter('x').to_string(), "'x'");
+        assert_eq!(to_sqlparameter(Some(3)).to_string(), "3");
+        assert_eq!(to_sqlparameter(None::<&str>).to_string(), "NULL");
+    }
+
+    #[test]
+    fn floats_to_sql() {
+        assert_eq!(to_sqlparameter(1.0).to_string(), "1e0");
+        assert_eq!(to_sqlparameter(-0.015625).to_string(), "-1.5625e-2");
+        assert_eq!(to_sqlparameter(1e300).to_string(), "1e300");
+        assert_eq!(to_sqlparameter(2.5f32).to_string(), "CAST(2.5e0 AS REAL)");
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let error = apply_parameters("SELECT ?", vec![to_sqlparameter(value)]).unwrap_err();
+            assert!(matches!(error, MonetDBError::InvalidParameters(_)));
+        }
+        assert!(apply_parameters("SELECT ?", vec![to_sqlparameter(f32::NAN)]).is_err());
+    }
+
+    #[test]
+    fn blobs_to_sql() {
+        assert_eq!(
+            to_sqlparameter(&[0u8, 15, 255][..]).to_string(),
+            "BLOB '000FFF'"
+        );
+        assert_eq!(to_sqlparameter(vec![0xabu8]).to_string(), "BLOB 'AB'");
+        assert_eq!(to_sqlparameter(Vec::<u8>::new()).to_string(), "BLOB ''");
+    }
+
+    #[test]
+    fn times_to_sql() {
+        let t = UNIX_EPOCH + Duration::new(1_656_000_000, 123_456_789);
+        assert_eq!(
+            to_sqlparameter(t).to_string(),
+            "TIMESTAMPTZ '2022-06-23 16:00:00.123456+00:00'"
+        );
+        let t = UNIX_EPOCH - Duration::new(86_400 * 365, 500_000);
+        assert_eq!(
+            to_sqlparameter(t).to_string(),
+            "TIMESTAMPTZ '1968-12-31 23:59:59.999500+00:00'"
+        );
+        assert_eq!(
+            to_sqlparameter(UNIX_EPOCH + Duration::from_secs(951_782_400)).to_string(),
+            "TIMESTAMPTZ '2000-02-29 00:00:00.000000+00:00'"
+        );
+        assert_eq!(
+            to_sqlparameter(Duration::from_millis(90_500)).to_string(),
+            "INTERVAL '90.500000' SECOND"
+        );
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn uuids_to_sql() {
+        let u = uuid::Uuid::from_u128(0x6a1c2f6c_3c7e_4b5f_9a0e_0123456789ab);
+        assert_eq!(
+            to_sqlparameter(u).to_string(),
+            "'6a1c2f6c-3c7e-4b5f-9a0e-0123456789ab'::uuid"
+        );
+    }
 }
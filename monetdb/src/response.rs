@@ -0,0 +1,656 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Parsing of the responses MonetDB sends for SQL commands.
+//!
+//! Every statement gets a response that starts with a `&` line. A command
+//! with several statements gets all of them concatenated, and stops at the
+//! first statement that fails, which is reported with `!` lines:
+//!
+//! ```text
+//! &3 12 4                      schema change (e.g. CREATE TABLE)
+//! &2 1 -1 7 40 12 20           update (e.g. INSERT), with the row count
+//! &4 f                         start of a transaction, auto commit off
+//! &1 0 2 1 2 8 60 15 25        result set: id, rows, columns, rows sent
+//! % sys.t # table_name
+//! % a # name
+//! % int # type
+//! % 1 # length
+//! % 32 0 # typesizes
+//! [ 1\t]
+//! [ 2\t]
+//! !42000!syntax error ...      error
+//! ```
+use std::borrow::Cow;
+use std::result;
+use std::time::Duration;
+
+use crate::monetizer::{FromSql, SqlType};
+use mapi::errors::{MapiError, MonetDBError};
+
+type Result<T> = result::Result<T, MonetDBError>;
+
+/// What kind of statement a `QueryResult` belongs to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResultKind {
+    /// A statement that returns rows, e.g. `SELECT`.
+    Table,
+    /// A statement that changes rows, e.g. `INSERT` or `DELETE`.
+    Update,
+    /// A statement that changes the schema, e.g. `CREATE TABLE`.
+    Schema,
+    /// A statement that starts or ends a transaction.
+    Transaction,
+    /// A `PREPARE` statement.
+    Prepare,
+}
+
+/// A column of a result set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub table: String,
+    pub sql_type: SqlType,
+}
+
+/// The outcome of a single statement.
+///
+/// Servers before Jun2020 do not send the query id and the timings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResult {
+    pub kind: ResultKind,
+    /// The id the server uses for a result set or prepared statement.
+    pub result_id: Option<u64>,
+    /// The number of rows changed by an update.
+    pub affected_rows: Option<u64>,
+    /// The value generated for an auto increment column by an `INSERT`.
+    pub last_id: Option<i64>,
+    /// The server's id of the query, as in `sys.queue`.
+    pub query_id: Option<u64>,
+    /// The total number of rows in a result set.
+    pub tuple_count: Option<u64>,
+    /// Time spent parsing and optimizing the SQL.
+    pub sql_parse_time: Option<Duration>,
+    /// Time spent optimizing the MAL plan.
+    pub optimize_time: Option<Duration>,
+    /// Time spent executing the query.
+    pub exec_time: Option<Duration>,
+    /// Whether auto commit is on after a transaction statement.
+    pub auto_commit: Option<bool>,
+    /// The columns of a result set.
+    pub columns: Vec<Column>,
+    /// The rows of a result set that were sent along with the response. NULL
+    /// values are `None`.
+    pub rows: Vec<Vec<Option<String>>>,
+}
+
+impl QueryResult {
+    fn new(kind: ResultKind) -> QueryResult {
+        QueryResult {
+            kind,
+            result_id: None,
+            affected_rows: None,
+            last_id: None,
+            query_id: None,
+            tuple_count: None,
+            sql_parse_time: None,
+            optimize_time: None,
+            exec_time: None,
+            auto_commit: None,
+            columns: vec![],
+            rows: vec![],
+        }
+    }
+}
+
+/// Split an error of the server in its SQLSTATE, if it has one, and the
+/// message: `42000` and `syntax error` for `!42000!syntax error`.
+pub fn split_error(error: &str) -> (Option<&str>, &str) {
+    let error = error.trim_end().trim_start_matches('!');
+    match error.split_once('!') {
+        Some((code, message))
+            if code.len() == 5 && code.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            (Some(code), message)
+        }
+        _ => (None, error),
+    }
+}
+
+/// A constraint the server reports a violation of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Constraint {
+    Unique,
+    ForeignKey,
+    NotNull,
+    Check,
+}
+
+impl Constraint {
+    /// The constraint an error message says was violated. MonetDB reports
+    /// all of them as `40002`, so only the message tells which it was.
+    pub fn violated(message: &str) -> Option<Constraint> {
+        if message.contains("PRIMARY KEY constraint") || message.contains("UNIQUE constraint") {
+            Some(Constraint::Unique)
+        } else if message.contains("FOREIGN KEY constraint") {
+            Some(Constraint::ForeignKey)
+        } else if message.contains("NOT NULL constraint") {
+            Some(Constraint::NotNull)
+        } else if message.contains("CHECK constraint") {
+            Some(Constraint::Check)
+        } else {
+            None
+        }
+    }
+}
+
+fn unknown_response(what: &str, line: &str) -> MonetDBError {
+    MonetDBError::ConnectionError(MapiError::UnknownServerResponse(format!(
+        "unexpected {} in response: {:?}",
+        what, line
+    )))
+}
+
+/// Split a response into the results of the individual statements. The
+/// first error is returned as `StatementError`, with the index of the
+/// statement that failed.
+pub fn parse_response(response: &str) -> Result<Vec<QueryResult>> {
+    let mut results: Vec<QueryResult> = vec![];
+    let mut lines = response.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(error) = line.strip_prefix('!') {
+            let mut message = error.to_string();
+            while let Some(more) = lines.peek().and_then(|l| l.strip_prefix('!')) {
+                message.push('\n');
+                message.push_str(more);
+                lines.next();
+            }
+            return Err(MonetDBError::StatementError(results.len(), message));
+        }
+
+        let header = line
+            .strip_prefix('&')
+            .ok_or_else(|| unknown_response("line", line))?;
+        let mut fields = header.split_whitespace();
+        let result = match fields.next() {
+            // id rows columns rows-sent query-id exec mal-optimize sql-parse
+            Some(kind @ ("1" | "5")) => {
+                let fields: Vec<&str> = fields.collect();
+                QueryResult {
+                    result_id: Some(number(fields.first().copied(), line)?),
+                    tuple_count: Some(number(fields.get(1).copied(), line)?),
+                    query_id: optional(fields.get(4), line)?,
+                    exec_time: micros(fields.get(5), line)?,
+                    optimize_time: micros(fields.get(6), line)?,
+                    sql_parse_time: micros(fields.get(7), line)?,
+                    ..QueryResult::new(if kind == "1" {
+                        ResultKind::Table
+                    } else {
+                        ResultKind::Prepare
+                    })
+                }
+            }
+            // rows last-id query-id exec mal-optimize sql-parse
+            Some("2") => {
+                let fields: Vec<&str> = fields.collect();
+                QueryResult {
+                    affected_rows: Some(number(fields.first().copied(), line)?),
+                    last_id: optional(fields.get(1), line)?,
+                    query_id: optional(fields.get(2), line)?,
+                    exec_time: micros(fields.get(3), line)?,
+                    optimize_time: micros(fields.get(4), line)?,
+                    sql_parse_time: micros(fields.get(5), line)?,
+                    ..QueryResult::new(ResultKind::Update)
+                }
+            }
+            // exec mal-optimize
+            Some("3") => {
+                let fields: Vec<&str> = fields.collect();
+                QueryResult {
+                    exec_time: micros(fields.first(), line)?,
+                    optimize_time: micros(fields.get(1), line)?,
+                    ..QueryResult::new(ResultKind::Schema)
+                }
+            }
+            Some("4") => QueryResult {
+                auto_commit: Some(match fields.next() {
+                    Some("t") => true,
+                    Some("f") => false,
+                    _ => return Err(unknown_response("transaction state", line)),
+                }),
+                ..QueryResult::new(ResultKind::Transaction)
+            },
+            _ => return Err(unknown_response("response type", line)),
+        };
+        results.push(result);
+
+        if let Some(result) = results.last_mut() {
+            if result.kind == ResultKind::Table || result.kind == ResultKind::Prepare {
+                let column_count: usize = number(header.split_whitespace().nth(3), line)?;
+                let mut headers = vec![];
+                while let Some(l) = lines.peek().and_then(|l| l.strip_prefix('%')) {
+                    headers.push(l);
+                    lines.next();
+                }
+                result.columns = parse_columns(&headers, column_count)?;
+                while let Some(l) = lines.peek().filter(|l| l.starts_with('[')) {
+                    result.rows.push(parse_tuple(l, column_count)?);
+                    lines.next();
+                }
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+/// Parse the response to `Xexport`: a `&6` line followed by the rows.
+pub fn parse_block(response: &str, column_count: usize) -> Result<Vec<Vec<Option<String>>>> {
+    response[block_start(response)?..]
+        .lines()
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| parse_tuple(l, column_count))
+        .collect()
+}
+
+/// The offset of the first row in the response to `Xexport`.
+pub fn block_start(response: &str) -> Result<usize> {
+    match response.find('\n') {
+        Some(end) if response.starts_with("&6 ") => Ok(end + 1),
+        _ if response.starts_with('!') => Err(MonetDBError::ConnectionError(
+            MapiError::OperationError(response.trim_end().to_string()),
+        )),
+        _ => Err(unknown_response("block", response)),
+    }
+}
+
+/// Split the response to a query into the result and the text of the rows
+/// that were sent along with it.
+pub fn split_rows(response: &str) -> Result<(QueryResult, &str)> {
+    let start = if response.starts_with('[') {
+        0
+    } else {
+        response.find("\n[").map_or(response.len(), |i| i + 1)
+    };
+    let result = parse_response(&response[..start])?
+        .pop()
+        .ok_or_else(|| unknown_response("response", "no result"))?;
+    Ok((result, &response[start..]))
+}
+
+fn number<T: std::str::FromStr>(field: Option<&str>, line: &str) -> Result<T> {
+    field
+        .and_then(|f| f.parse().ok())
+        .ok_or_else(|| unknown_response("header", line))
+}
+
+/// A field that may be missing, with -1 meaning "not available".
+fn optional<T: TryFrom<i64>>(field: Option<&&str>, line: &str) -> Result<Option<T>> {
+    match field {
+        None => Ok(None),
+        Some(f) => {
+            let value: i64 = number(Some(f), line)?;
+            Ok(T::try_from(value).ok().filter(|_| value >= 0))
+        }
+    }
+}
+
+/// A time in microseconds that may be missing.
+fn micros(field: Option<&&str>, line: &str) -> Result<Option<Duration>> {
+    Ok(optional::<u64>(field, line)?.map(Duration::from_micros))
+}
+
+/// Build the columns from the `%` lines that follow a result set header,
+/// e.g. `% a,\tb # name`.
+fn parse_columns(headers: &[&str], column_count: usize) -> Result<Vec<Column>> {
+    let mut tables = None;
+    let mut names = None;
+    let mut types = None;
+    let mut sizes = None;
+    for header in headers {
+        let (values, kind) = header
+            .rsplit_once(" # ")
+            .ok_or_else(|| unknown_response("column header", header))?;
+        let values: Vec<&str> = values.trim().split(",\t").collect();
+        if values.len() != column_count {
+            return Err(unknown_response("column count", header));
+        }
+        match kind.trim() {
+            "table_name" => tables = Some(values),
+            "name" => names = Some(values),
+            "type" => types = Some(values),
+            "typesizes" => sizes = Some(values),
+            _ => {}
+        }
+    }
+
+    let names = names.ok_or_else(|| unknown_response("result set", "no column names"))?;
+    let types = types.ok_or_else(|| unknown_response("result set", "no column types"))?;
+    (0..column_count)
+        .map(|i| {
+            // typesizes holds the number of digits and the scale. The
+            // connection asks for it with Xsizeheader.
+            let (digits, scale) = match sizes.as_ref().map(|s| s[i].split_once(' ')) {
+                Some(Some((d, s))) => (
+                    d.parse().map_err(|_| unknown_response("typesizes", d))?,
+                    s.parse().map_err(|_| unknown_response("typesizes", s))?,
+                ),
+                _ => (0, 0),
+            };
+            Ok(Column {
+                name: names[i].to_string(),
+                table: tables.as_ref().map_or("", |t| t[i]).to_string(),
+                sql_type: SqlType::with_size(types[i], digits, scale),
+            })
+        })
+        .collect()
+}
+
+/// Parse a row of a result set, e.g. `[ 1,\t"a \"b\"",\tNULL\t]`.
+pub fn parse_tuple(line: &str, column_count: usize) -> Result<Vec<Option<String>>> {
+    let values = RawRow::parse(line)?
+        .fields()
+        .map(|f| Ok(decode(f?)?.map(|v| v.into_owned())))
+        .collect::<Result<Vec<_>>>()?;
+    if values.len() != column_count {
+        return Err(unknown_response("number of values in row", line));
+    }
+
+    Ok(values)
+}
+
+/// A row of a result set that borrows its values from the response.
+/// Nothing is parsed or copied until a value is asked for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RawRow<'a> {
+    // The line without the surrounding "[ " and "\t]"
+    body: &'a str,
+}
+
+impl<'a> RawRow<'a> {
+    /// Wrap a `[ ... ]` line of a response.
+    pub fn parse(line: &'a str) -> Result<RawRow<'a>> {
+        let body = line
+            .strip_prefix("[ ")
+            .and_then(|l| l.strip_suffix("\t]"))
+            .ok_or_else(|| unknown_response("row", line))?;
+        Ok(RawRow { body })
+    }
+
+    /// The values as the server sent them: strings are still quoted and
+    /// escaped, NULL is `NULL`.
+    pub fn fields(&self) -> Fields<'a> {
+        Fields {
+            rest: Some(self.body),
+        }
+    }
+
+    /// Value `index` as the server sent it.
+    pub fn raw(&self, index: usize) -> Result<&'a str> {
+        self.fields().nth(index).unwrap_or_else(|| {
+            Err(MonetDBError::InvalidParameters(format!(
+                "no column {} in row",
+                index
+            )))
+        })
+    }
+
+    /// Value `index`, or `None` if it is NULL. Only strings with escape
+    /// sequences are copied.
+    pub fn get_str(&self, index: usize) -> Result<Option<Cow<'a, str>>> {
+        decode(self.raw(index)?)
+    }
+
+    /// Value `index`, converted to `T`.
+    pub fn get<T: FromSql>(&self, index: usize, sql_type: &SqlType) -> Result<T> {
+        let value = self.get_str(index)?;
+        T::from_nullable_sql(value.as_deref(), sql_type)
+    }
+}
+
+/// The raw values of a `RawRow`.
+pub struct Fields<'a> {
+    rest: Option<&'a str>,
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<&'a str>;
+
+    fn next(&mut self) -> Option<Result<&'a str>> {
+        let rest = self.rest?;
+        let end = if rest.starts_with('"') {
+            match quoted_length(rest) {
+                Some(end) => end,
+                None => {
+                    self.rest = None;
+                    return Some(Err(unknown_response("string", rest)));
+                }
+            }
+        } else {
+            rest.find(",\t").unwrap_or(rest.len())
+        };
+        let (field, remainder) = rest.split_at(end);
+        self.rest = if remainder.is_empty() {
+            None
+        } else if let Some(r) = remainder.strip_prefix(",\t") {
+            Some(r)
+        } else {
+            self.rest = None;
+            return Some(Err(unknown_response("row", rest)));
+        };
+        Some(Ok(field))
+    }
+}
+
+/// Turn a raw value into its text, or `None` for NULL.
+fn decode(field: &str) -> Result<Option<Cow<'_, str>>> {
+    if field == "NULL" {
+        return Ok(None);
+    }
+    match field.strip_prefix('"').and_then(|f| f.strip_suffix('"')) {
+        Some(inner) if inner.contains('\\') => unescape(inner)
+            .map(|v| Some(Cow::Owned(v)))
+            .ok_or_else(|| unknown_response("string", field)),
+        Some(inner) => Ok(Some(Cow::Borrowed(inner))),
+        None => Ok(Some(Cow::Borrowed(field))),
+    }
+}
+
+/// The length of the quoted string at the start of `input`, including the
+/// quotes.
+fn quoted_length(input: &str) -> Option<usize> {
+    let bytes = input.as_bytes();
+    let mut i = 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => return Some(i + 1),
+            b'\\' => i += 2,
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Decode the escape sequences in a string value.
+fn unescape(input: &str) -> Option<String> {
+    let mut value = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => value.push('\n'),
+            't' => value.push('\t'),
+            'r' => value.push('\r'),
+            'f' => value.push('\x0c'),
+            escaped @ '0'..='7' => {
+                // Three octal digits
+                let mut code = escaped.to_digit(8)?;
+                for _ in 0..2 {
+                    code = code * 8 + chars.next()?.to_digit(8)?;
+                }
+                value.push(char::from_u32(code)?);
+            }
+            other => value.push(other),
+        }
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            split_error("!42000!syntax error\n"),
+            (Some("42000"), "syntax error")
+        );
+        assert_eq!(
+            split_error("!InvalidCredentialsException:checkCredentials"),
+            (None, "InvalidCredentialsException:checkCredentials")
+        );
+        let (code, message) =
+            split_error("!40002!INSERT INTO: PRIMARY KEY constraint 't.t_id_pkey' violated");
+        assert_eq!(code, Some("40002"));
+        assert_eq!(Constraint::violated(message), Some(Constraint::Unique));
+        assert_eq!(Constraint::violated("syntax error"), None);
+    }
+
+    #[test]
+    fn script() {
+        let response = "&3 12 4\n&2 2 -1\n&4 f\n\
+                        &1 0 3 2 2\n\
+                        % sys.t,\tsys.t # table_name\n\
+                        % a,\tb # name\n\
+                        % decimal,\tvarchar # type\n\
+                        % 6,\t5 # length\n\
+                        % 10 2,\t5 0 # typesizes\n\
+                        [ 1.50,\t\"x\\\"y\"\t]\n\
+                        [ NULL,\t\"NULL\"\t]\n\
+                        &4 t\n";
+        let results = parse_response(response).unwrap();
+        let kinds: Vec<ResultKind> = results.iter().map(|r| r.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ResultKind::Schema,
+                ResultKind::Update,
+                ResultKind::Transaction,
+                ResultKind::Table,
+                ResultKind::Transaction
+            ]
+        );
+        assert_eq!(results[1].affected_rows, Some(2));
+        assert_eq!(results[2].auto_commit, Some(false));
+        assert_eq!(results[4].auto_commit, Some(true));
+
+        let table = &results[3];
+        assert_eq!(table.result_id, Some(0));
+        assert_eq!(table.tuple_count, Some(3));
+        assert_eq!(table.columns[0].name, "a");
+        assert_eq!(table.columns[0].table, "sys.t");
+        assert_eq!(
+            table.columns[0].sql_type,
+            SqlType::with_size("decimal", 10, 2)
+        );
+        assert_eq!(
+            table.rows,
+            vec![
+                vec![Some(String::from("1.50")), Some(String::from("x\"y"))],
+                vec![None, Some(String::from("NULL"))],
+            ]
+        );
+    }
+
+    #[test]
+    fn metadata() {
+        let results = parse_response(
+            "&2 1 42 7 40 12 20\n&2 3 -1\n&3 5 2\n&1 0 1000 1 0 8 60 15 25\n\
+             % t # table_name\n% a # name\n% int # type\n% 1 # length\n",
+        )
+        .unwrap();
+        let insert = &results[0];
+        assert_eq!(insert.affected_rows, Some(1));
+        assert_eq!(insert.last_id, Some(42));
+        assert_eq!(insert.query_id, Some(7));
+        assert_eq!(insert.exec_time, Some(Duration::from_micros(40)));
+        assert_eq!(insert.optimize_time, Some(Duration::from_micros(12)));
+        assert_eq!(insert.sql_parse_time, Some(Duration::from_micros(20)));
+
+        // An old server, without query id and timings
+        assert_eq!(results[1].last_id, None);
+        assert_eq!(results[1].query_id, None);
+        assert_eq!(results[1].exec_time, None);
+
+        assert_eq!(results[2].exec_time, Some(Duration::from_micros(5)));
+
+        let table = &results[3];
+        assert_eq!(table.tuple_count, Some(1000));
+        assert!(table.rows.is_empty());
+        assert_eq!(table.query_id, Some(8));
+        assert_eq!(table.sql_parse_time, Some(Duration::from_micros(25)));
+
+        assert!(parse_response("&2 1 x\n").is_err());
+    }
+
+    #[test]
+    fn errors_have_the_statement_index() {
+        let response = "&3 1 1\n&2 1 -1\n!42S02!SELECT: no such table 'x'\n!more detail\n";
+        match parse_response(response) {
+            Err(MonetDBError::StatementError(index, message)) => {
+                assert_eq!(index, 2);
+                assert_eq!(message, "42S02!SELECT: no such table 'x'\nmore detail");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse_response("&9 1\n").is_err());
+        assert!(parse_response("hello\n").is_err());
+    }
+
+    #[test]
+    fn tuples() {
+        assert_eq!(
+            parse_tuple("[ \"a,\\tb\",\t\"\\101\\\\\"\t]", 2).unwrap(),
+            vec![Some(String::from("a,\tb")), Some(String::from("A\\"))]
+        );
+        assert_eq!(
+            parse_tuple("[ 42\t]", 1).unwrap(),
+            vec![Some(String::from("42"))]
+        );
+        assert!(parse_tuple("[ 1,\t2\t]", 1).is_err());
+        assert!(parse_tuple("[ \"open\t]", 1).is_err());
+        assert!(parse_tuple("1,\t2", 2).is_err());
+
+        assert_eq!(
+            parse_block("&6 3 1 2 100\n[ 7\t]\n[ NULL\t]\n", 1).unwrap(),
+            vec![vec![Some(String::from("7"))], vec![None]]
+        );
+        assert!(parse_block("&2 1 -1\n", 1).is_err());
+    }
+
+    #[test]
+    fn raw_rows() {
+        let row = RawRow::parse("[ \"plain\",\t\"tab\\t\",\tNULL,\t-12\t]").unwrap();
+        assert!(matches!(
+            row.get_str(0).unwrap(),
+            Some(Cow::Borrowed("plain"))
+        ));
+        assert!(matches!(row.get_str(1).unwrap(), Some(Cow::Owned(s)) if s == "tab\t"));
+        assert_eq!(row.get_str(2).unwrap(), None);
+        assert_eq!(row.get::<i32>(3, &SqlType::new("int")).unwrap(), -12);
+        assert_eq!(row.raw(1).unwrap(), "\"tab\\t\"");
+        assert_eq!(row.fields().count(), 4);
+        assert!(row.raw(4).is_err());
+    }
+}
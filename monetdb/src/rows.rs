@@ -0,0 +1,441 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Iterating over the rows of a result set.
+//!
+//! The server sends the first rows of a result set along with the response
+//! to the query, and keeps the result set around so that the rest can be
+//! fetched in pages with `Xexport`. `Rows` fetches those pages as needed, and
+//! when it is dropped tells the `Connection` that the server can release the
+//! result set.
+//!
+//! `RawRows` does the same without copying: its rows borrow their values
+//! from a receive buffer that is reused for every page.
+use std::collections::VecDeque;
+use std::mem;
+
+use crate::connection::{Connection, Result};
+use crate::response::{self, Column, QueryResult, RawRow};
+use mapi::errors::MapiError;
+
+/// The number of rows `Rows` fetches at a time by default.
+pub const DEFAULT_FETCH_SIZE: u64 = 100;
+
+/// A row, with `None` for NULL values.
+pub type Row = Vec<Option<String>>;
+
+/// The client side state of a result set, shared by `Rows` and `Cursor`.
+pub(crate) struct ResultSet {
+    pub columns: Vec<Column>,
+    result_id: Option<u64>,
+    pub tuple_count: u64,
+    // The number of rows received from the server so far
+    received: u64,
+    buffer: VecDeque<Row>,
+    // Whether the server still holds the result set
+    open: bool,
+}
+
+impl ResultSet {
+    pub fn new(connection: &mut Connection, result: QueryResult) -> ResultSet {
+        let received = result.rows.len() as u64;
+        let tuple_count = result.tuple_count.unwrap_or(received);
+        let open = result.result_id.is_some() && tuple_count > received;
+        if let (true, Some(id)) = (open, result.result_id) {
+            connection.open_result(id);
+        }
+        ResultSet {
+            columns: result.columns,
+            result_id: result.result_id,
+            tuple_count,
+            received,
+            buffer: result.rows.into(),
+            open,
+        }
+    }
+
+    /// The next row, fetching `fetch_size` more from the server if needed.
+    pub fn next_row(
+        &mut self,
+        connection: &mut Connection,
+        fetch_size: u64,
+    ) -> Result<Option<Row>> {
+        if self.buffer.is_empty() && self.received < self.tuple_count {
+            if let Err(e) = self.fetch(connection, fetch_size) {
+                // Don't keep trying
+                self.tuple_count = self.received;
+                return Err(e);
+            }
+        }
+        Ok(self.buffer.pop_front())
+    }
+
+    fn fetch(&mut self, connection: &mut Connection, fetch_size: u64) -> Result<()> {
+        let id = match self.result_id {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let count = fetch_size.max(1).min(self.tuple_count - self.received);
+        let block = connection.export(id, self.received, count)?;
+        let rows = response::parse_block(&block, self.columns.len())?;
+        if rows.is_empty() {
+            // The server has fewer rows than it announced, don't ask again
+            self.tuple_count = self.received;
+        }
+        self.received += rows.len() as u64;
+        self.buffer.extend(rows);
+        Ok(())
+    }
+
+    /// Let the server release the result set once we no longer need it.
+    pub fn close(&mut self, connection: &mut Connection) {
+        if let (true, Some(id)) = (self.open, self.result_id) {
+            connection.close_result(id);
+        }
+        self.open = false;
+    }
+}
+
+/// An iterator over the rows of a result set.
+pub struct Rows<'a> {
+    connection: &'a mut Connection,
+    result: ResultSet,
+    fetch_size: u64,
+}
+
+impl<'a> Rows<'a> {
+    pub(crate) fn new(connection: &'a mut Connection, result: QueryResult) -> Rows<'a> {
+        let result = ResultSet::new(connection, result);
+        Rows {
+            connection,
+            result,
+            fetch_size: DEFAULT_FETCH_SIZE,
+        }
+    }
+
+    /// Set the number of rows to fetch from the server at a time.
+    pub fn with_fetch_size(mut self, fetch_size: u64) -> Rows<'a> {
+        self.fetch_size = fetch_size.max(1);
+        self
+    }
+
+    /// The columns of the result set.
+    pub fn columns(&self) -> &[Column] {
+        &self.result.columns
+    }
+
+    /// The total number of rows in the result set.
+    pub fn tuple_count(&self) -> u64 {
+        self.result.tuple_count
+    }
+}
+
+impl<'a> Iterator for Rows<'a> {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Result<Row>> {
+        self.result
+            .next_row(self.connection, self.fetch_size)
+            .transpose()
+    }
+}
+
+impl<'a> Drop for Rows<'a> {
+    fn drop(&mut self) {
+        self.result.close(self.connection);
+    }
+}
+
+/// The rows of a result set, borrowing their values from a receive buffer.
+///
+/// This is a lending iterator: a row is only valid until the next call to
+/// `next`, which may reuse the buffer for the next page of rows.
+pub struct RawRows<'a> {
+    connection: &'a mut Connection,
+    // The current page of the response, rows start at `position`
+    page: String,
+    position: usize,
+    columns: Vec<Column>,
+    result_id: Option<u64>,
+    tuple_count: u64,
+    // The number of rows returned by `next` so far
+    received: u64,
+    // Whether the server still holds the result set
+    open: bool,
+    fetch_size: u64,
+}
+
+impl<'a> RawRows<'a> {
+    pub(crate) fn new(connection: &'a mut Connection, page: String) -> Result<RawRows<'a>> {
+        let (result, rows) = match response::split_rows(&page) {
+            Ok((result, rows)) => (result, rows.len()),
+            Err(e) => {
+                connection.return_buffer(page.into_bytes());
+                return Err(e);
+            }
+        };
+        let position = page.len() - rows;
+        let sent = page[position..]
+            .lines()
+            .filter(|l| l.starts_with('['))
+            .count() as u64;
+        let tuple_count = result.tuple_count.unwrap_or(sent);
+        let open = result.result_id.is_some() && tuple_count > sent;
+        if let (true, Some(id)) = (open, result.result_id) {
+            connection.open_result(id);
+        }
+        Ok(RawRows {
+            connection,
+            page,
+            position,
+            columns: result.columns,
+            result_id: result.result_id,
+            tuple_count,
+            received: 0,
+            open,
+            fetch_size: DEFAULT_FETCH_SIZE,
+        })
+    }
+
+    /// Set the number of rows to fetch from the server at a time.
+    pub fn with_fetch_size(mut self, fetch_size: u64) -> RawRows<'a> {
+        self.fetch_size = fetch_size.max(1);
+        self
+    }
+
+    /// The columns of the result set.
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    /// The total number of rows in the result set.
+    pub fn tuple_count(&self) -> u64 {
+        self.tuple_count
+    }
+
+    /// The next row, fetching more from the server if needed.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Result<RawRow<'_>>> {
+        let (start, end) = loop {
+            if self.position >= self.page.len() {
+                if self.received >= self.tuple_count {
+                    return None;
+                }
+                if let Err(e) = self.fetch() {
+                    // Don't keep trying
+                    self.tuple_count = self.received;
+                    return Some(Err(e));
+                }
+                if self.position >= self.page.len() {
+                    // The server has fewer rows than it announced
+                    self.tuple_count = self.received;
+                    return None;
+                }
+            }
+            let start = self.position;
+            let end = self.page[start..]
+                .find('\n')
+                .map_or(self.page.len(), |i| start + i);
+            self.position = end + 1;
+            if self.page[start..end].starts_with('[') {
+                break (start, end);
+            }
+        };
+        self.received += 1;
+        Some(RawRow::parse(&self.page[start..end]))
+    }
+
+    fn fetch(&mut self) -> Result<()> {
+        let id = match self.result_id {
+            Some(id) => id,
+            None => {
+                self.tuple_count = self.received;
+                return Ok(());
+            }
+        };
+        let count = self.fetch_size.min(self.tuple_count - self.received);
+        let mut buffer = mem::take(&mut self.page).into_bytes();
+        self.connection
+            .export_into(id, self.received, count, &mut buffer)?;
+        self.page = String::from_utf8(buffer).map_err(MapiError::from)?;
+        self.position = response::block_start(&self.page)?;
+        Ok(())
+    }
+}
+
+impl<'a> Drop for RawRows<'a> {
+    fn drop(&mut self) {
+        if let (true, Some(id)) = (self.open, self.result_id) {
+            self.connection.close_result(id);
+        }
+        let page = mem::take(&mut self.page);
+        self.connection.return_buffer(page.into_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{mock_connection, mock_server};
+    use crate::monetizer::SqlType;
+
+    const HEADER: &str = "% t # table_name\n% i # name\n% int # type\n% 1 # length\n";
+
+    #[test]
+    fn pages_are_fetched() {
+        let (port, server) = mock_server(vec![
+            format!("&1 4 5 1 2\n{}[ 1\t]\n[ 2\t]\n", HEADER),
+            String::from("&6 4 1 2 2\n[ 3\t]\n[ 4\t]\n"),
+            String::from("&6 4 1 1 4\n[ 5\t]\n"),
+            String::new(),
+        ]);
+        let mut connection = mock_connection(port);
+        let rows = connection.query("SELECT i FROM t", vec![]).unwrap();
+        assert_eq!(rows.tuple_count(), 5);
+        assert_eq!(rows.columns()[0].name, "i");
+        let values: Vec<String> = rows
+            .with_fetch_size(2)
+            .map(|r| r.unwrap()[0].clone().unwrap())
+            .collect();
+        assert_eq!(values, vec!["1", "2", "3", "4", "5"]);
+        connection.execute("SELECT 1", vec![]).ok();
+        drop(connection);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[1], "Xexport 4 2 2");
+        assert_eq!(received[2], "Xexport 4 4 1");
+        assert_eq!(received[3], "Xclose 4");
+    }
+
+    #[test]
+    fn closes_are_deferred() {
+        let result = |id| format!("&1 {} 500 1 1\n{}[ 1\t]\n", id, HEADER);
+        let (port, server) = mock_server(vec![
+            result(1),
+            String::new(),
+            result(2),
+            String::new(),
+            String::from("&2 1 -1\n"),
+        ]);
+        let mut connection = mock_connection(port);
+        for _ in 0..2 {
+            let mut rows = connection.query("SELECT i FROM t", vec![]).unwrap();
+            assert_eq!(rows.next().unwrap().unwrap(), vec![Some(String::from("1"))]);
+        }
+        // The first result set is closed before the second query
+        assert_eq!(connection.open_results(), 1);
+        let result = connection.execute("INSERT INTO t VALUES (1)", vec![]);
+        assert_eq!(result.unwrap().affected_rows, Some(1));
+        assert_eq!(connection.open_results(), 0);
+        drop(connection);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[1], "Xclose 1");
+        assert_eq!(received[3], "Xclose 2");
+        assert_eq!(received[4], "sINSERT INTO t VALUES (1)\n;");
+    }
+
+    #[test]
+    fn executed_results_are_closed() {
+        let result = |id| format!("&1 {} 500 1 1\n{}[ 1\t]\n", id, HEADER);
+        let (port, server) = mock_server(vec![
+            result(1),
+            String::new(),
+            format!("{}&2 1 -1\n", result(2)),
+            String::new(),
+            String::from("&2 1 -1\n"),
+        ]);
+        let mut connection = mock_connection(port);
+        let result = connection.execute("SELECT i FROM t", vec![]).unwrap();
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(connection.open_results(), 1);
+        let results = connection
+            .execute_script("SELECT i FROM t; INSERT INTO t VALUES (1)")
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(connection.open_results(), 1);
+        connection
+            .execute("INSERT INTO t VALUES (1)", vec![])
+            .unwrap();
+        assert_eq!(connection.open_results(), 0);
+        drop(connection);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[1], "Xclose 1");
+        assert_eq!(received[3], "Xclose 2");
+    }
+
+    #[test]
+    fn closes_are_pipelined() {
+        let result = |id| format!("&1 {} 500 1 1\n{}[ 1\t]\n", id, HEADER);
+        let (port, server) = mock_server(vec![
+            format!("{}{}{}", result(1), result(2), result(3)),
+            String::new(),
+            String::from("!42000!Xclose: no such result set\n"),
+            String::new(),
+            String::from("&2 1 -1\n"),
+        ]);
+        let mut connection = mock_connection(port);
+        let results = connection
+            .execute_script("SELECT i FROM t; SELECT i FROM t; SELECT i FROM t")
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(connection.open_results(), 3);
+        // An error for a result set that is gone already is not ours
+        let result = connection.execute("INSERT INTO t VALUES (1)", vec![]);
+        assert_eq!(result.unwrap().affected_rows, Some(1));
+        assert_eq!(connection.open_results(), 0);
+        drop(connection);
+
+        let received = server.join().unwrap();
+        assert_eq!(
+            received[1..],
+            [
+                "Xclose 1",
+                "Xclose 2",
+                "Xclose 3",
+                "sINSERT INTO t VALUES (1)\n;"
+            ]
+        );
+    }
+
+    #[test]
+    fn small_results_are_not_closed() {
+        let (port, server) = mock_server(vec![format!("&1 7 1 1 1\n{}[ 1\t]\n", HEADER)]);
+        let mut connection = mock_connection(port);
+        assert_eq!(connection.query("SELECT 1", vec![]).unwrap().count(), 1);
+        assert_eq!(connection.open_results(), 0);
+        drop(connection);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn raw_rows() {
+        let (port, server) = mock_server(vec![
+            format!("&1 4 3 1 2\n{}[ 1\t]\n[ 2\t]\n", HEADER),
+            String::from("&6 4 1 1 2\n[ 3\t]\n"),
+            String::new(),
+        ]);
+        let mut connection = mock_connection(port);
+        let mut rows = connection
+            .query_raw("SELECT i FROM t", vec![])
+            .unwrap()
+            .with_fetch_size(2);
+        assert_eq!(rows.tuple_count(), 3);
+        let int = SqlType::new("int");
+        let mut values = vec![];
+        while let Some(row) = rows.next() {
+            values.push(row.unwrap().get::<i32>(0, &int).unwrap());
+        }
+        assert_eq!(values, vec![1, 2, 3]);
+        drop(rows);
+        connection.execute("SELECT 1", vec![]).ok();
+        drop(connection);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[1], "Xexport 4 2 1");
+        assert_eq!(received[2], "Xclose 4");
+    }
+}
@@ -0,0 +1,82 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! `ToSql` and `FromSql` for `rust_decimal::Decimal`.
+//!
+//! A `Decimal` has a 96 bit mantissa, so it holds about 28 digits. Reading a
+//! `decimal(p,s)` value that does not fit is an error rather than rounding.
+use std::result;
+
+use rust_decimal::Decimal;
+
+use crate::decimal;
+use crate::monetizer::{conversion_error, FromSql, SQLParameter, SqlType, ToSql};
+use mapi::errors::MonetDBError;
+
+type Result<T> = result::Result<T, MonetDBError>;
+
+impl ToSql for Decimal {
+    fn to_sql(&self) -> SQLParameter {
+        let mantissa = self.mantissa();
+        match decimal::format_decimal(
+            mantissa < 0,
+            &mantissa.unsigned_abs().to_string(),
+            self.scale() as i64,
+        ) {
+            Ok(literal) => SQLParameter::literal(literal),
+            Err(reason) => SQLParameter::invalid(reason),
+        }
+    }
+}
+
+impl FromSql for Decimal {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let unscaled = decimal::parse_decimal(value, sql_type)?;
+        let mantissa = unscaled
+            .digits
+            .parse::<i128>()
+            .ok()
+            .and_then(|m| Decimal::try_from_i128_with_scale(m, unscaled.scale).ok())
+            .ok_or_else(|| conversion_error(value, sql_type, "Decimal"))?;
+
+        Ok(if unscaled.negative {
+            -mantissa
+        } else {
+            mantissa
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monetizer::to_sqlparameter;
+    use std::str::FromStr;
+
+    fn read(value: &str, digits: u32, scale: u32) -> Result<Decimal> {
+        Decimal::from_sql(value, &SqlType::with_size("decimal", digits, scale))
+    }
+
+    #[test]
+    fn decimals() {
+        let d = Decimal::from_str("-1234.5600").unwrap();
+        assert_eq!(to_sqlparameter(d).to_string(), "-1234.56");
+        assert_eq!(to_sqlparameter(Decimal::ZERO).to_string(), "0");
+        assert_eq!(
+            to_sqlparameter(Decimal::MAX).to_string(),
+            Decimal::MAX.to_string()
+        );
+
+        let read_d = read("-1234.560", 10, 3).unwrap();
+        assert_eq!(read_d, d);
+        assert_eq!(read_d.scale(), 3);
+        assert_eq!(read("42", 10, 2).unwrap().to_string(), "42.00");
+
+        // 29 digits is more than a Decimal can hold
+        assert!(read(&"9".repeat(29), 38, 0).is_err());
+        assert!(read("1.2345", 10, 2).is_err());
+    }
+}
@@ -0,0 +1,906 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Building MonetDB SQL: quoting of identifiers and literals, and a small
+//! query builder.
+//!
+//! The builders render values as `?` placeholders and collect them, rendered
+//! with `monetizer`, in the `Query`, ready for `Connection::execute`:
+//!
+//! ```no_run
+//! # use monetdb::connection::Connection;
+//! use monetdb::sql::{col, Order, Select};
+//!
+//! let mut connection = Connection::connect("mapi://localhost:50000/demo").unwrap();
+//! let query = Select::from(("sales", "orders"))
+//!     .column("id")
+//!     .column("Customer")
+//!     .filter(col("amount").gt(100).and(col("status").eq("open")))
+//!     .order_by("id", Order::Desc)
+//!     .limit(10)
+//!     .build();
+//! // SELECT id, "Customer" FROM sales.orders WHERE amount > ? AND status = ?
+//! //     ORDER BY id DESC LIMIT 10
+//! let rows = connection.query(&query.sql, query.parameters).unwrap();
+//! ```
+//!
+//! Identifiers are only quoted when they have to be: when they are reserved
+//! words, or contain anything but lower case letters, digits and
+//! underscores. MonetDB folds unquoted identifiers to lower case.
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::monetizer::{self, SQLParameter, ToSql};
+use mapi::errors::MonetDBError;
+
+/// Quote an identifier, doubling embedded double quotes.
+pub use mapi::session::quote_identifier;
+
+/// The reserved words of MonetDB's SQL dialect, which cannot be used as
+/// identifiers without quoting them.
+const RESERVED_WORDS: &[&str] = &[
+    "ALL",
+    "ALTER",
+    "AND",
+    "ANY",
+    "AS",
+    "ASC",
+    "ASYMMETRIC",
+    "AT",
+    "AUTHORIZATION",
+    "BETWEEN",
+    "BIGINT",
+    "BINARY",
+    "BLOB",
+    "BOOLEAN",
+    "BOTH",
+    "BY",
+    "CALL",
+    "CASE",
+    "CAST",
+    "CHAR",
+    "CHARACTER",
+    "CHECK",
+    "CLOB",
+    "COALESCE",
+    "COLUMN",
+    "COMMIT",
+    "CONSTRAINT",
+    "CONVERT",
+    "COPY",
+    "CORRESPONDING",
+    "CREATE",
+    "CROSS",
+    "CUBE",
+    "CURRENT",
+    "CURRENT_DATE",
+    "CURRENT_ROLE",
+    "CURRENT_SCHEMA",
+    "CURRENT_TIME",
+    "CURRENT_TIMESTAMP",
+    "CURRENT_USER",
+    "DATE",
+    "DAY",
+    "DEALLOCATE",
+    "DEC",
+    "DECIMAL",
+    "DECLARE",
+    "DEFAULT",
+    "DELETE",
+    "DESC",
+    "DISTINCT",
+    "DOUBLE",
+    "DROP",
+    "EACH",
+    "ELSE",
+    "ELSEIF",
+    "END",
+    "ESCAPE",
+    "EXCEPT",
+    "EXEC",
+    "EXECUTE",
+    "EXISTS",
+    "EXTERNAL",
+    "EXTRACT",
+    "FALSE",
+    "FETCH",
+    "FILTER",
+    "FLOAT",
+    "FOR",
+    "FOREIGN",
+    "FROM",
+    "FULL",
+    "FUNCTION",
+    "GLOBAL",
+    "GRANT",
+    "GROUP",
+    "GROUPING",
+    "HAVING",
+    "HOUR",
+    "HUGEINT",
+    "IF",
+    "ILIKE",
+    "IMPRINTS",
+    "IN",
+    "INNER",
+    "INSERT",
+    "INT",
+    "INTEGER",
+    "INTERSECT",
+    "INTERVAL",
+    "INTO",
+    "IS",
+    "JOIN",
+    "LATERAL",
+    "LEADING",
+    "LEFT",
+    "LIKE",
+    "LIMIT",
+    "LOCAL",
+    "LOCALTIME",
+    "LOCALTIMESTAMP",
+    "MERGE",
+    "MINUTE",
+    "MONTH",
+    "NATURAL",
+    "NEW",
+    "NOT",
+    "NULL",
+    "NULLIF",
+    "NUMERIC",
+    "OF",
+    "OFFSET",
+    "OLD",
+    "ON",
+    "OR",
+    "ORDER",
+    "OUTER",
+    "OVER",
+    "PARTITION",
+    "PRECISION",
+    "PREPARE",
+    "PRIMARY",
+    "PROCEDURE",
+    "RANGE",
+    "REAL",
+    "REFERENCES",
+    "REFERENCING",
+    "REVOKE",
+    "RIGHT",
+    "ROLLBACK",
+    "ROLLUP",
+    "ROWS",
+    "SAMPLE",
+    "SECOND",
+    "SELECT",
+    "SESSION_USER",
+    "SET",
+    "SMALLINT",
+    "SOME",
+    "START",
+    "SYMMETRIC",
+    "TABLE",
+    "THEN",
+    "TIME",
+    "TIMESTAMP",
+    "TINYINT",
+    "TO",
+    "TRAILING",
+    "TRIGGER",
+    "TRUE",
+    "UNION",
+    "UNIQUE",
+    "UPDATE",
+    "USER",
+    "USING",
+    "VALUES",
+    "VARCHAR",
+    "VARYING",
+    "WHEN",
+    "WHERE",
+    "WHILE",
+    "WINDOW",
+    "WITH",
+    "YEAR",
+];
+
+/// Whether `word` is reserved, in any case.
+pub fn is_reserved(word: &str) -> bool {
+    RESERVED_WORDS
+        .binary_search(&word.to_ascii_uppercase().as_str())
+        .is_ok()
+}
+
+/// The identifier as it has to be written in SQL: unquoted if MonetDB reads
+/// it back unchanged that way, quoted otherwise.
+pub fn format_identifier(name: &str) -> Cow<'_, str> {
+    let plain = name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && !is_reserved(name);
+    if plain {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(quote_identifier(name))
+    }
+}
+
+/// Quote a string literal. Single quotes are doubled. MonetDB interprets
+/// backslash escapes, so strings with backslashes are written as `E''`
+/// strings with the backslashes doubled, which does not depend on that.
+pub fn quote_literal(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 3);
+    if value.contains('\\') {
+        quoted.push('E');
+    }
+    quoted.push('\'');
+    for c in value.chars() {
+        match c {
+            '\'' => quoted.push_str("''"),
+            '\\' => quoted.push_str("\\\\"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+
+    quoted
+}
+
+/// A possibly qualified name of a table or column, e.g. `sys.tables`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ident(Vec<String>);
+
+impl Ident {
+    pub fn new(name: &str) -> Ident {
+        Ident(vec![name.to_string()])
+    }
+
+    /// A name in a schema, or a column of a table.
+    pub fn qualified(qualifier: &str, name: &str) -> Ident {
+        Ident(vec![qualifier.to_string(), name.to_string()])
+    }
+}
+
+impl From<&str> for Ident {
+    fn from(name: &str) -> Ident {
+        Ident::new(name)
+    }
+}
+
+impl From<String> for Ident {
+    fn from(name: String) -> Ident {
+        Ident(vec![name])
+    }
+}
+
+impl From<(&str, &str)> for Ident {
+    fn from((qualifier, name): (&str, &str)) -> Ident {
+        Ident::qualified(qualifier, name)
+    }
+}
+
+impl fmt::Display for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, part) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            f.write_str(&format_identifier(part))?;
+        }
+        Ok(())
+    }
+}
+
+/// An SQL expression, for filters, columns and values.
+#[derive(Debug)]
+pub struct Expr(Node);
+
+#[derive(Debug)]
+enum Node {
+    Column(Ident),
+    Value(SQLParameter),
+    Raw(String),
+    // Operator and precedence
+    Binary(Box<Expr>, &'static str, u8, Box<Expr>),
+    Not(Box<Expr>),
+    Postfix(Box<Expr>, &'static str),
+    In(Box<Expr>, Vec<Expr>),
+}
+
+const OR: u8 = 1;
+const AND: u8 = 2;
+const NOT: u8 = 3;
+const COMPARISON: u8 = 4;
+
+/// A column.
+pub fn col<I: Into<Ident>>(name: I) -> Expr {
+    Expr(Node::Column(name.into()))
+}
+
+/// A value, bound as a parameter.
+pub fn val<T: ToSql>(value: T) -> Expr {
+    Expr(Node::Value(value.to_sql()))
+}
+
+/// SQL that is used verbatim, e.g. `count(*)`. It must not contain `?`
+/// outside of string literals. As an operand it is put in parentheses,
+/// whatever operators it contains.
+pub fn raw(sql: &str) -> Expr {
+    Expr(Node::Raw(sql.to_string()))
+}
+
+impl<T: ToSql> From<T> for Expr {
+    fn from(value: T) -> Expr {
+        val(value)
+    }
+}
+
+impl Expr {
+    fn binary<E: Into<Expr>>(self, operator: &'static str, precedence: u8, other: E) -> Expr {
+        Expr(Node::Binary(
+            Box::new(self),
+            operator,
+            precedence,
+            Box::new(other.into()),
+        ))
+    }
+
+    pub fn eq<E: Into<Expr>>(self, other: E) -> Expr {
+        self.binary("=", COMPARISON, other)
+    }
+
+    pub fn ne<E: Into<Expr>>(self, other: E) -> Expr {
+        self.binary("<>", COMPARISON, other)
+    }
+
+    pub fn lt<E: Into<Expr>>(self, other: E) -> Expr {
+        self.binary("<", COMPARISON, other)
+    }
+
+    pub fn le<E: Into<Expr>>(self, other: E) -> Expr {
+        self.binary("<=", COMPARISON, other)
+    }
+
+    pub fn gt<E: Into<Expr>>(self, other: E) -> Expr {
+        self.binary(">", COMPARISON, other)
+    }
+
+    pub fn ge<E: Into<Expr>>(self, other: E) -> Expr {
+        self.binary(">=", COMPARISON, other)
+    }
+
+    pub fn like<E: Into<Expr>>(self, pattern: E) -> Expr {
+        self.binary("LIKE", COMPARISON, pattern)
+    }
+
+    /// Case insensitive `like`.
+    pub fn ilike<E: Into<Expr>>(self, pattern: E) -> Expr {
+        self.binary("ILIKE", COMPARISON, pattern)
+    }
+
+    pub fn and(self, other: Expr) -> Expr {
+        self.binary("AND", AND, other)
+    }
+
+    pub fn or(self, other: Expr) -> Expr {
+        self.binary("OR", OR, other)
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Expr {
+        Expr(Node::Not(Box::new(self)))
+    }
+
+    pub fn is_null(self) -> Expr {
+        Expr(Node::Postfix(Box::new(self), "IS NULL"))
+    }
+
+    pub fn is_not_null(self) -> Expr {
+        Expr(Node::Postfix(Box::new(self), "IS NOT NULL"))
+    }
+
+    /// Whether the value is one of `values`. Never true if there are none.
+    pub fn is_in<I, E>(self, values: I) -> Expr
+    where
+        I: IntoIterator<Item = E>,
+        E: Into<Expr>,
+    {
+        Expr(Node::In(
+            Box::new(self),
+            values.into_iter().map(Into::into).collect(),
+        ))
+    }
+
+    fn precedence(&self) -> u8 {
+        match self.0 {
+            Node::Binary(_, _, precedence, _) => precedence,
+            Node::Not(_) => NOT,
+            Node::Postfix(..) | Node::In(..) => COMPARISON,
+            // We don't know what is in there
+            Node::Raw(_) => 0,
+            Node::Column(_) | Node::Value(_) => u8::MAX,
+        }
+    }
+
+    /// Write the expression to `query`, with parentheses where the
+    /// operators would otherwise bind differently.
+    fn write(self, query: &mut Query) {
+        match self.0 {
+            Node::Column(name) => query.sql.push_str(&name.to_string()),
+            Node::Value(value) => {
+                query.sql.push('?');
+                query.parameters.push(value);
+            }
+            Node::Raw(sql) => query.sql.push_str(&sql),
+            Node::Binary(left, operator, precedence, right) => {
+                // Comparisons don't chain
+                let limit = if precedence == COMPARISON {
+                    precedence + 1
+                } else {
+                    precedence
+                };
+                left.write_operand(query, limit);
+                query.sql.push(' ');
+                query.sql.push_str(operator);
+                query.sql.push(' ');
+                right.write_operand(query, limit);
+            }
+            Node::Not(operand) => {
+                query.sql.push_str("NOT ");
+                operand.write_operand(query, NOT);
+            }
+            Node::Postfix(operand, operator) => {
+                operand.write_operand(query, COMPARISON + 1);
+                query.sql.push(' ');
+                query.sql.push_str(operator);
+            }
+            Node::In(operand, values) if values.is_empty() => {
+                // IN () is a syntax error
+                drop(operand);
+                query.sql.push_str("FALSE");
+            }
+            Node::In(operand, values) => {
+                operand.write_operand(query, COMPARISON + 1);
+                query.sql.push_str(" IN (");
+                query.write_list(values, |query, value| value.write(query));
+                query.sql.push(')');
+            }
+        }
+    }
+
+    fn write_operand(self, query: &mut Query, precedence: u8) {
+        if self.precedence() < precedence {
+            query.sql.push('(');
+            self.write(query);
+            query.sql.push(')');
+        } else {
+            self.write(query);
+        }
+    }
+}
+
+/// The direction of an `ORDER BY`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// SQL with `?` placeholders, and the parameters to bind to them.
+#[derive(Debug, Default)]
+pub struct Query {
+    pub sql: String,
+    pub parameters: Vec<SQLParameter>,
+}
+
+impl Query {
+    /// The SQL with the parameters substituted, e.g. for
+    /// `Connection::execute_script`.
+    pub fn render(self) -> Result<String, MonetDBError> {
+        monetizer::apply_parameters(&self.sql, self.parameters)
+    }
+
+    fn write_list<T, F: FnMut(&mut Query, T)>(&mut self, items: Vec<T>, mut write: F) {
+        for (i, item) in items.into_iter().enumerate() {
+            if i > 0 {
+                self.sql.push_str(", ");
+            }
+            write(self, item);
+        }
+    }
+
+    fn write_filter(&mut self, filter: Option<Expr>) {
+        if let Some(filter) = filter {
+            self.sql.push_str(" WHERE ");
+            filter.write(self);
+        }
+    }
+}
+
+fn and(filter: Option<Expr>, condition: Expr) -> Option<Expr> {
+    Some(match filter {
+        Some(filter) => filter.and(condition),
+        None => condition,
+    })
+}
+
+/// A `SELECT` query.
+#[derive(Debug)]
+pub struct Select {
+    table: Ident,
+    distinct: bool,
+    columns: Vec<Expr>,
+    filter: Option<Expr>,
+    group_by: Vec<Expr>,
+    order_by: Vec<(Expr, Order)>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+}
+
+impl Select {
+    /// Select from `table`, all columns unless some are added.
+    pub fn from<I: Into<Ident>>(table: I) -> Select {
+        Select {
+            table: table.into(),
+            distinct: false,
+            columns: vec![],
+            filter: None,
+            group_by: vec![],
+            order_by: vec![],
+            limit: None,
+            offset: None,
+        }
+    }
+
+    pub fn distinct(mut self) -> Select {
+        self.distinct = true;
+        self
+    }
+
+    pub fn column<I: Into<Ident>>(self, name: I) -> Select {
+        self.expr(col(name))
+    }
+
+    /// Select a computed column, e.g. `raw("count(*)")`.
+    pub fn expr(mut self, expr: Expr) -> Select {
+        self.columns.push(expr);
+        self
+    }
+
+    /// Only the rows for which `condition` holds. Conditions added with
+    /// several calls must all hold.
+    pub fn filter(mut self, condition: Expr) -> Select {
+        self.filter = and(self.filter, condition);
+        self
+    }
+
+    pub fn group_by<I: Into<Ident>>(mut self, name: I) -> Select {
+        self.group_by.push(col(name));
+        self
+    }
+
+    pub fn order_by<I: Into<Ident>>(mut self, name: I, order: Order) -> Select {
+        self.order_by.push((col(name), order));
+        self
+    }
+
+    pub fn limit(mut self, rows: u64) -> Select {
+        self.limit = Some(rows);
+        self
+    }
+
+    pub fn offset(mut self, rows: u64) -> Select {
+        self.offset = Some(rows);
+        self
+    }
+
+    pub fn build(self) -> Query {
+        let mut query = Query::default();
+        query.sql.push_str("SELECT ");
+        if self.distinct {
+            query.sql.push_str("DISTINCT ");
+        }
+        if self.columns.is_empty() {
+            query.sql.push('*');
+        }
+        query.write_list(self.columns, |query, column| column.write(query));
+        query.sql.push_str(&format!(" FROM {}", self.table));
+        query.write_filter(self.filter);
+        if !self.group_by.is_empty() {
+            query.sql.push_str(" GROUP BY ");
+            query.write_list(self.group_by, |query, column| column.write(query));
+        }
+        if !self.order_by.is_empty() {
+            query.sql.push_str(" ORDER BY ");
+            query.write_list(self.order_by, |query, (column, order)| {
+                column.write(query);
+                if order == Order::Desc {
+                    query.sql.push_str(" DESC");
+                }
+            });
+        }
+        if let Some(limit) = self.limit {
+            query.sql.push_str(&format!(" LIMIT {}", limit));
+        }
+        if let Some(offset) = self.offset {
+            query.sql.push_str(&format!(" OFFSET {}", offset));
+        }
+        query
+    }
+}
+
+/// An `INSERT` of one or more rows.
+#[derive(Debug)]
+pub struct Insert {
+    table: Ident,
+    columns: Vec<Ident>,
+    rows: Vec<Vec<Expr>>,
+}
+
+impl Insert {
+    pub fn into<I: Into<Ident>>(table: I) -> Insert {
+        Insert {
+            table: table.into(),
+            columns: vec![],
+            rows: vec![],
+        }
+    }
+
+    /// The columns the values of every row are for, in order.
+    pub fn columns<I, N>(mut self, names: I) -> Insert
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<Ident>,
+    {
+        self.columns.extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add a row, with a value for every column.
+    pub fn values<I, E>(mut self, values: I) -> Insert
+    where
+        I: IntoIterator<Item = E>,
+        E: Into<Expr>,
+    {
+        self.rows.push(values.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn build(self) -> Query {
+        let mut query = Query::default();
+        query.sql.push_str(&format!("INSERT INTO {}", self.table));
+        if !self.columns.is_empty() {
+            query.sql.push_str(" (");
+            query.write_list(self.columns, |query, column| {
+                query.sql.push_str(&column.to_string())
+            });
+            query.sql.push(')');
+        }
+        query.sql.push_str(" VALUES ");
+        query.write_list(self.rows, |query, row| {
+            query.sql.push('(');
+            query.write_list(row, |query, value| value.write(query));
+            query.sql.push(')');
+        });
+        query
+    }
+}
+
+/// An `UPDATE` of the rows that match a filter, or all rows.
+#[derive(Debug)]
+pub struct Update {
+    table: Ident,
+    assignments: Vec<(Ident, Expr)>,
+    filter: Option<Expr>,
+}
+
+impl Update {
+    pub fn table<I: Into<Ident>>(table: I) -> Update {
+        Update {
+            table: table.into(),
+            assignments: vec![],
+            filter: None,
+        }
+    }
+
+    pub fn set<I: Into<Ident>, E: Into<Expr>>(mut self, column: I, value: E) -> Update {
+        self.assignments.push((column.into(), value.into()));
+        self
+    }
+
+    /// Like `Select::filter`.
+    pub fn filter(mut self, condition: Expr) -> Update {
+        self.filter = and(self.filter, condition);
+        self
+    }
+
+    pub fn build(self) -> Query {
+        let mut query = Query::default();
+        query.sql.push_str(&format!("UPDATE {} SET ", self.table));
+        query.write_list(self.assignments, |query, (column, value)| {
+            query.sql.push_str(&format!("{} = ", column));
+            value.write(query);
+        });
+        query.write_filter(self.filter);
+        query
+    }
+}
+
+/// A `DELETE` of the rows that match a filter, or all rows.
+#[derive(Debug)]
+pub struct Delete {
+    table: Ident,
+    filter: Option<Expr>,
+}
+
+impl Delete {
+    pub fn from<I: Into<Ident>>(table: I) -> Delete {
+        Delete {
+            table: table.into(),
+            filter: None,
+        }
+    }
+
+    /// Like `Select::filter`.
+    pub fn filter(mut self, condition: Expr) -> Delete {
+        self.filter = and(self.filter, condition);
+        self
+    }
+
+    pub fn build(self) -> Query {
+        let mut query = Query::default();
+        query.sql.push_str(&format!("DELETE FROM {}", self.table));
+        query.write_filter(self.filter);
+        query
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{mock_connection, mock_server};
+
+    #[test]
+    fn reserved_words_are_sorted() {
+        assert!(RESERVED_WORDS.windows(2).all(|w| w[0] < w[1]));
+        assert!(is_reserved("select"));
+        assert!(is_reserved("User"));
+        assert!(!is_reserved("users"));
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote_identifier("a\"b"), "\"a\"\"b\"");
+        assert_eq!(format_identifier("orders_2024"), "orders_2024");
+        assert_eq!(format_identifier("Orders"), "\"Orders\"");
+        assert_eq!(format_identifier("order"), "\"order\"");
+        assert_eq!(format_identifier("2024"), "\"2024\"");
+        assert_eq!(format_identifier("a b"), "\"a b\"");
+        assert_eq!(format_identifier(""), "\"\"");
+        assert_eq!(Ident::from(("sys", "user")).to_string(), "sys.\"user\"");
+
+        assert_eq!(quote_literal("it's"), "'it''s'");
+        assert_eq!(quote_literal("C:\\temp"), "E'C:\\\\temp'");
+        assert_eq!(quote_literal("ünï\ncode"), "'ünï\ncode'");
+    }
+
+    #[test]
+    fn select() {
+        let query = Select::from(("sales", "orders"))
+            .distinct()
+            .column("id")
+            .column("Customer")
+            .expr(raw("count(*)"))
+            .filter(col("amount").gt(100).and(col("status").eq("it's")))
+            .filter(
+                col("region")
+                    .is_in(["north", "south"])
+                    .or(col("region").is_null())
+                    .not(),
+            )
+            .group_by("id")
+            .order_by("id", Order::Desc)
+            .order_by("Customer", Order::Asc)
+            .limit(10)
+            .offset(20)
+            .build();
+        assert_eq!(
+            query.sql,
+            "SELECT DISTINCT id, \"Customer\", count(*) FROM sales.orders \
+             WHERE amount > ? AND status = ? \
+             AND NOT (region IN (?, ?) OR region IS NULL) \
+             GROUP BY id ORDER BY id DESC, \"Customer\" LIMIT 10 OFFSET 20"
+        );
+        let parameters: Vec<String> = query.parameters.iter().map(|p| p.to_string()).collect();
+        assert_eq!(parameters, vec!["100", "'it''s'", "'north'", "'south'"]);
+
+        assert_eq!(Select::from("t").build().sql, "SELECT * FROM t");
+    }
+
+    #[test]
+    fn precedence() {
+        let query = Select::from("t")
+            .filter(
+                col("a")
+                    .eq(1)
+                    .or(col("b").eq(2))
+                    .and(col("c").eq(col("d").eq(true)))
+                    .and(col("e").is_in(Vec::<i32>::new())),
+            )
+            .build();
+        assert_eq!(
+            query.sql,
+            "SELECT * FROM t WHERE (a = ? OR b = ?) AND c = (d = ?) AND FALSE"
+        );
+        assert_eq!(
+            Select::from("t")
+                .filter(col("a").gt(1).is_null())
+                .build()
+                .sql,
+            "SELECT * FROM t WHERE (a > ?) IS NULL"
+        );
+        let query = Select::from("t")
+            .filter(raw("a = 1 OR b = 2").and(col("c").eq(3)))
+            .build();
+        assert_eq!(
+            query.sql,
+            "SELECT * FROM t WHERE (a = 1 OR b = 2) AND c = ?"
+        );
+        let query = Select::from("t").filter(raw("a = 1 OR b = 2")).build();
+        assert_eq!(query.sql, "SELECT * FROM t WHERE a = 1 OR b = 2");
+    }
+
+    #[test]
+    fn modifications() {
+        let insert = Insert::into("users")
+            .columns(["id", "name"])
+            .values([val(1), val("a\\b")])
+            .values([val(2), val(None::<String>)])
+            .build();
+        assert_eq!(
+            insert.sql,
+            "INSERT INTO users (id, name) VALUES (?, ?), (?, ?)"
+        );
+        assert_eq!(
+            insert.render().unwrap(),
+            "INSERT INTO users (id, name) VALUES (1, E'a\\\\b'), (2, NULL)"
+        );
+
+        let update = Update::table("users")
+            .set("name", "b")
+            .set("Group", raw("DEFAULT"))
+            .filter(col("id").eq(1))
+            .build();
+        assert_eq!(
+            update.render().unwrap(),
+            "UPDATE users SET name = 'b', \"Group\" = DEFAULT WHERE id = 1"
+        );
+
+        let delete = Delete::from("users").filter(col("name").like("a%")).build();
+        assert_eq!(delete.sql, "DELETE FROM users WHERE name LIKE ?");
+        assert_eq!(Delete::from("users").build().sql, "DELETE FROM users");
+    }
+
+    #[test]
+    fn execute() {
+        let (port, server) = mock_server(vec![String::from("&2 1 -1\n")]);
+        let mut connection = mock_connection(port);
+        let query = Update::table("user")
+            .set("name", "?")
+            .filter(col("id").eq(7))
+            .build();
+        let result = connection.execute(&query.sql, query.parameters).unwrap();
+        assert_eq!(result.affected_rows, Some(1));
+        drop(connection);
+
+        let received = server.join().unwrap();
+        assert_eq!(
+            received[0],
+            "sUPDATE \"user\" SET name = '?' WHERE id = 7\n;"
+        );
+    }
+}
@@ -0,0 +1,304 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! Parsing and formatting of the textual representation MonetDB uses for
+//! dates, times, timestamps and intervals. Shared by the `ToSql` and
+//! `FromSql` implementations of the different date/time libraries.
+use std::result;
+
+use mapi::errors::MonetDBError;
+
+type Result<T> = result::Result<T, MonetDBError>;
+
+/// A time of day, with nanosecond precision.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) struct TimeOfDay {
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub nanosecond: u32,
+}
+
+/// The pieces of a `timestamp` or `timestamptz` value.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) struct Timestamp {
+    pub date: (i32, u32, u32),
+    pub time: TimeOfDay,
+    /// The offset from UTC in seconds, only present for `timestamptz`.
+    pub offset: Option<i32>,
+}
+
+fn invalid(what: &str, value: &str) -> MonetDBError {
+    MonetDBError::ConversionError(format!("invalid {}: {:?}", what, value))
+}
+
+fn number<T: std::str::FromStr>(what: &str, value: &str, field: &str) -> Result<T> {
+    if field.is_empty() || !field.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid(what, value));
+    }
+    field.parse::<T>().map_err(|_| invalid(what, value))
+}
+
+/// Parse `YYYY-MM-DD` into (year, month, day).
+pub(crate) fn parse_date(value: &str) -> Result<(i32, u32, u32)> {
+    let (negative, rest) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    let mut parts = rest.splitn(3, '-');
+    let mut next = || parts.next().ok_or_else(|| invalid("date", value));
+    let year: i32 = number("date", value, next()?)?;
+    let month: u32 = number("date", value, next()?)?;
+    let day: u32 = number("date", value, next()?)?;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return Err(invalid("date", value));
+    }
+
+    Ok((if negative { -year } else { year }, month, day))
+}
+
+/// Parse `HH:MM:SS[.fraction]`, ignoring digits beyond nanoseconds.
+pub(crate) fn parse_time(value: &str) -> Result<TimeOfDay> {
+    let (hms, fraction) = match value.split_once('.') {
+        Some((hms, fraction)) => (hms, fraction),
+        None => (value, ""),
+    };
+    let mut parts = hms.splitn(3, ':');
+    let mut next = || parts.next().ok_or_else(|| invalid("time", value));
+    let hour: u32 = number("time", value, next()?)?;
+    let minute: u32 = number("time", value, next()?)?;
+    let second: u32 = number("time", value, next()?)?;
+    let nanosecond = parse_fraction("time", value, fraction)?;
+    if hour > 23 || minute > 59 || second > 59 {
+        return Err(invalid("time", value));
+    }
+
+    Ok(TimeOfDay {
+        hour,
+        minute,
+        second,
+        nanosecond,
+    })
+}
+
+fn parse_fraction(what: &str, value: &str, fraction: &str) -> Result<u32> {
+    if fraction.is_empty() {
+        return Ok(0);
+    }
+    let digits = &fraction[..fraction.len().min(9)];
+    let n: u32 = number(what, value, digits)?;
+    if !fraction[digits.len()..].bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid(what, value));
+    }
+
+    Ok(n * 10u32.pow(9 - digits.len() as u32))
+}
+
+/// Parse a UTC offset of the form `+HH:MM` or `-HH:MM` into seconds.
+pub(crate) fn parse_offset(value: &str) -> Result<i32> {
+    let sign = match value.as_bytes().first() {
+        Some(b'+') => 1,
+        Some(b'-') => -1,
+        _ => return Err(invalid("time zone offset", value)),
+    };
+    let (hours, minutes) = value[1..].split_once(':').unwrap_or((&value[1..], "0"));
+    let hours: i32 = number("time zone offset", value, hours)?;
+    let minutes: i32 = number("time zone offset", value, minutes)?;
+    if hours > 18 || minutes > 59 {
+        return Err(invalid("time zone offset", value));
+    }
+
+    Ok(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Split a time with an optional trailing UTC offset, e.g. `12:00:00+02:00`.
+pub(crate) fn parse_time_with_offset(value: &str) -> Result<(TimeOfDay, Option<i32>)> {
+    match value.find(['+', '-']) {
+        Some(i) => Ok((parse_time(&value[..i])?, Some(parse_offset(&value[i..])?))),
+        None => Ok((parse_time(value)?, None)),
+    }
+}
+
+/// Parse `YYYY-MM-DD HH:MM:SS[.fraction][+HH:MM]`.
+pub(crate) fn parse_timestamp(value: &str) -> Result<Timestamp> {
+    let (date, time) = value
+        .split_once([' ', 'T'])
+        .ok_or_else(|| invalid("timestamp", value))?;
+    let (time, offset) = parse_time_with_offset(time)?;
+
+    Ok(Timestamp {
+        date: parse_date(date)?,
+        time,
+        offset,
+    })
+}
+
+/// Parse a `sec_interval`, which MonetDB sends as a decimal number of seconds,
+/// into (negative, seconds, nanoseconds).
+pub(crate) fn parse_seconds(value: &str) -> Result<(bool, u64, u32)> {
+    let (negative, rest) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    let (seconds, fraction) = rest.split_once('.').unwrap_or((rest, ""));
+
+    Ok((
+        negative,
+        number("interval", value, seconds)?,
+        parse_fraction("interval", value, fraction)?,
+    ))
+}
+
+/// Format a date as `YYYY-MM-DD`.
+pub(crate) fn format_date(year: i32, month: u32, day: u32) -> String {
+    if year < 0 {
+        format!("-{:04}-{:02}-{:02}", -year, month, day)
+    } else {
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+/// Format a time as `HH:MM:SS.ffffff`, truncated to microseconds.
+pub(crate) fn format_time(time: TimeOfDay) -> String {
+    format!(
+        "{:02}:{:02}:{:02}.{:06}",
+        time.hour,
+        time.minute,
+        time.second,
+        time.nanosecond / 1000
+    )
+}
+
+/// Format a UTC offset in seconds as `+HH:MM`.
+pub(crate) fn format_offset(offset: i32) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs();
+    format!("{}{:02}:{:02}", sign, offset / 3600, offset % 3600 / 60)
+}
+
+/// Format a signed duration as a `sec_interval` literal.
+pub(crate) fn format_seconds(negative: bool, seconds: u64, nanosecond: u32) -> String {
+    format!(
+        "INTERVAL '{}{}.{:06}' SECOND",
+        if negative { "-" } else { "" },
+        seconds,
+        nanosecond / 1000
+    )
+}
+
+fn is_leap_year(year: i32) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Convert a number of days since 1970-01-01 to a (year, month, day) date in
+/// the proleptic Gregorian calendar.
+pub(crate) fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year as i32, month, day)
+}
+
+/// The inverse of `civil_from_days`.
+pub(crate) fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let year = year as i64 - if month <= 2 { 1 } else { 0 };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("2022-06-23").unwrap(), (2022, 6, 23));
+        assert_eq!(parse_date("2000-02-29").unwrap(), (2000, 2, 29));
+        assert_eq!(parse_date("-0044-03-15").unwrap(), (-44, 3, 15));
+        for bad in [
+            "2022-06",
+            "2022-13-01",
+            "1900-02-29",
+            "2022-06-xx",
+            "",
+            "2022-6-1a",
+        ] {
+            assert!(parse_date(bad).is_err(), "{}", bad);
+        }
+        for days in [-719468, -1, 0, 11_016, 19_166, 2_932_896] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+        assert_eq!(format_date(-44, 3, 15), "-0044-03-15");
+    }
+
+    #[test]
+    fn times() {
+        let t = parse_time("13:45:07.123456").unwrap();
+        assert_eq!(
+            (t.hour, t.minute, t.second, t.nanosecond),
+            (13, 45, 7, 123_456_000)
+        );
+        assert_eq!(parse_time("00:00:00").unwrap().nanosecond, 0);
+        assert_eq!(parse_time("00:00:00.5").unwrap().nanosecond, 500_000_000);
+        assert_eq!(
+            parse_time("00:00:00.1234567891").unwrap().nanosecond,
+            123_456_789
+        );
+        assert!(parse_time("24:00:00").is_err());
+        assert!(parse_time("12:00").is_err());
+        assert!(parse_time("12:00:00.x").is_err());
+        assert_eq!(format_time(t), "13:45:07.123456");
+    }
+
+    #[test]
+    fn timestamps() {
+        let ts = parse_timestamp("2022-06-23 18:00:00.250000+02:00").unwrap();
+        assert_eq!(ts.date, (2022, 6, 23));
+        assert_eq!(ts.time.hour, 18);
+        assert_eq!(ts.time.nanosecond, 250_000_000);
+        assert_eq!(ts.offset, Some(7200));
+        let ts = parse_timestamp("2022-06-23 18:00:00-05:30").unwrap();
+        assert_eq!(ts.offset, Some(-19800));
+        assert_eq!(parse_timestamp("2022-06-23 18:00:00").unwrap().offset, None);
+        assert!(parse_timestamp("2022-06-23").is_err());
+        assert_eq!(format_offset(-19800), "-05:30");
+        assert_eq!(format_offset(0), "+00:00");
+    }
+
+    #[test]
+    fn intervals() {
+        assert_eq!(parse_seconds("90.500").unwrap(), (false, 90, 500_000_000));
+        assert_eq!(parse_seconds("-1.000").unwrap(), (true, 1, 0));
+        assert_eq!(parse_seconds("86400").unwrap(), (false, 86400, 0));
+        assert!(parse_seconds("1.2.3").is_err());
+        assert_eq!(
+            format_seconds(true, 90, 500_000_000),
+            "INTERVAL '-90.500000' SECOND"
+        );
+    }
+}
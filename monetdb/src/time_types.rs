@@ -0,0 +1,217 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0.  If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+// Copyright 1997 - July 2008 CWI, August 2008 - 2022 MonetDB B.V.
+//
+//! `ToSql` and `FromSql` for the date and time types of the `time` crate.
+//!
+//! | time                | MonetDB                       |
+//! |---------------------|-------------------------------|
+//! | `Date`              | `date`                        |
+//! | `Time`              | `time(p)`, `timetz(p)`        |
+//! | `PrimitiveDateTime` | `timestamp(p)`                |
+//! | `OffsetDateTime`    | `timestamptz(p)`              |
+//! | `Duration`          | `sec_interval`, `day_interval`|
+//!
+//! Values are sent with microsecond precision, the highest MonetDB supports.
+//! A `timestamptz` is returned by the server in the session time zone, and
+//! keeps that offset when read as `OffsetDateTime`.
+use std::result;
+
+use time::{Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+
+use crate::monetizer::{conversion_error, FromSql, SQLParameter, SqlType, ToSql};
+use crate::temporal::{self, TimeOfDay};
+use mapi::errors::MonetDBError;
+
+type Result<T> = result::Result<T, MonetDBError>;
+
+fn date_string(date: &Date) -> String {
+    temporal::format_date(date.year(), date.month() as u32, date.day() as u32)
+}
+
+fn time_string(time: &Time) -> String {
+    temporal::format_time(TimeOfDay {
+        hour: time.hour() as u32,
+        minute: time.minute() as u32,
+        second: time.second() as u32,
+        nanosecond: time.nanosecond(),
+    })
+}
+
+fn to_date(value: &str, sql_type: &SqlType, (y, m, d): (i32, u32, u32)) -> Result<Date> {
+    Month::try_from(m as u8)
+        .ok()
+        .and_then(|m| Date::from_calendar_date(y, m, d as u8).ok())
+        .ok_or_else(|| conversion_error(value, sql_type, "Date"))
+}
+
+fn to_time(value: &str, sql_type: &SqlType, t: TimeOfDay) -> Result<Time> {
+    Time::from_hms_nano(t.hour as u8, t.minute as u8, t.second as u8, t.nanosecond)
+        .map_err(|_| conversion_error(value, sql_type, "Time"))
+}
+
+impl ToSql for Date {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(format!("DATE '{}'", date_string(self)))
+    }
+}
+
+impl FromSql for Date {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        to_date(value, sql_type, temporal::parse_date(value)?)
+    }
+}
+
+impl ToSql for Time {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(format!("TIME '{}'", time_string(self)))
+    }
+}
+
+impl FromSql for Time {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let (time, _) = temporal::parse_time_with_offset(value)?;
+        to_time(value, sql_type, time)
+    }
+}
+
+impl ToSql for PrimitiveDateTime {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(format!(
+            "TIMESTAMP '{} {}'",
+            date_string(&self.date()),
+            time_string(&self.time())
+        ))
+    }
+}
+
+impl FromSql for PrimitiveDateTime {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let ts = temporal::parse_timestamp(value)?;
+        Ok(PrimitiveDateTime::new(
+            to_date(value, sql_type, ts.date)?,
+            to_time(value, sql_type, ts.time)?,
+        ))
+    }
+}
+
+impl ToSql for OffsetDateTime {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(format!(
+            "TIMESTAMPTZ '{} {}{}'",
+            date_string(&self.date()),
+            time_string(&self.time()),
+            temporal::format_offset(self.offset().whole_seconds())
+        ))
+    }
+}
+
+impl FromSql for OffsetDateTime {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let ts = temporal::parse_timestamp(value)?;
+        let offset = ts
+            .offset
+            .and_then(|o| UtcOffset::from_whole_seconds(o).ok())
+            .ok_or_else(|| conversion_error(value, sql_type, "OffsetDateTime"))?;
+        Ok(PrimitiveDateTime::new(
+            to_date(value, sql_type, ts.date)?,
+            to_time(value, sql_type, ts.time)?,
+        )
+        .assume_offset(offset))
+    }
+}
+
+impl ToSql for Duration {
+    fn to_sql(&self) -> SQLParameter {
+        SQLParameter::literal(temporal::format_seconds(
+            self.is_negative(),
+            self.whole_seconds().unsigned_abs(),
+            self.subsec_nanoseconds().unsigned_abs(),
+        ))
+    }
+}
+
+impl FromSql for Duration {
+    fn from_sql(value: &str, sql_type: &SqlType) -> Result<Self> {
+        let (negative, seconds, nanos) = temporal::parse_seconds(value)?;
+        let seconds = if sql_type.name == "day_interval" {
+            seconds.checked_mul(86400)
+        } else {
+            Some(seconds)
+        };
+        let seconds = seconds
+            .and_then(|s| i64::try_from(s).ok())
+            .ok_or_else(|| conversion_error(value, sql_type, "Duration"))?;
+        let duration = Duration::new(seconds, nanos as i32);
+
+        Ok(if negative { -duration } else { duration })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monetizer::to_sqlparameter;
+
+    fn read<T: FromSql>(value: &str, name: &str) -> Result<T> {
+        T::from_sql(value, &SqlType::new(name))
+    }
+
+    #[test]
+    fn dates_and_times() {
+        let date = Date::from_calendar_date(2022, Month::June, 23).unwrap();
+        assert_eq!(to_sqlparameter(date).to_string(), "DATE '2022-06-23'");
+        assert_eq!(read::<Date>("2022-06-23", "date").unwrap(), date);
+
+        let time = Time::from_hms_micro(13, 45, 7, 123_456).unwrap();
+        assert_eq!(to_sqlparameter(time).to_string(), "TIME '13:45:07.123456'");
+        assert_eq!(
+            read::<Time>("13:45:07.123456-01:00", "timetz").unwrap(),
+            time
+        );
+
+        let ts = PrimitiveDateTime::new(date, time);
+        assert_eq!(
+            to_sqlparameter(ts).to_string(),
+            "TIMESTAMP '2022-06-23 13:45:07.123456'"
+        );
+        assert_eq!(
+            read::<PrimitiveDateTime>("2022-06-23 13:45:07.123456", "timestamp").unwrap(),
+            ts
+        );
+        assert!(read::<Date>("2021-02-29", "date").is_err());
+    }
+
+    #[test]
+    fn time_zones() {
+        let date = Date::from_calendar_date(2022, Month::June, 23).unwrap();
+        let offset = UtcOffset::from_hms(-5, -30, 0).unwrap();
+        let dt =
+            PrimitiveDateTime::new(date, Time::from_hms(18, 0, 0).unwrap()).assume_offset(offset);
+        assert_eq!(
+            to_sqlparameter(dt).to_string(),
+            "TIMESTAMPTZ '2022-06-23 18:00:00.000000-05:30'"
+        );
+        let read_dt =
+            read::<OffsetDateTime>("2022-06-23 18:00:00.000000-05:30", "timestamptz").unwrap();
+        assert_eq!(read_dt, dt);
+        assert_eq!(read_dt.offset(), offset);
+        assert!(read::<OffsetDateTime>("2022-06-23 18:00:00", "timestamp").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        let d = Duration::milliseconds(-90_500);
+        assert_eq!(
+            to_sqlparameter(d).to_string(),
+            "INTERVAL '-90.500000' SECOND"
+        );
+        assert_eq!(read::<Duration>("-90.500", "sec_interval").unwrap(), d);
+        assert_eq!(
+            read::<Duration>("2", "day_interval").unwrap(),
+            Duration::days(2)
+        );
+    }
+}